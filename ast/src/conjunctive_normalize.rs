@@ -1,36 +1,237 @@
 use crate::{
-    definitions::{visitor::Visitor, Expression, UntaggedOperator, UntaggedOperatorName},
+    definitions::{Expression, LiteralValue, UntaggedOperator, UntaggedOperatorName},
     negative_normalize::NegativeNormalize,
 };
 
-struct CNFVisitor;
+/// DEFAULT_CLAUSE_LIMIT is the number of clauses a normal form may have. A disjunction (for the
+/// conjunctive normal form) or conjunction (for the disjunctive one) that would distribute into
+/// more clauses is kept as a single clause instead, since distributing is exponential.
+pub const DEFAULT_CLAUSE_LIMIT: usize = 64;
 
-impl Visitor for CNFVisitor {
-    fn visit_expression(&mut self, expression: Expression) -> Expression {
+// A normal form is a list of clauses joined by outer, where every clause is a list of literals
+// joined by inner: $and of $ors for the conjunctive normal form, and $or of $ands for the
+// disjunctive normal form.
+#[derive(Clone, Copy)]
+struct NormalForm {
+    outer: UntaggedOperatorName,
+    inner: UntaggedOperatorName,
+    limit: usize,
+}
+
+type Clauses = Vec<Vec<Expression>>;
+
+impl Expression {
+    /// get_conjunctive_normal_form rewrites the boolean structure of the expression, its $and,
+    /// $or and $not operators, into an $and of $ors of literals, so that every conjunct can be
+    /// handled on its own. Conjuncts common to every branch of an $or are factored out before
+    /// distributing, and clauses implied by others are removed, so `(a AND b) OR (a AND c)`
+    /// becomes `a AND (b OR c)`. An $and with a single conjunct is just the conjunct.
+    pub fn get_conjunctive_normal_form(&self) -> Expression {
+        self.get_conjunctive_normal_form_with_limit(DEFAULT_CLAUSE_LIMIT)
+    }
+
+    /// get_conjunctive_normal_form_with_limit is get_conjunctive_normal_form with at most limit
+    /// clauses from distributing any one $or.
+    pub fn get_conjunctive_normal_form_with_limit(&self, limit: usize) -> Expression {
+        NormalForm {
+            outer: UntaggedOperatorName::And,
+            inner: UntaggedOperatorName::Or,
+            limit,
+        }
+        .normalize(self)
+    }
+
+    /// get_disjunctive_normal_form rewrites the boolean structure of the expression into an $or
+    /// of $ands of literals, the dual of get_conjunctive_normal_form.
+    pub fn get_disjunctive_normal_form(&self) -> Expression {
+        self.get_disjunctive_normal_form_with_limit(DEFAULT_CLAUSE_LIMIT)
+    }
+
+    /// get_disjunctive_normal_form_with_limit is get_disjunctive_normal_form with at most limit
+    /// clauses from distributing any one $and.
+    pub fn get_disjunctive_normal_form_with_limit(&self, limit: usize) -> Expression {
+        NormalForm {
+            outer: UntaggedOperatorName::Or,
+            inner: UntaggedOperatorName::And,
+            limit,
+        }
+        .normalize(self)
+    }
+}
+
+impl NormalForm {
+    fn normalize(self, expression: &Expression) -> Expression {
+        let clauses = self.clauses(push_negations(expression, false));
+        self.build(
+            self.outer,
+            clauses
+                .into_iter()
+                .map(|clause| self.build(self.inner, clause.into_iter())),
+        )
+    }
+
+    // clauses returns the normal form of an expression whose negations have been pushed down to
+    // its literals.
+    fn clauses(self, expression: Expression) -> Clauses {
         match expression {
-            Expression::UntaggedOperator(UntaggedOperator {
-                op: UntaggedOperatorName::Or,
-                args,
-            }) => {
-                let args = args
-                    .into_iter()
-                    .map(|operand| self.visit_expression(operand).get_negation())
-                    .collect::<Vec<_>>();
-                Expression::UntaggedOperator(UntaggedOperator {
-                    op: UntaggedOperatorName::Not,
-                    args: vec![Expression::UntaggedOperator(UntaggedOperator {
-                        op: UntaggedOperatorName::And,
-                        args,
-                    })],
-                })
+            Expression::UntaggedOperator(UntaggedOperator { op, args }) if op == self.outer => {
+                let mut clauses = Clauses::new();
+                for arg in args {
+                    for clause in self.clauses(arg) {
+                        add_clause(&mut clauses, clause);
+                    }
+                }
+                clauses
             }
-            _ => expression.walk(self),
+            Expression::UntaggedOperator(UntaggedOperator { op, args }) if op == self.inner => {
+                let (common, rest) = self.factor(args);
+                let mut clauses = Clauses::new();
+                for arg in common {
+                    for clause in self.clauses(arg) {
+                        add_clause(&mut clauses, clause);
+                    }
+                }
+                if let Some(rest) = rest {
+                    for clause in self.distribute(rest) {
+                        add_clause(&mut clauses, clause);
+                    }
+                }
+                clauses
+            }
+            literal => vec![vec![literal]],
+        }
+    }
+
+    // factor splits the operands of an inner operator into the outer operands common to all of
+    // them, and the remaining operands, which are None if the common operands absorb them:
+    // `(a AND b) OR (a AND c)` is `a AND (b OR c)`, and `a OR (a AND b)` is `a`.
+    fn factor(self, args: Vec<Expression>) -> (Vec<Expression>, Option<Vec<Vec<Expression>>>) {
+        let operands: Vec<Vec<Expression>> = args
+            .into_iter()
+            .map(|arg| match arg {
+                Expression::UntaggedOperator(UntaggedOperator { op, args }) if op == self.outer => {
+                    args
+                }
+                arg => vec![arg],
+            })
+            .collect();
+        let Some((first, others)) = operands.split_first() else {
+            return (Vec::new(), Some(Vec::new()));
+        };
+        let common: Vec<Expression> = first
+            .iter()
+            .filter(|operand| others.iter().all(|other| other.contains(operand)))
+            .fold(Vec::new(), |mut common, operand| {
+                if !common.contains(operand) {
+                    common.push(operand.clone());
+                }
+                common
+            });
+        let mut rest = Vec::new();
+        for operand in operands {
+            let remaining: Vec<Expression> = operand
+                .into_iter()
+                .filter(|operand| !common.contains(operand))
+                .collect();
+            if remaining.is_empty() {
+                return (common, None);
+            }
+            rest.push(remaining);
+        }
+        (common, Some(rest))
+    }
+
+    // distribute returns the normal form of the inner operator applied to the operands, each an
+    // outer operator applied to its operands. If that takes more clauses than the limit, the
+    // operator is kept as a single literal instead.
+    fn distribute(self, operands: Vec<Vec<Expression>>) -> Clauses {
+        let mut clauses: Clauses = vec![vec![]];
+        for operand in operands.iter() {
+            let operand_clauses = self.clauses(self.build(self.outer, operand.iter().cloned()));
+            let mut distributed = Clauses::new();
+            for clause in clauses.iter() {
+                for operand_clause in operand_clauses.iter() {
+                    let mut clause = clause.clone();
+                    for literal in operand_clause {
+                        if !clause.contains(literal) {
+                            clause.push(literal.clone());
+                        }
+                    }
+                    add_clause(&mut distributed, clause);
+                    if distributed.len() > self.limit {
+                        let operands = operands
+                            .into_iter()
+                            .map(|operand| self.build(self.outer, operand.into_iter()));
+                        return vec![vec![self.build(self.inner, operands)]];
+                    }
+                }
+            }
+            clauses = distributed;
         }
+        clauses
+    }
+
+    // build applies the operator to the operands, or returns the single operand.
+    fn build(
+        self,
+        op: UntaggedOperatorName,
+        operands: impl Iterator<Item = Expression>,
+    ) -> Expression {
+        let mut args: Vec<Expression> = operands.collect();
+        if args.len() == 1 {
+            return args.remove(0);
+        }
+        Expression::UntaggedOperator(UntaggedOperator { op, args })
     }
 }
 
-impl Expression {
-    pub fn get_conjunctive_normal_form(&self) -> Expression {
-        CNFVisitor.visit_expression(self.clone())
+// add_clause adds the clause unless a clause that implies it is already present, and removes the
+// clauses it implies. A clause implies another if its literals are a subset of the other's.
+fn add_clause(clauses: &mut Clauses, clause: Vec<Expression>) {
+    let implies = |a: &Vec<Expression>, b: &Vec<Expression>| a.iter().all(|e| b.contains(e));
+    if clauses.iter().any(|existing| implies(existing, &clause)) {
+        return;
+    }
+    clauses.retain(|existing| !implies(&clause, existing));
+    clauses.push(clause);
+}
+
+// push_negations moves every $not of the boolean structure of the expression down to its
+// literals, negating the expression if negate is set. Comparisons and boolean literals are
+// negated with NegativeNormalize, any other literal keeps its $not, since NegativeNormalize
+// negates them for schema derivation only.
+fn push_negations(expression: &Expression, negate: bool) -> Expression {
+    match expression {
+        Expression::UntaggedOperator(UntaggedOperator {
+            op: op @ (UntaggedOperatorName::And | UntaggedOperatorName::Or),
+            args,
+        }) => Expression::UntaggedOperator(UntaggedOperator {
+            op: match (op, negate) {
+                (UntaggedOperatorName::And, true) => UntaggedOperatorName::Or,
+                (UntaggedOperatorName::Or, true) => UntaggedOperatorName::And,
+                (op, _) => *op,
+            },
+            args: args.iter().map(|arg| push_negations(arg, negate)).collect(),
+        }),
+        Expression::UntaggedOperator(UntaggedOperator {
+            op: UntaggedOperatorName::Not,
+            args,
+        }) if args.len() == 1 => push_negations(&args[0], !negate),
+        expression if !negate => expression.clone(),
+        Expression::UntaggedOperator(UntaggedOperator {
+            op:
+                UntaggedOperatorName::Eq
+                | UntaggedOperatorName::Ne
+                | UntaggedOperatorName::Lt
+                | UntaggedOperatorName::Lte
+                | UntaggedOperatorName::Gt
+                | UntaggedOperatorName::Gte,
+            ..
+        })
+        | Expression::Literal(LiteralValue::Boolean(_)) => expression.get_negation(),
+        expression => Expression::UntaggedOperator(UntaggedOperator {
+            op: UntaggedOperatorName::Not,
+            args: vec![expression.clone()],
+        }),
     }
 }
@@ -1,12 +1,20 @@
 macro_rules! test_expression_conjunctive_normal_form {
     ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        test_expression_conjunctive_normal_form!(
+            $func_name,
+            normal_form = get_conjunctive_normal_form(),
+            expected = $expected,
+            input = $input
+        );
+    };
+    ($func_name:ident, normal_form = $method:ident($($arg:expr)?), expected = $expected:expr, input = $input:expr) => {
         #[test]
         fn $func_name() {
             use crate::definitions::Expression;
 
             let input: Expression = serde_json::from_str($input).unwrap();
             let expected: Expression = serde_json::from_str($expected).unwrap();
-            let result = input.get_conjunctive_normal_form();
+            let result = input.$method($($arg)?);
             assert_eq!(expected, result);
         }
     };
@@ -29,13 +37,94 @@ mod constants {
 mod logical {
     test_expression_conjunctive_normal_form!(
         simple_or,
-        expected = r#"{"$not": {"$and": [{"$gt": ["$foo", null]}, {"$ne": ["$foo", 0]}, {"$ne": ["$foo", false]}]}}"#,
+        expected = r#"{"$or": [{"$lte": ["$foo", null]}, {"$eq": ["$foo", 0]}, {"$eq": ["$foo", false]}]}"#,
         input = r#"{"$or": [{"$lte": ["$foo", null]}, {"$eq": ["$foo", 0]}, {"$eq": ["$foo", false]}]}"#
     );
 
     test_expression_conjunctive_normal_form!(
         nested_or,
-        expected = r#"{"$not": {"$and": [{"$gt": ["$foo", {"$not": {"$and": [false, true]}}]}, {"$ne": ["$foo", 0]}, {"$ne": ["$foo", false]}]}}"#,
+        expected = r#"{"$or": [{"$lte": ["$foo", {"$or": [true, false]}]}, {"$eq": ["$foo", 0]}, {"$eq": ["$foo", false]}]}"#,
         input = r#"{"$or": [{"$lte": ["$foo", {"$or": [true, false]}]}, {"$eq": ["$foo", 0]}, {"$eq": ["$foo", false]}]}"#
     );
+
+    test_expression_conjunctive_normal_form!(
+        flatten_and,
+        expected = r#"{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}, {"$eq": ["$c", 3]}]}"#,
+        input = r#"{"$and": [{"$eq": ["$a", 1]}, {"$and": [{"$eq": ["$b", 2]}, {"$eq": ["$c", 3]}]}, {"$eq": ["$a", 1]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        factor_common_conjunct,
+        expected =
+            r#"{"$and": [{"$eq": ["$a", 1]}, {"$or": [{"$eq": ["$b", 2]}, {"$eq": ["$c", 3]}]}]}"#,
+        input = r#"{"$or": [{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}, {"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$c", 3]}]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        absorb_disjunct,
+        expected = r#"{"$eq": ["$a", 1]}"#,
+        input =
+            r#"{"$or": [{"$eq": ["$a", 1]}, {"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        distribute_or,
+        expected = r#"{"$and": [
+            {"$or": [{"$eq": ["$a", 1]}, {"$eq": ["$c", 3]}]},
+            {"$or": [{"$eq": ["$a", 1]}, {"$eq": ["$d", 4]}]},
+            {"$or": [{"$eq": ["$b", 2]}, {"$eq": ["$c", 3]}]},
+            {"$or": [{"$eq": ["$b", 2]}, {"$eq": ["$d", 4]}]}
+        ]}"#,
+        input = r#"{"$or": [{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}, {"$and": [{"$eq": ["$c", 3]}, {"$eq": ["$d", 4]}]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        clause_limit,
+        normal_form = get_conjunctive_normal_form_with_limit(3),
+        expected = r#"{"$and": [
+            {"$eq": ["$e", 5]},
+            {"$or": [{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}, {"$and": [{"$eq": ["$c", 3]}, {"$eq": ["$d", 4]}]}]}
+        ]}"#,
+        input = r#"{"$and": [
+            {"$eq": ["$e", 5]},
+            {"$or": [{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}, {"$and": [{"$eq": ["$c", 3]}, {"$eq": ["$d", 4]}]}]}
+        ]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        not_or,
+        expected = r#"{"$and": [{"$ne": ["$a", 1]}, {"$gte": ["$b", 2]}]}"#,
+        input = r#"{"$not": [{"$or": [{"$eq": ["$a", 1]}, {"$lt": ["$b", 2]}]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        not_and,
+        expected = r#"{"$or": [{"$ne": ["$a", 1]}, {"$not": [{"$in": ["$b", [1, 2]]}]}]}"#,
+        input = r#"{"$not": [{"$and": [{"$eq": ["$a", 1]}, {"$in": ["$b", [1, 2]]}]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        double_not,
+        expected = r#"{"$in": ["$b", [1, 2]]}"#,
+        input = r#"{"$not": [{"$not": [{"$in": ["$b", [1, 2]]}]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        disjunctive_normal_form,
+        normal_form = get_disjunctive_normal_form(),
+        expected = r#"{"$or": [
+            {"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$c", 3]}]},
+            {"$and": [{"$eq": ["$b", 2]}, {"$eq": ["$c", 3]}]}
+        ]}"#,
+        input =
+            r#"{"$and": [{"$or": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}, {"$eq": ["$c", 3]}]}"#
+    );
+
+    test_expression_conjunctive_normal_form!(
+        disjunctive_normal_form_factors,
+        normal_form = get_disjunctive_normal_form(),
+        expected =
+            r#"{"$or": [{"$eq": ["$a", 1]}, {"$and": [{"$eq": ["$b", 2]}, {"$eq": ["$c", 3]}]}]}"#,
+        input = r#"{"$and": [{"$or": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}, {"$or": [{"$eq": ["$a", 1]}, {"$eq": ["$c", 3]}]}]}"#
+    );
 }
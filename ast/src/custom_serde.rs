@@ -1,10 +1,10 @@
 use crate::{
     definitions::{
         Cond, Convert, DateExpression, DateFromParts, DateFromString, DateToString, Expression,
-        LiteralValue, MatchArrayExpression, MatchArrayQuery, MatchBinaryOp, MatchElement,
-        MatchExpression, MatchField, MatchNot, MatchNotExpression, MatchRegex, MatchStage,
-        ProjectItem, ProjectStage, Ref, SetWindowFieldsOutput, Trim, UntaggedOperator,
-        UntaggedOperatorName, VecOrSingleExpr, Window,
+        LiteralValue, LookupFrom, MatchArrayExpression, MatchArrayQuery, MatchBinaryOp,
+        MatchElement, MatchExpression, MatchField, MatchNot, MatchNotExpression, MatchRegex,
+        MatchStage, Namespace, ProjectItem, ProjectStage, Ref, SetWindowFieldsOutput, Trim,
+        UntaggedOperator, UntaggedOperatorName, VecOrSingleExpr, Window,
     },
     map,
 };
@@ -782,6 +782,57 @@ impl ser::Serialize for Ref {
     }
 }
 
+impl<'de> Deserialize<'de> for LookupFrom {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // $lookup from can take one of two forms:
+        // 1. from: <collection>
+        // 2. from: { db: <db>, coll: <collection> }
+        struct LookupFromVisitor;
+
+        impl<'de> Visitor<'de> for LookupFromVisitor {
+            type Value = LookupFrom;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str(r#"a collection name or {"db": <db>, "coll": <collection>}"#)
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(LookupFrom::Collection(s.to_string()))
+            }
+
+            fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let (mut db, mut coll) = (None, None);
+                while let Some(key) = access.next_key::<String>()? {
+                    let field = match key.as_str() {
+                        "db" => &mut db,
+                        "coll" => &mut coll,
+                        _ => return Err(de::Error::unknown_field(&key, &["db", "coll"])),
+                    };
+                    if field.is_some() {
+                        return Err(de::Error::custom(format!("duplicate field `{key}`")));
+                    }
+                    *field = Some(access.next_value::<String>()?);
+                }
+                Ok(LookupFrom::Namespace(Namespace {
+                    db: db.ok_or_else(|| de::Error::missing_field("db"))?,
+                    coll: coll.ok_or_else(|| de::Error::missing_field("coll"))?,
+                }))
+            }
+        }
+
+        deserializer.deserialize_any(LookupFromVisitor)
+    }
+}
+
 impl<'de> Deserialize<'de> for SetWindowFieldsOutput {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -859,7 +910,7 @@ impl<'de> Deserialize<'de> for SetWindowFieldsOutput {
                     _ => {
                         return Err(serde_err::custom(
                             "setWindowFields output could not be parsed",
-                        ))
+                        ));
                     }
                 };
 
@@ -917,6 +968,38 @@ where
     map.end()
 }
 
+/// serialize_group_aggregations writes the argument of a $group accumulator bare when it is
+/// given as a single element list, since accumulators are unary and reject an argument list.
+pub fn serialize_group_aggregations<S>(
+    aggregations: &LinkedHashMap<String, Expression>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: ser::Serializer,
+{
+    let mut map = serializer.serialize_map(Some(aggregations.len()))?;
+    for (name, aggregation) in aggregations.iter() {
+        match aggregation {
+            Expression::UntaggedOperator(UntaggedOperator { op, args }) if args.len() == 1 => {
+                map.serialize_entry(name, &UnaryAccumulator(op, &args[0]))?
+            }
+            aggregation => map.serialize_entry(name, aggregation)?,
+        }
+    }
+    map.end()
+}
+
+struct UnaryAccumulator<'a>(&'a UntaggedOperatorName, &'a Expression);
+
+impl ser::Serialize for UnaryAccumulator<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serializer.collect_map(std::iter::once((self.0, self.1)))
+    }
+}
+
 impl<'de> Visitor<'de> for UntaggedOperatorVisitor {
     type Value = UntaggedOperator;
 
@@ -1053,7 +1136,7 @@ impl<'de> de::Deserialize<'de> for LiteralValue {
             Bson::Array(_) | Bson::Document(_) => {
                 return Err(de::Error::custom(format_args!(
                     "expected a literal value, found a document or array"
-                )))
+                )));
             }
             Bson::Boolean(b) => LiteralValue::Boolean(b),
             Bson::Null => LiteralValue::Null,
@@ -1271,7 +1354,7 @@ impl<'de> Deserialize<'de> for Convert {
                         _ => {
                             return Err(serde_err::custom(
                                 "expected format for convert to be string or none",
-                            ))
+                            ));
                         }
                     };
                     let on_error = d.remove("onError").map(Box::new);
@@ -1,4 +1,6 @@
-use crate::custom_serde::{deserialize_mql_operator, serialize_mql_operator};
+use crate::custom_serde::{
+    deserialize_mql_operator, serialize_group_aggregations, serialize_mql_operator,
+};
 use bson::Bson;
 use linked_hash_map::LinkedHashMap;
 use serde::{Deserialize, Serialize};
@@ -51,6 +53,7 @@ impl Pipeline {
 }
 
 /// Stage represents an aggregation pipeline stage.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub enum Stage {
     // This is used so that we can visit Stages that rewrite to multiple Stages.
@@ -116,6 +119,10 @@ pub enum Stage {
     Sample(Sample),
     #[serde(rename = "$unionWith")]
     UnionWith(UnionWith),
+    #[serde(rename = "$merge")]
+    Merge(Merge),
+    #[serde(rename = "$out")]
+    Out(Out),
 
     // Search stages
     #[serde(rename = "$graphLookup")]
@@ -453,7 +460,7 @@ pub enum MatchBinaryOp {
 pub struct Group {
     #[serde(rename = "_id")]
     pub keys: Expression,
-    #[serde(flatten)]
+    #[serde(flatten, serialize_with = "serialize_group_aggregations")]
     pub aggregations: LinkedHashMap<String, Expression>,
 }
 
@@ -509,7 +516,7 @@ pub struct FakeJoin {
     pub collection: Option<String>,
     #[serde(rename = "joinType")]
     pub join_type: JoinType,
-    #[serde(rename = "let")]
+    #[serde(rename = "let", skip_serializing_if = "Option::is_none")]
     pub let_body: Option<LinkedHashMap<String, Expression>>,
     pub pipeline: Pipeline,
     pub condition: Option<Expression>,
@@ -523,8 +530,42 @@ pub enum Join {
     Left(JoinExpression),
     #[serde(rename = "$derived")]
     Derived(Derived),
+    #[serde(rename = "$recursive")]
+    Recursive(Recursive),
     #[serde(untagged)]
     Entity(String),
+    #[serde(untagged)]
+    Alias(Alias),
+}
+
+/// Alias joins an entity under another name, so that the same entity can be joined more than
+/// once. via names the ERD relationship to follow, which picks between several relationships to
+/// the entity, and is required if the entity is already in scope.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alias {
+    pub entity: String,
+    #[serde(rename = "as", skip_serializing_if = "Option::is_none")]
+    pub as_var: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+}
+
+/// Recursive joins the records reachable from an entity along a relationship of the entity to
+/// itself, such as the ancestors of a category through its parent, as an array. via names the
+/// relationship, which is only needed if the entity has more than one, and as defaults to its
+/// name. max_depth and depth_field mean the same as in `$graphLookup`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recursive {
+    pub entity: String,
+    #[serde(rename = "as", skip_serializing_if = "Option::is_none")]
+    pub as_var: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth_field: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -540,6 +581,22 @@ pub struct JoinExpression {
     pub args: Vec<Join>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub condition: Option<Expression>,
+    /// exclude_implicit projects away the entities that were only joined because they are on
+    /// the path to a requested entity.
+    #[serde(rename = "excludeImplicit", skip_serializing_if = "Option::is_none")]
+    pub exclude_implicit: Option<bool>,
+    /// read_concern is the weakest consistency the joined entities may be read with. Paths
+    /// through relationships of weaker consistency are not taken.
+    #[serde(rename = "readConcern", skip_serializing_if = "Option::is_none")]
+    pub read_concern: Option<ReadConcern>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadConcern {
+    Strong,
+    Weak,
+    Eventual,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -588,7 +645,9 @@ pub enum Lookup {
     Subquery(SubqueryLookup),
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+/// LookupFrom is the `from` of a `$lookup`: a collection in the pipeline's own database, or a
+/// `{db, coll}` namespace in any database.
+#[derive(Clone, Debug, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum LookupFrom {
     Collection(String),
@@ -611,7 +670,7 @@ pub struct ConciseSubqueryLookup {
     pub from: Option<LookupFrom>,
     pub local_field: String,
     pub foreign_field: String,
-    #[serde(rename = "let")]
+    #[serde(rename = "let", skip_serializing_if = "Option::is_none")]
     pub let_body: Option<LinkedHashMap<String, Expression>>,
     pub pipeline: Pipeline,
     #[serde(rename = "as")]
@@ -622,7 +681,7 @@ pub struct ConciseSubqueryLookup {
 #[serde(rename_all = "camelCase")]
 pub struct SubqueryLookup {
     pub from: Option<LookupFrom>,
-    #[serde(rename = "let")]
+    #[serde(rename = "let", skip_serializing_if = "Option::is_none")]
     pub let_body: Option<LinkedHashMap<String, Expression>>,
     pub pipeline: Pipeline,
     #[serde(rename = "as")]
@@ -793,6 +852,60 @@ pub struct UnionWithPipeline {
     pub pipeline: Pipeline,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Merge {
+    pub into: MergeInto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on: Option<MergeOn>,
+    #[serde(rename = "let", skip_serializing_if = "Option::is_none")]
+    pub let_body: Option<LinkedHashMap<String, Expression>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when_matched: Option<MergeWhenMatched>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when_not_matched: Option<MergeWhenNotMatched>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MergeInto {
+    Collection(String),
+    Namespace(Namespace),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MergeOn {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeWhenMatched {
+    Replace,
+    KeepExisting,
+    Merge,
+    Fail,
+    #[serde(untagged)]
+    Pipeline(Pipeline),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeWhenNotMatched {
+    Insert,
+    Discard,
+    Fail,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Out {
+    Collection(String),
+    Namespace(Namespace),
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GraphLookup {
@@ -802,8 +915,11 @@ pub struct GraphLookup {
     pub connect_to_field: String,
     #[serde(rename = "as")]
     pub as_var: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_depth: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub depth_field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub restrict_search_with_match: Option<Box<Expression>>,
 }
 
@@ -1489,6 +1605,7 @@ pub struct SQLConvert {
 pub struct Convert {
     pub input: Box<Expression>,
     pub to: Box<Expression>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub format: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub on_null: Option<Box<Expression>>,
@@ -1504,6 +1621,7 @@ pub struct Filter {
     #[serde(rename = "as")]
     pub _as: Option<String>,
     pub cond: Box<Expression>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<Box<Expression>>,
 }
 
@@ -1882,6 +2000,8 @@ impl Stage {
             Stage::GeoNear(_) => "$geoNear",
             Stage::Sample(_) => "$sample",
             Stage::UnionWith(_) => "$unionWith",
+            Stage::Merge(_) => "$merge",
+            Stage::Out(_) => "$out",
             Stage::GraphLookup(_) => "$graphLookup",
             Stage::Sentinel => "<Sentinel>",
             Stage::AtlasSearchStage(_) => "<Atlas search stage>",
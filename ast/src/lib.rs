@@ -8,10 +8,16 @@ pub mod negative_normalize;
 mod negative_normalize_tests;
 #[cfg(test)]
 mod serde_test;
+pub mod simplify;
+#[cfg(test)]
+mod simplify_tests;
 pub mod uses;
+#[cfg(test)]
+mod uses_tests;
 
 pub const ROOT_NAME: &str = "ROOT";
 pub const PRUNE_NAME: &str = "PRUNE";
+pub const REMOVE_NAME: &str = "REMOVE";
 
 #[allow(dead_code)]
 pub const KEEP_NAME: &str = "KEEP";
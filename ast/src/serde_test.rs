@@ -46,6 +46,19 @@ macro_rules! test_serde_expr {
     };
 }
 
+macro_rules! test_serialize_stage {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::definitions::Stage;
+
+            let input: Stage = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            assert_eq!(expected, serde_json::to_value(&input).unwrap());
+        }
+    };
+}
+
 #[test]
 fn test_derived() {
     use crate::{map, definitions::{Derived, Join, Pipeline, MatchExpression, MatchField, MatchStage, ProjectStage, Ref, Stage, MatchBinaryOp, ProjectItem, Expression}};
@@ -776,8 +789,9 @@ mod stage_test {
     mod join {
         use crate::{
             definitions::{
-                Expression, FakeJoin, Join, JoinExpression, JoinType, LiteralValue, Pipeline,
-                ProjectItem, ProjectStage, Ref, Stage, UntaggedOperator, UntaggedOperatorName,
+                Alias, Expression, FakeJoin, Join, JoinExpression, JoinType, LiteralValue, Pipeline,
+                ProjectItem, ProjectStage, ReadConcern, Recursive, Ref, Stage, UntaggedOperator,
+                UntaggedOperatorName,
             },
             map,
         };
@@ -798,14 +812,97 @@ mod stage_test {
                                 Expression::Ref(Ref::FieldRef("c.bar".to_string())),
                                 Expression::Ref(Ref::FieldRef("d.bar".to_string()))
                             ]
-                        }))
+                        })),
+                        exclude_implicit: None,
+                        read_concern: None
                     })
                 ],
-                condition: None
+                condition: None,
+                exclude_implicit: None,
+                read_concern: None
             }))),
             input = r#"stage: {"$join": {"$inner": {"root": "z", "args": ["a", "b", {"$left": {"args": ["c", "d"], "condition": {"$gt": ["$c.bar", "$d.bar"]}}}]}}}"#
         );
 
+        test_serde_stage!(
+            babel_join_alias,
+            expected = Stage::Join(Box::new(Join::Inner(JoinExpression {
+                root: Some("Employee".to_string()),
+                args: vec![
+                    Join::Alias(Alias {
+                        entity: "Employee".to_string(),
+                        as_var: Some("Manager".to_string()),
+                        via: Some("manager".to_string())
+                    }),
+                    Join::Alias(Alias {
+                        entity: "Address".to_string(),
+                        as_var: Some("Home".to_string()),
+                        via: None
+                    }),
+                    Join::Alias(Alias {
+                        entity: "Address".to_string(),
+                        as_var: None,
+                        via: Some("billingAddress".to_string())
+                    })
+                ],
+                condition: None,
+                exclude_implicit: None,
+                read_concern: None
+            }))),
+            input = r#"stage: {"$join": {"$inner": {"root": "Employee", "args": [{"entity": "Employee", "as": "Manager", "via": "manager"}, {"entity": "Address", "as": "Home"}, {"entity": "Address", "via": "billingAddress"}]}}}"#
+        );
+
+        test_serde_stage!(
+            babel_join_recursive,
+            expected = Stage::Join(Box::new(Join::Inner(JoinExpression {
+                root: Some("Category".to_string()),
+                args: vec![
+                    Join::Recursive(Recursive {
+                        entity: "Category".to_string(),
+                        as_var: Some("Ancestors".to_string()),
+                        via: Some("parent".to_string()),
+                        max_depth: Some(5),
+                        depth_field: Some("depth".to_string())
+                    }),
+                    Join::Recursive(Recursive {
+                        entity: "Employee".to_string(),
+                        as_var: None,
+                        via: None,
+                        max_depth: None,
+                        depth_field: None
+                    })
+                ],
+                condition: None,
+                exclude_implicit: None,
+                read_concern: None
+            }))),
+            input = r#"stage: {"$join": {"$inner": {"root": "Category", "args": [{"$recursive": {"entity": "Category", "as": "Ancestors", "via": "parent", "maxDepth": 5, "depthField": "depth"}}, {"$recursive": {"entity": "Employee"}}]}}}"#
+        );
+
+        test_serde_stage!(
+            babel_join_exclude_implicit,
+            expected = Stage::Join(Box::new(Join::Inner(JoinExpression {
+                root: Some("z".to_string()),
+                args: vec![Join::Entity("a".to_string())],
+                condition: None,
+                exclude_implicit: Some(true),
+                read_concern: None
+            }))),
+            input = r#"stage: {"$join": {"$inner": {"root": "z", "args": ["a"], "excludeImplicit": true}}}"#
+        );
+
+        test_serde_stage!(
+            babel_join_read_concern,
+            expected = Stage::Join(Box::new(Join::Inner(JoinExpression {
+                root: Some("z".to_string()),
+                args: vec![Join::Entity("a".to_string())],
+                condition: None,
+                exclude_implicit: None,
+                read_concern: Some(ReadConcern::Strong)
+            }))),
+            input = r#"stage: {"$join": {"$inner": {"root": "z", "args": ["a"], "readConcern": "strong"}}}"#
+        );
+
         test_serde_stage!(
             inner_join,
             expected = Stage::FakeJoin(Box::new(FakeJoin {
@@ -1181,6 +1278,56 @@ mod stage_test {
             })),
             input = r#"stage: { "$lookup": { "from": "coll", "as": "__subquery_result_0", "localField": "foo", "foreignField": "bar" }}"#
         );
+
+        test_serde_stage!(
+            equality_lookup_from_namespace,
+            expected = Stage::Lookup(Lookup::Equality(EqualityLookup {
+                from: LookupFrom::Namespace(Namespace {
+                    db: "from_db".to_string(),
+                    coll: "from_coll".to_string()
+                }),
+                as_var: "as_var".to_string(),
+                local_field: "foo".to_string(),
+                foreign_field: "bar".to_string()
+            })),
+            input = r#"stage: {"$lookup": {
+                "from": {"db": "from_db", "coll": "from_coll"},
+                "localField": "foo",
+                "foreignField": "bar",
+                "as": "as_var"
+            }}"#
+        );
+
+        test_serde_stage!(
+            concise_subquery_lookup_from_namespace,
+            expected = Stage::Lookup(Lookup::ConciseSubquery(ConciseSubqueryLookup {
+                from: Some(LookupFrom::Namespace(Namespace {
+                    db: "from_db".to_string(),
+                    coll: "from_coll".to_string()
+                })),
+                let_body: None,
+                pipeline: Pipeline { pipeline: vec![] },
+                as_var: "as_var".to_string(),
+                local_field: "foo".to_string(),
+                foreign_field: "bar".to_string()
+            })),
+            input = r#"stage: {"$lookup": {
+                "from": {"db": "from_db", "coll": "from_coll"},
+                "localField": "foo",
+                "foreignField": "bar",
+                "pipeline": [],
+                "as": "as_var"
+            }}"#
+        );
+
+        #[test]
+        fn lookup_from_namespace_requires_db_and_coll() {
+            let e = serde_json::from_str::<LookupFrom>(r#"{"coll": "from_coll"}"#).unwrap_err();
+            assert!(e.to_string().contains("missing field `db`"), "{}", e);
+            let e = serde_json::from_str::<LookupFrom>(r#"{"db": "from_db", "collection": "c"}"#)
+                .unwrap_err();
+            assert!(e.to_string().contains("unknown field `collection`"), "{}", e);
+        }
     }
 
     mod group_test {
@@ -1271,6 +1418,13 @@ mod stage_test {
             }),
             input = r#"stage: { "$group": { "_id": null, "acc": { "$addToSet": "$a" } } }"#
         );
+
+        // accumulators are unary and reject an argument list
+        test_serialize_stage!(
+            serialize_accumulator_argument_bare,
+            expected = r#"{"$group": {"_id": null, "total": {"$sum": "$a"}}}"#,
+            input = r#"{"$group": {"_id": null, "total": {"$sum": ["$a"]}}}"#
+        );
     }
 
     mod add_fields {
@@ -2019,6 +2173,89 @@ mod stage_test {
         );
     }
 
+    mod merge {
+        use crate::{
+            definitions::{
+                Expression, Merge, MergeInto, MergeOn, MergeWhenMatched, MergeWhenNotMatched,
+                Namespace, Pipeline, Ref, Stage,
+            },
+            map,
+        };
+
+        test_serde_stage!(
+            into_collection,
+            expected = Stage::Merge(Merge {
+                into: MergeInto::Collection("coll".to_string()),
+                on: None,
+                let_body: None,
+                when_matched: None,
+                when_not_matched: None,
+            }),
+            input = r#"stage: {"$merge": {"into": "coll"}}"#
+        );
+
+        test_serde_stage!(
+            into_namespace_with_options,
+            expected = Stage::Merge(Merge {
+                into: MergeInto::Namespace(Namespace {
+                    db: "db".to_string(),
+                    coll: "coll".to_string(),
+                }),
+                on: Some(MergeOn::Multiple(vec!["a".to_string(), "b".to_string()])),
+                let_body: None,
+                when_matched: Some(MergeWhenMatched::KeepExisting),
+                when_not_matched: Some(MergeWhenNotMatched::Discard),
+            }),
+            input = r#"stage: {"$merge": {
+                "into": {"db": "db", "coll": "coll"},
+                "on": ["a", "b"],
+                "whenMatched": "keepExisting",
+                "whenNotMatched": "discard"
+            }}"#
+        );
+
+        test_serde_stage!(
+            when_matched_pipeline,
+            expected = Stage::Merge(Merge {
+                into: MergeInto::Collection("coll".to_string()),
+                on: Some(MergeOn::Single("_id".to_string())),
+                let_body: Some(map! {
+                    "new".to_string() => Expression::Ref(Ref::VariableRef("ROOT".to_string())),
+                }),
+                when_matched: Some(MergeWhenMatched::Pipeline(Pipeline {
+                    pipeline: vec![Stage::Limit(1)]
+                })),
+                when_not_matched: Some(MergeWhenNotMatched::Insert),
+            }),
+            input = r#"stage: {"$merge": {
+                "into": "coll",
+                "on": "_id",
+                "let": {"new": "$$ROOT"},
+                "whenMatched": [{"$limit": 1}],
+                "whenNotMatched": "insert"
+            }}"#
+        );
+    }
+
+    mod out {
+        use crate::definitions::{Namespace, Out, Stage};
+
+        test_serde_stage!(
+            collection,
+            expected = Stage::Out(Out::Collection("coll".to_string())),
+            input = r#"stage: {"$out": "coll"}"#
+        );
+
+        test_serde_stage!(
+            namespace,
+            expected = Stage::Out(Out::Namespace(Namespace {
+                db: "db".to_string(),
+                coll: "coll".to_string(),
+            })),
+            input = r#"stage: {"$out": {"db": "db", "coll": "coll"}}"#
+        );
+    }
+
     mod search_stages {
         use crate::{
             definitions::{AtlasSearchStage, Expression, GraphLookup, LiteralValue, Ref, Stage},
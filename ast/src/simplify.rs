@@ -0,0 +1,381 @@
+use crate::{
+    definitions::{
+        visitor::Visitor, Cond, Expression, GetField, LiteralValue, MatchExpr, Pipeline,
+        TaggedOperator, UntaggedOperator, UntaggedOperatorName,
+    },
+    negative_normalize::NegativeNormalize,
+};
+use std::cmp::Ordering;
+
+impl Expression {
+    /// simplify folds the parts of the expression that do not depend on the document:
+    ///
+    /// - arithmetic ($add, $subtract, $multiply, $divide) and comparisons ($eq, $ne, $lt, $lte,
+    ///   $gt, $gte, $cmp) of literals
+    /// - $getField of a field of a document literal
+    /// - $and and $or: literal operands are evaluated, nested operators of the same kind are
+    ///   flattened, duplicate operands are removed, and an operand next to its negation makes the
+    ///   whole operator false for $and and true for $or
+    /// - $not of a literal, and $not of $not of a boolean
+    /// - $cond with a literal condition
+    ///
+    /// The arguments of $literal are values, and are left alone. Comparisons of different
+    /// strings are not folded, since their order depends on the collation.
+    pub fn simplify(self) -> Expression {
+        SimplifyVisitor.visit_expression(self)
+    }
+}
+
+impl Pipeline {
+    /// simplify_matches simplifies the $expr of every $match stage of the pipeline, including
+    /// those of its subpipelines. Other expressions are left alone, since a folded literal has a
+    /// different meaning in stages such as $project.
+    pub fn simplify_matches(self) -> Pipeline {
+        MatchSimplifyVisitor.visit_pipeline(self)
+    }
+}
+
+struct SimplifyVisitor;
+
+impl Visitor for SimplifyVisitor {
+    fn visit_expression(&mut self, expression: Expression) -> Expression {
+        match expression {
+            Expression::UntaggedOperator(UntaggedOperator {
+                op: UntaggedOperatorName::Literal,
+                ..
+            }) => expression,
+            expression => simplify_operator(expression.walk(self)),
+        }
+    }
+}
+
+struct MatchSimplifyVisitor;
+
+impl Visitor for MatchSimplifyVisitor {
+    fn visit_match_expr(&mut self, node: MatchExpr) -> MatchExpr {
+        MatchExpr {
+            expr: Box::new(node.expr.simplify()),
+        }
+    }
+}
+
+// simplify_operator simplifies the top level operator of an expression whose arguments are
+// already simplified.
+fn simplify_operator(expression: Expression) -> Expression {
+    match expression {
+        Expression::UntaggedOperator(UntaggedOperator { op, args }) => {
+            let folded = match op {
+                UntaggedOperatorName::And => return simplify_logical(op, args),
+                UntaggedOperatorName::Or => return simplify_logical(op, args),
+                UntaggedOperatorName::Not => simplify_not(&args),
+                UntaggedOperatorName::Eq
+                | UntaggedOperatorName::Ne
+                | UntaggedOperatorName::Lt
+                | UntaggedOperatorName::Lte
+                | UntaggedOperatorName::Gt
+                | UntaggedOperatorName::Gte
+                | UntaggedOperatorName::Cmp => fold_comparison(op, &args),
+                UntaggedOperatorName::Add
+                | UntaggedOperatorName::Subtract
+                | UntaggedOperatorName::Multiply
+                | UntaggedOperatorName::Divide => fold_arithmetic(op, &args),
+                UntaggedOperatorName::Cond => match args.as_slice() {
+                    [_if, then, _else] => {
+                        truthiness(_if).map(|b| if b { then.clone() } else { _else.clone() })
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            folded.unwrap_or(Expression::UntaggedOperator(UntaggedOperator { op, args }))
+        }
+        Expression::TaggedOperator(TaggedOperator::GetField(GetField { field, input })) => {
+            match *input {
+                Expression::Document(ref document) if document.contains_key(&field) => {
+                    document[&field].clone()
+                }
+                _ => {
+                    Expression::TaggedOperator(TaggedOperator::GetField(GetField { field, input }))
+                }
+            }
+        }
+        Expression::TaggedOperator(TaggedOperator::Cond(Cond { _if, then, _else })) => {
+            match truthiness(&_if) {
+                Some(true) => *then,
+                Some(false) => *_else,
+                None => Expression::TaggedOperator(TaggedOperator::Cond(Cond { _if, then, _else })),
+            }
+        }
+        expression => expression,
+    }
+}
+
+// simplify_logical simplifies an $and or an $or. The absorbing value is the literal operand
+// value that decides the result: false for $and, and true for $or.
+fn simplify_logical(op: UntaggedOperatorName, args: Vec<Expression>) -> Expression {
+    let absorbing = op == UntaggedOperatorName::Or;
+    let mut operands: Vec<Expression> = Vec::new();
+    let mut pending = args;
+    pending.reverse();
+    while let Some(arg) = pending.pop() {
+        match arg {
+            Expression::UntaggedOperator(UntaggedOperator { op: nested, args }) if nested == op => {
+                pending.extend(args.into_iter().rev());
+            }
+            arg => match truthiness(&arg) {
+                Some(b) if b == absorbing => return boolean(absorbing),
+                Some(_) => {}
+                None if operands.contains(&arg) => {}
+                None if operands.iter().any(|operand| complements(operand, &arg)) => {
+                    return boolean(absorbing);
+                }
+                None => operands.push(arg),
+            },
+        }
+    }
+    match operands.len() {
+        0 => boolean(!absorbing),
+        // a single operand is converted to a boolean by the operator
+        1 if is_boolean(&operands[0]) => operands.remove(0),
+        _ => Expression::UntaggedOperator(UntaggedOperator { op, args: operands }),
+    }
+}
+
+fn simplify_not(args: &[Expression]) -> Option<Expression> {
+    let [arg] = args else {
+        return None;
+    };
+    if let Some(b) = truthiness(arg) {
+        return Some(boolean(!b));
+    }
+    match arg {
+        Expression::UntaggedOperator(UntaggedOperator {
+            op: UntaggedOperatorName::Not,
+            args,
+        }) => match args.as_slice() {
+            [arg] if is_boolean(arg) => Some(arg.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// complements returns true if the truthiness of one expression is always the opposite of the
+// truthiness of the other.
+fn complements(a: &Expression, b: &Expression) -> bool {
+    let negation = |e: &Expression| match e {
+        Expression::UntaggedOperator(UntaggedOperator {
+            op:
+                UntaggedOperatorName::Eq
+                | UntaggedOperatorName::Ne
+                | UntaggedOperatorName::Lt
+                | UntaggedOperatorName::Lte
+                | UntaggedOperatorName::Gt
+                | UntaggedOperatorName::Gte,
+            ..
+        }) => Some(e.get_negation()),
+        Expression::UntaggedOperator(UntaggedOperator {
+            op: UntaggedOperatorName::Not,
+            args,
+        }) if args.len() == 1 => Some(args[0].clone()),
+        _ => None,
+    };
+    negation(a).as_ref() == Some(b) || negation(b).as_ref() == Some(a)
+}
+
+// is_boolean returns true if the expression always evaluates to a boolean.
+fn is_boolean(expression: &Expression) -> bool {
+    matches!(
+        expression,
+        Expression::Literal(LiteralValue::Boolean(_))
+            | Expression::UntaggedOperator(UntaggedOperator {
+                op: UntaggedOperatorName::And
+                    | UntaggedOperatorName::Or
+                    | UntaggedOperatorName::Not
+                    | UntaggedOperatorName::Eq
+                    | UntaggedOperatorName::Ne
+                    | UntaggedOperatorName::Lt
+                    | UntaggedOperatorName::Lte
+                    | UntaggedOperatorName::Gt
+                    | UntaggedOperatorName::Gte
+                    | UntaggedOperatorName::In
+                    | UntaggedOperatorName::IsArray
+                    | UntaggedOperatorName::IsNumber,
+                ..
+            })
+    )
+}
+
+// truthiness returns whether a literal is truthy: false, null, undefined and zero are falsy,
+// everything else is truthy. It returns None for non-literals and for decimals, whose zeros
+// have many representations.
+fn truthiness(expression: &Expression) -> Option<bool> {
+    let Expression::Literal(literal) = expression else {
+        return None;
+    };
+    match literal {
+        LiteralValue::Boolean(b) => Some(*b),
+        LiteralValue::Null | LiteralValue::Undefined => Some(false),
+        LiteralValue::Int32(i) => Some(*i != 0),
+        LiteralValue::Int64(i) => Some(*i != 0),
+        LiteralValue::Double(d) => Some(*d != 0.0),
+        LiteralValue::Decimal128(_) => None,
+        _ => Some(true),
+    }
+}
+
+fn boolean(b: bool) -> Expression {
+    Expression::Literal(LiteralValue::Boolean(b))
+}
+
+fn fold_comparison(op: UntaggedOperatorName, args: &[Expression]) -> Option<Expression> {
+    let [Expression::Literal(a), Expression::Literal(b)] = args else {
+        return None;
+    };
+    let ordering = compare(a, b)?;
+    Some(match op {
+        UntaggedOperatorName::Eq => boolean(ordering == Ordering::Equal),
+        UntaggedOperatorName::Ne => boolean(ordering != Ordering::Equal),
+        UntaggedOperatorName::Lt => boolean(ordering == Ordering::Less),
+        UntaggedOperatorName::Lte => boolean(ordering != Ordering::Greater),
+        UntaggedOperatorName::Gt => boolean(ordering == Ordering::Greater),
+        UntaggedOperatorName::Gte => boolean(ordering != Ordering::Less),
+        UntaggedOperatorName::Cmp => Expression::Literal(LiteralValue::Int32(ordering as i32)),
+        _ => return None,
+    })
+}
+
+// compare orders literals of the types it knows the BSON comparison order of, which compares
+// types first: null, then numbers, then strings, then booleans.
+fn compare(a: &LiteralValue, b: &LiteralValue) -> Option<Ordering> {
+    let rank = |literal: &LiteralValue| match literal {
+        LiteralValue::Null => Some(0),
+        LiteralValue::Int32(_) | LiteralValue::Int64(_) | LiteralValue::Double(_) => Some(1),
+        LiteralValue::String(_) => Some(2),
+        LiteralValue::Boolean(_) => Some(3),
+        _ => None,
+    };
+    match rank(a)?.cmp(&rank(b)?) {
+        Ordering::Equal => {}
+        ordering => return Some(ordering),
+    }
+    match (a, b) {
+        (LiteralValue::Null, LiteralValue::Null) => Some(Ordering::Equal),
+        (LiteralValue::String(a), LiteralValue::String(b)) => (a == b).then_some(Ordering::Equal),
+        (LiteralValue::Boolean(a), LiteralValue::Boolean(b)) => Some(a.cmp(b)),
+        (a, b) => Number::from_literal(a)?.compare(Number::from_literal(b)?),
+    }
+}
+
+fn fold_arithmetic(op: UntaggedOperatorName, args: &[Expression]) -> Option<Expression> {
+    let numbers = args
+        .iter()
+        .map(|arg| match arg {
+            Expression::Literal(literal) => Number::from_literal(literal),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let result = match (op, numbers.as_slice()) {
+        (UntaggedOperatorName::Add, [first, rest @ ..]) => rest
+            .iter()
+            .try_fold(*first, |a, b| a.apply(*b, i64::checked_add, |a, b| a + b))?,
+        (UntaggedOperatorName::Multiply, [first, rest @ ..]) => rest
+            .iter()
+            .try_fold(*first, |a, b| a.apply(*b, i64::checked_mul, |a, b| a * b))?,
+        (UntaggedOperatorName::Subtract, [a, b]) => a.apply(*b, i64::checked_sub, |a, b| a - b)?,
+        // division by zero is an error, which is left for the server to report
+        (UntaggedOperatorName::Divide, [a, b]) if b.as_f64() != 0.0 => {
+            Number::Double(a.as_f64() / b.as_f64())
+        }
+        _ => return None,
+    };
+    Some(Expression::Literal(result.into_literal()))
+}
+
+// Number is a numeric literal that can be folded. Decimals are not folded.
+#[derive(Clone, Copy)]
+enum Number {
+    Int32(i32),
+    Int64(i64),
+    Double(f64),
+}
+
+// MAX_EXACT_DOUBLE is the largest magnitude up to which every integer is exactly a double.
+const MAX_EXACT_DOUBLE: u64 = 1 << 53;
+
+impl Number {
+    fn from_literal(literal: &LiteralValue) -> Option<Number> {
+        match literal {
+            LiteralValue::Int32(i) => Some(Number::Int32(*i)),
+            LiteralValue::Int64(i) => Some(Number::Int64(*i)),
+            LiteralValue::Double(d) => Some(Number::Double(*d)),
+            _ => None,
+        }
+    }
+
+    fn into_literal(self) -> LiteralValue {
+        match self {
+            Number::Int32(i) => LiteralValue::Int32(i),
+            Number::Int64(i) => LiteralValue::Int64(i),
+            Number::Double(d) => LiteralValue::Double(d),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int32(i) => i as f64,
+            Number::Int64(i) => i as f64,
+            Number::Double(d) => d,
+        }
+    }
+
+    fn as_i64(self) -> Option<i64> {
+        match self {
+            Number::Int32(i) => Some(i as i64),
+            Number::Int64(i) => Some(i),
+            Number::Double(_) => None,
+        }
+    }
+
+    // apply computes a binary operation with the result type the server uses: a double if
+    // either operand is a double, an int if both are ints and the result fits, and a long
+    // otherwise. Long overflow, which the server turns into a double, is not folded.
+    fn apply(
+        self,
+        other: Number,
+        int: fn(i64, i64) -> Option<i64>,
+        double: fn(f64, f64) -> f64,
+    ) -> Option<Number> {
+        match (self, other) {
+            (Number::Double(_), _) | (_, Number::Double(_)) => {
+                Some(Number::Double(double(self.as_f64(), other.as_f64())))
+            }
+            (Number::Int32(a), Number::Int32(b)) => {
+                let result = int(a as i64, b as i64)?;
+                Some(i32::try_from(result).map_or(Number::Int64(result), Number::Int32))
+            }
+            _ => int(self.as_i64()?, other.as_i64()?).map(Number::Int64),
+        }
+    }
+
+    // compare orders numbers by value, with NaN equal to itself and less than every other
+    // number. Longs too large to be exactly a double are not compared to doubles.
+    fn compare(self, other: Number) -> Option<Ordering> {
+        if let (Some(a), Some(b)) = (self.as_i64(), other.as_i64()) {
+            return Some(a.cmp(&b));
+        }
+        if [self, other].iter().any(|n| {
+            n.as_i64()
+                .is_some_and(|i| i.unsigned_abs() > MAX_EXACT_DOUBLE)
+        }) {
+            return None;
+        }
+        let (a, b) = (self.as_f64(), other.as_f64());
+        Some(match (a.is_nan(), b.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => a.partial_cmp(&b)?,
+        })
+    }
+}
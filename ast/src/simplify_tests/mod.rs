@@ -0,0 +1,165 @@
+macro_rules! test_simplify {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::definitions::Expression;
+
+            let input: Expression = serde_json::from_str($input).unwrap();
+            let expected: Expression = serde_json::from_str($expected).unwrap();
+            let result = input.simplify();
+            assert_eq!(expected, result);
+        }
+    };
+}
+
+#[cfg(test)]
+mod arithmetic {
+    test_simplify!(add, expected = r#"6"#, input = r#"{"$add": [1, 2, 3]}"#);
+
+    test_simplify!(
+        add_double,
+        expected = r#"3.5"#,
+        input = r#"{"$add": [1, 2.5]}"#
+    );
+
+    test_simplify!(
+        nested,
+        expected = r#"{"$eq": ["$a", 10]}"#,
+        input = r#"{"$eq": ["$a", {"$multiply": [{"$subtract": [7, 2]}, 2]}]}"#
+    );
+
+    test_simplify!(
+        divide,
+        expected = r#"2.5"#,
+        input = r#"{"$divide": [5, 2]}"#
+    );
+
+    test_simplify!(
+        divide_by_zero,
+        expected = r#"{"$divide": [5, 0]}"#,
+        input = r#"{"$divide": [5, 0]}"#
+    );
+
+    test_simplify!(
+        field_ref,
+        expected = r#"{"$add": ["$a", 1]}"#,
+        input = r#"{"$add": ["$a", 1]}"#
+    );
+}
+
+#[cfg(test)]
+mod comparison {
+    test_simplify!(eq, expected = r#"true"#, input = r#"{"$eq": [1, 1.0]}"#);
+
+    test_simplify!(
+        lt_across_types,
+        expected = r#"true"#,
+        input = r#"{"$lt": [null, 0]}"#
+    );
+
+    test_simplify!(
+        cmp,
+        expected = r#"{"$numberInt": "-1"}"#,
+        input = r#"{"$cmp": [false, true]}"#
+    );
+
+    test_simplify!(
+        different_strings,
+        expected = r#"{"$lt": ["a", "b"]}"#,
+        input = r#"{"$lt": ["a", "b"]}"#
+    );
+
+    test_simplify!(
+        literal_operator,
+        expected = r#"{"$eq": [{"$literal": {"$add": [1, 2]}}, 3]}"#,
+        input = r#"{"$eq": [{"$literal": {"$add": [1, 2]}}, 3]}"#
+    );
+}
+
+#[cfg(test)]
+mod get_field {
+    test_simplify!(
+        document,
+        expected = r#"true"#,
+        input = r#"{"$eq": [{"$getField": {"input": {"a": 1}, "field": "a"}}, 1]}"#
+    );
+
+    test_simplify!(
+        missing_field,
+        expected = r#"{"$getField": {"input": {"a": 1}, "field": "b"}}"#,
+        input = r#"{"$getField": {"input": {"a": 1}, "field": "b"}}"#
+    );
+}
+
+#[cfg(test)]
+mod logical {
+    test_simplify!(
+        and_true,
+        expected = r#"{"$eq": ["$a", 1]}"#,
+        input = r#"{"$and": [true, {"$eq": ["$a", 1]}]}"#
+    );
+
+    test_simplify!(
+        and_false,
+        expected = r#"false"#,
+        input = r#"{"$and": [{"$eq": ["$a", 1]}, 0]}"#
+    );
+
+    test_simplify!(
+        and_single_non_boolean,
+        expected = r#"{"$and": ["$a"]}"#,
+        input = r#"{"$and": [true, "$a"]}"#
+    );
+
+    test_simplify!(
+        and_flatten_and_dedupe,
+        expected = r#"{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", 2]}]}"#,
+        input =
+            r#"{"$and": [{"$eq": ["$a", 1]}, {"$and": [{"$eq": ["$b", 2]}, {"$eq": ["$a", 1]}]}]}"#
+    );
+
+    test_simplify!(
+        and_contradiction,
+        expected = r#"false"#,
+        input = r#"{"$and": [{"$lt": ["$a", 1]}, {"$eq": ["$b", 2]}, {"$gte": ["$a", 1]}]}"#
+    );
+
+    test_simplify!(
+        or_tautology,
+        expected = r#"true"#,
+        input = r#"{"$or": ["$a", {"$not": ["$a"]}]}"#
+    );
+
+    test_simplify!(
+        or_false,
+        expected = r#"false"#,
+        input = r#"{"$or": [false, null]}"#
+    );
+
+    test_simplify!(
+        not_literal,
+        expected = r#"true"#,
+        input = r#"{"$not": [0]}"#
+    );
+
+    test_simplify!(
+        double_not,
+        expected = r#"{"$eq": ["$a", 1]}"#,
+        input = r#"{"$not": [{"$not": [{"$eq": ["$a", 1]}]}]}"#
+    );
+}
+
+#[cfg(test)]
+mod cond {
+    test_simplify!(
+        untagged,
+        expected = r#""$a""#,
+        input = r#"{"$cond": [{"$gt": [2, 1]}, "$a", "$b"]}"#
+    );
+
+    test_simplify!(
+        tagged,
+        expected = r#""$b""#,
+        input = r#"{"$cond": {"if": null, "then": "$a", "else": "$b"}}"#
+    );
+}
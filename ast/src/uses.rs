@@ -1,9 +1,10 @@
 use crate::{
     definitions::{
-        visitor::Visitor, visitor_ref::VisitorRef, Expression, GetField, Lookup, ProjectItem, Ref,
-        Stage, TaggedOperator, Unwind,
+        visitor::Visitor, visitor_ref::VisitorRef, Expression, GetField, LiteralValue, Lookup,
+        Pipeline, ProjectItem, Ref, ReplaceStage, SetWindowFields, Stage, TaggedOperator, Unset,
+        UntaggedOperator, UntaggedOperatorName, Unwind,
     },
-    set,
+    map, set, REMOVE_NAME, ROOT_NAME,
 };
 use std::collections::{HashMap, HashSet};
 
@@ -31,7 +32,16 @@ impl Uses {
         self.0.is_empty()
     }
 
-    pub fn into_iter(self) -> impl IntoIterator<Item = String> {
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.0.iter()
+    }
+}
+
+impl IntoIterator for Uses {
+    type Item = String;
+    type IntoIter = std::collections::hash_set::IntoIter<String>;
+
+    fn into_iter(self) -> Self::IntoIter {
         self.0.into_iter()
     }
 }
@@ -194,32 +204,28 @@ impl Expression {
     }
 }
 
+impl Pipeline {
+    pub fn variable_uses(&self) -> Uses {
+        let mut visitor = VarUsesVisitor { u: HashSet::new() };
+        visitor.visit_pipeline(self);
+        Uses(visitor.u)
+    }
+}
+
 impl Stage {
     pub fn opaque_defines(&self) -> Option<HashSet<String>> {
         Some(match self {
-            Stage::Group(group) => group
-                .aggregations
-                .keys()
-                .cloned()
-                .chain(["_id".to_string()].into_iter())
-                .collect(),
+            // the _id of $group and $sortByCount is in defines
+            Stage::Group(group) => group.aggregations.keys().cloned().collect(),
+            Stage::SortByCount(_) => set!["count".to_string()],
+            Stage::SetWindowFields(stage) => stage.output.keys().cloned().collect(),
             Stage::Lookup(Lookup::Equality(lookup)) => set![lookup.as_var.clone()],
             Stage::Lookup(Lookup::ConciseSubquery(lookup)) => set![lookup.as_var.clone()],
             Stage::Lookup(Lookup::Subquery(lookup)) => set![lookup.as_var.clone()],
-            Stage::Unwind(Unwind::FieldPath(expr)) => {
-                if let Expression::Ref(Ref::FieldRef(ref field)) = *expr {
-                    set![field.clone()]
-                } else {
-                    unreachable!()
-                }
-            }
+            Stage::GraphLookup(lookup) => set![lookup.as_var.clone()],
+            Stage::Unwind(Unwind::FieldPath(expr)) => set![unwind_field(expr)?.clone()],
             Stage::Unwind(Unwind::Document(expr)) => {
-                let mut ret: HashSet<_> =
-                    if let Expression::Ref(Ref::FieldRef(ref field)) = *expr.path {
-                        set![field.clone()]
-                    } else {
-                        unreachable!()
-                    };
+                let mut ret: HashSet<_> = set![unwind_field(&expr.path)?.clone()];
                 if let Some(include_array_index) = &expr.include_array_index {
                     ret.insert(include_array_index.clone());
                 }
@@ -236,22 +242,205 @@ impl Stage {
             Stage::AddFields(fields) => {
                 fields.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
             }
-            Stage::Project(stage) => stage
-                .items
+            Stage::Project(stage) => {
+                let mut defines: HashMap<_, _> = stage
+                    .items
+                    .iter()
+                    .map(|(k, v)| {
+                        (
+                            k.clone(),
+                            match v {
+                                ProjectItem::Assignment(expr) => expr.clone(),
+                                ProjectItem::Inclusion => Expression::Ref(Ref::FieldRef(k.clone())),
+                                ProjectItem::Exclusion => remove(),
+                            },
+                        )
+                    })
+                    .collect();
+                // inclusion projections keep _id unless it is excluded
+                if self.replaces_document() {
+                    defines
+                        .entry("_id".to_string())
+                        .or_insert_with(|| Expression::Ref(Ref::FieldRef("_id".to_string())));
+                }
+                defines
+            }
+            Stage::Unset(Unset::Single(field)) => map! {field.clone() => remove()},
+            Stage::Unset(Unset::Multiple(fields)) => fields
                 .iter()
-                .flat_map(|(k, v)| {
-                    Some((
-                        k.clone(),
-                        match v {
-                            ProjectItem::Assignment(expr) => expr.clone(),
-                            ProjectItem::Inclusion => Expression::Ref(Ref::FieldRef(k.clone())),
-                            _ => None?,
-                        },
-                    ))
-                })
+                .map(|field| (field.clone(), remove()))
                 .collect(),
-            Stage::Match(_) | Stage::Lookup(_) | Stage::Unwind(_) => HashMap::new(),
-            _ => None?,
+            Stage::ReplaceWith(ReplaceStage::NewRoot(root) | ReplaceStage::Expression(root)) => {
+                match root {
+                    Expression::Document(document) => document
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                    Expression::Ref(Ref::VariableRef(var)) if var == ROOT_NAME => HashMap::new(),
+                    // the fields of other roots are only known for given uses, see
+                    // defines_for_uses
+                    _ => None?,
+                }
+            }
+            // filtering on the group keys keeps or drops whole groups
+            Stage::Group(group) => map! {"_id".to_string() => group_key(&group.keys)},
+            Stage::SortByCount(expr) => map! {"_id".to_string() => group_key(expr)},
+            Stage::Unwind(Unwind::FieldPath(expr)) => {
+                unwind_field(expr)?;
+                HashMap::new()
+            }
+            Stage::Unwind(Unwind::Document(expr)) => {
+                unwind_field(&expr.path)?;
+                HashMap::new()
+            }
+            // filtering commutes with sorting
+            Stage::Match(_) | Stage::Lookup(_) | Stage::GraphLookup(_) | Stage::Sort(_) => {
+                HashMap::new()
+            }
+            // stages that change the number or order of documents a filter sees, such as
+            // $limit, $skip, $sample or $densify, stages that must stay first or last, and
+            // stages that are rewritten away before match movement
+            Stage::SubPipeline(_)
+            | Stage::Assemble(_)
+            | Stage::Collection(_)
+            | Stage::Conjure(_)
+            | Stage::Documents(_)
+            | Stage::Limit(_)
+            | Stage::Skip(_)
+            | Stage::FakeJoin(_)
+            | Stage::Join(_)
+            | Stage::EquiJoin(_)
+            | Stage::Redact(_)
+            | Stage::SetWindowFields(_)
+            | Stage::Bucket(_)
+            | Stage::BucketAuto(_)
+            | Stage::Count(_)
+            | Stage::Densify(_)
+            | Stage::Facet(_)
+            | Stage::Fill(_)
+            | Stage::GeoNear(_)
+            | Stage::Sample(_)
+            | Stage::UnionWith(_)
+            | Stage::Merge(_)
+            | Stage::Out(_)
+            | Stage::Sentinel
+            | Stage::AtlasSearchStage(_) => None?,
         })
     }
+
+    /// replaces_document returns true if the documents the stage outputs only have the fields
+    /// the stage defines.
+    pub fn replaces_document(&self) -> bool {
+        match self {
+            Stage::Project(stage) => stage
+                .items
+                .values()
+                .any(|item| !matches!(item, ProjectItem::Exclusion)),
+            Stage::ReplaceWith(_)
+            | Stage::Group(_)
+            | Stage::SortByCount(_)
+            | Stage::Bucket(_)
+            | Stage::BucketAuto(_)
+            | Stage::Count(_)
+            | Stage::Facet(_) => true,
+            _ => false,
+        }
+    }
+
+    /// defines_for_uses returns the definitions to substitute into an expression using the uses
+    /// to move it before the stage, or None if it cannot move before the stage. On top of
+    /// defines, the fields a $replaceWith root is not known to have are looked up in the root,
+    /// the fields of a replaced document the stage does not define are $$REMOVE, and a
+    /// $setWindowFields can be crossed by expressions using only its partitionBy field, since
+    /// those keep or drop whole partitions. An expression using a field the stage defines
+    /// subfields of cannot move.
+    pub fn defines_for_uses(&self, uses: &Uses) -> Option<HashMap<String, Expression>> {
+        let mut defines = match self {
+            Stage::ReplaceWith(ReplaceStage::NewRoot(root) | ReplaceStage::Expression(root)) => {
+                uses.iter()
+                    .map(|u| u.split('.').next().unwrap_or(u))
+                    .map(|field| (field.to_string(), root_field(root, field)))
+                    .collect()
+            }
+            Stage::SetWindowFields(SetWindowFields {
+                partition_by: Some(partition_by),
+                ..
+            }) => match partition_by.as_ref() {
+                Expression::Ref(Ref::FieldRef(field))
+                    if uses.iter().all(|u| is_path_prefix(field, u)) =>
+                {
+                    HashMap::new()
+                }
+                _ => None?,
+            },
+            stage => stage.defines()?,
+        };
+        if uses
+            .iter()
+            .any(|u| defines.keys().any(|k| k != u && is_path_prefix(u, k)))
+        {
+            return None;
+        }
+        if self.replaces_document() {
+            let missing: Vec<String> = uses
+                .iter()
+                .filter(|u| !defines.keys().any(|k| is_path_prefix(k, u)))
+                .cloned()
+                .collect();
+            for u in missing {
+                defines.insert(u, remove());
+            }
+        }
+        Some(defines)
+    }
+}
+
+// is_path_prefix returns true if path is prefix or one of its subfields.
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+// unwind_field returns the field an $unwind path refers to. Paths are always field refs in
+// valid MQL.
+fn unwind_field(path: &Expression) -> Option<&String> {
+    match path {
+        Expression::Ref(Ref::FieldRef(field)) => Some(field),
+        _ => None,
+    }
+}
+
+// root_field returns the field of the new root of a $replaceWith.
+fn root_field(root: &Expression, field: &str) -> Expression {
+    match root {
+        Expression::Ref(Ref::FieldRef(path)) => {
+            Expression::Ref(Ref::FieldRef(format!("{}.{}", path, field)))
+        }
+        Expression::Ref(Ref::VariableRef(var)) if var == ROOT_NAME => {
+            Expression::Ref(Ref::FieldRef(field.to_string()))
+        }
+        Expression::Document(document) => document.get(field).cloned().unwrap_or_else(remove),
+        root => Expression::TaggedOperator(TaggedOperator::GetField(GetField {
+            field: field.to_string(),
+            input: Box::new(root.clone()),
+        })),
+    }
+}
+
+// group_key is the _id a $group gives a document with the key: a missing key is grouped under
+// null. Missing fields of a document key are left out of the _id as they are of the key.
+fn group_key(key: &Expression) -> Expression {
+    match key {
+        Expression::Document(_) => key.clone(),
+        _ => Expression::UntaggedOperator(UntaggedOperator {
+            op: UntaggedOperatorName::IfNull,
+            args: vec![key.clone(), Expression::Literal(LiteralValue::Null)],
+        }),
+    }
+}
+
+fn remove() -> Expression {
+    Expression::Ref(Ref::VariableRef(REMOVE_NAME.to_string()))
 }
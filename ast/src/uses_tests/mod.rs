@@ -0,0 +1,90 @@
+macro_rules! test_move_before_stage {
+    ($func_name:ident, expected = $expected:expr, stage = $stage:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::definitions::{Expression, Stage};
+
+            let stage: Stage = serde_json::from_str($stage).unwrap();
+            let input: Expression = serde_json::from_str($input).unwrap();
+            let expected: Option<Expression> =
+                Option::<&str>::from($expected).map(|e| serde_json::from_str(e).unwrap());
+            let uses = input.uses();
+            let result = if stage
+                .opaque_defines()
+                .is_some_and(|defines| uses.prefix_overlap(&defines))
+            {
+                None
+            } else {
+                stage
+                    .defines_for_uses(&uses)
+                    .map(|defines| input.substitute(defines))
+            };
+            assert_eq!(expected, result);
+        }
+    };
+}
+
+test_move_before_stage!(
+    group_key_document,
+    expected =
+        Some(r#"{"$eq": [{"$getField": {"field": "a", "input": {"a": "$x", "b": "$y"}}}, 1]}"#),
+    stage = r#"{"$group": {"_id": {"a": "$x", "b": "$y"}, "total": {"$sum": "$z"}}}"#,
+    input = r#"{"$eq": ["$_id.a", 1]}"#
+);
+
+test_move_before_stage!(
+    accumulator_output_stays,
+    expected = None,
+    stage = r#"{"$group": {"_id": "$x", "total": {"$sum": "$z"}}}"#,
+    input = r#"{"$gt": ["$total", 10]}"#
+);
+
+test_move_before_stage!(
+    window_partition_field,
+    expected = Some(r#"{"$eq": ["$region", "north"]}"#),
+    stage = r#"{"$setWindowFields": {
+        "partitionBy": "$region",
+        "sortBy": {"day": 1},
+        "output": {"total": {"$sum": "$amount"}}
+    }}"#,
+    input = r#"{"$eq": ["$region", "north"]}"#
+);
+
+test_move_before_stage!(
+    window_other_field_stays,
+    expected = None,
+    stage = r#"{"$setWindowFields": {
+        "partitionBy": "$region",
+        "sortBy": {"day": 1},
+        "output": {"total": {"$sum": "$amount"}}
+    }}"#,
+    input = r#"{"$eq": ["$day", 1]}"#
+);
+
+test_move_before_stage!(
+    inclusion_project_removes_other_fields,
+    expected = Some(r#"{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$$REMOVE", null]}]}"#),
+    stage = r#"{"$project": {"a": 1}}"#,
+    input = r#"{"$and": [{"$eq": ["$a", 1]}, {"$eq": ["$b", null]}]}"#
+);
+
+test_move_before_stage!(
+    unwind_non_field_path_stays,
+    expected = None,
+    stage = r#"{"$unwind": {"path": {"$literal": [1, 2]}}}"#,
+    input = r#"{"$eq": ["$a", 1]}"#
+);
+
+test_move_before_stage!(
+    group_missing_key_is_null,
+    expected = Some(r#"{"$eq": [{"$ifNull": ["$a", null]}, null]}"#),
+    stage = r#"{"$group": {"_id": "$a", "total": {"$sum": "$z"}}}"#,
+    input = r#"{"$eq": ["$_id", null]}"#
+);
+
+test_move_before_stage!(
+    sort_by_count_missing_key_is_null,
+    expected = Some(r#"{"$eq": [{"$ifNull": ["$a", null]}, null]}"#),
+    stage = r#"{"$sortByCount": "$a"}"#,
+    input = r#"{"$eq": ["$_id", null]}"#
+);
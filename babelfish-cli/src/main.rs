@@ -1,7 +1,13 @@
 use ast::definitions::Pipeline;
-use babelfish::*;
-use clap::Parser;
-use schema::Erd;
+use babelfish::{
+    erd::Relationships,
+    erd_graph::ErdGraph,
+    join_rewrite::JoinOptions,
+    passes::{Pass, DEFAULT_PASSES},
+    *,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::Read;
 
 #[derive(Debug)]
 pub enum CliError {
@@ -10,6 +16,10 @@ pub enum CliError {
     Json(serde_json::Error),
     Conjure(babelfish::conjure_rewrite::Error),
     Join(babelfish::join_rewrite::Error),
+    Desugar(babelfish::desugar::Error),
+    Materialize(babelfish::materialize::Error),
+    InvalidErd(Vec<babelfish::erd_validation::Error>),
+    UnsupportedErdFormat(ErdFormat),
 }
 
 impl From<std::io::Error> for CliError {
@@ -26,7 +36,7 @@ impl From<bson::de::Error> for CliError {
 
 impl From<serde_json::Error> for CliError {
     fn from(e: serde_json::Error) -> Self {
-        CliError::Json(e.into())
+        CliError::Json(e)
     }
 }
 
@@ -42,17 +52,166 @@ impl From<babelfish::conjure_rewrite::Error> for CliError {
     }
 }
 
+impl From<babelfish::materialize::Error> for CliError {
+    fn from(e: babelfish::materialize::Error) -> Self {
+        CliError::Materialize(e)
+    }
+}
+
+impl From<babelfish::passes::Error> for CliError {
+    fn from(e: babelfish::passes::Error) -> Self {
+        match e {
+            babelfish::passes::Error::Conjure(e) => CliError::Conjure(e),
+            babelfish::passes::Error::Join(e) => CliError::Join(e),
+            babelfish::passes::Error::Desugar(e) => CliError::Desugar(e),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
 struct Cli {
-    #[arg(short, long, help = "pipeline bson file")]
-    pipeline_file: Option<String>,
-    #[arg(short, long, help = "erd file")]
-    erd_file: Option<String>,
-    #[arg(short, long, help = "match move")]
-    match_move: Option<String>,
-    #[arg(short, long, help = "new erd file")]
-    nerd_file: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(
+        about = "rewrite a pipeline into an aggregate command, by default running conjure, join, match-move and desugar"
+    )]
+    Rewrite(PipelineArgs),
+    #[command(about = "run match movement on a pipeline")]
+    MatchMove(PipelineArgs),
+    #[command(about = "explain how a pipeline is rewritten")]
+    Explain {
+        #[command(flatten)]
+        args: PipelineArgs,
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    #[command(about = "parse and validate an erd")]
+    ValidateErd(ErdArgs),
+    #[command(about = "render the graph of an erd")]
+    Graph {
+        #[command(flatten)]
+        args: ErdArgs,
+        #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    #[command(about = "build the physical collections of an erd from entity records")]
+    Materialize(MaterializeArgs),
+    #[command(about = "plan the data migration between two erds")]
+    Migrate(MigrateArgs),
+}
+
+#[derive(Args, Debug)]
+struct PipelineArgs {
+    #[arg(help = "pipeline json file, read from stdin if not given")]
+    file: Option<String>,
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        help = "comma separated passes to run in order: conjure, join, match-move, desugar"
+    )]
+    passes: Option<Vec<Pass>>,
+    #[arg(short, long, default_value = join_rewrite::DEFAULT_ERD_PATH, help = "erd file")]
+    erd: String,
+    #[arg(long, value_enum, default_value_t = ErdFormat::Relationships)]
+    erd_format: ErdFormat,
+    #[command(flatten)]
+    join: JoinArgs,
+}
+
+#[derive(Args, Debug)]
+struct JoinArgs {
+    #[arg(
+        long,
+        help = "fail joins that need a $lookup from a db other than the root entity's"
+    )]
+    no_cross_db_lookups: bool,
+}
+
+impl JoinArgs {
+    fn options(&self) -> JoinOptions {
+        JoinOptions {
+            cross_db_lookups: !self.no_cross_db_lookups,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct ErdArgs {
+    #[arg(help = "erd json file, read from stdin if not given")]
+    file: Option<String>,
+    #[arg(long, value_enum, default_value_t = ErdFormat::Relationships)]
+    erd_format: ErdFormat,
+}
+
+#[derive(Args, Debug)]
+struct MaterializeArgs {
+    #[arg(
+        help = "json file with an array of records for every entity, read from stdin if not given"
+    )]
+    file: Option<String>,
+    #[arg(short, long, default_value = join_rewrite::DEFAULT_ERD_PATH, help = "erd file")]
+    erd: String,
+    #[arg(long, value_enum, default_value_t = ErdFormat::Relationships)]
+    erd_format: ErdFormat,
+}
+
+#[derive(Args, Debug)]
+struct MigrateArgs {
+    #[arg(long, help = "erd the collections are currently stored in")]
+    from: String,
+    #[arg(long, help = "erd to migrate the collections to")]
+    to: String,
+    #[arg(long, value_enum, default_value_t = ErdFormat::Relationships)]
+    erd_format: ErdFormat,
+    #[arg(help = "pipeline json files to report plan changes for")]
+    pipelines: Vec<String>,
+    #[command(flatten)]
+    join: JoinArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ErdFormat {
+    /// The relationships format used by assets/rel.json
+    Relationships,
+    /// The erd format with sources and json schemas for every entity
+    Erd,
+    /// The schema crate erd format
+    Schema,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Json,
+    Text,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+enum LoadedErd {
+    Relationships(Relationships),
+    Erd(babelfish::erd::Erd),
+}
+
+// with_erd runs $body with $erd bound to whichever ERD format was loaded, since GetErdData
+// cannot be used as a trait object.
+macro_rules! with_erd {
+    ($loaded:expr, |$erd:ident| $body:expr) => {
+        match $loaded {
+            LoadedErd::Relationships($erd) => $body,
+            LoadedErd::Erd($erd) => $body,
+        }
+    };
 }
 
 fn main() {
@@ -61,37 +220,150 @@ fn main() {
             CliError::Io(e) => eprintln!("IO error: {}", e),
             CliError::Bson(e) => eprintln!("Bson error: {}", e),
             CliError::Json(e) => eprintln!("Json error: {}", e),
-            CliError::Join(e) => println!("Join error: {}", e),
-            CliError::Conjure(e) => println!("Conjure error: {}", e),
+            CliError::Join(e) => eprintln!("Join error: {}", e),
+            CliError::Conjure(e) => eprintln!("Conjure error: {}", e),
+            CliError::Desugar(e) => eprintln!("Desugar error: {}", e),
+            CliError::Materialize(e) => eprintln!("Materialize error: {}", e),
+            CliError::InvalidErd(errors) => {
+                for e in errors {
+                    eprintln!("ERD error: {}", e);
+                }
+            }
+            CliError::UnsupportedErdFormat(format) => {
+                eprintln!("ERD format {:?} is not supported by this command", format)
+            }
         }
+        std::process::exit(1);
     }
 }
 
+fn read_input(file: &Option<String>) -> Result<String, CliError> {
+    match file {
+        Some(file) => Ok(std::fs::read_to_string(file)?),
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            Ok(input)
+        }
+    }
+}
+
+fn parse_erd(input: &str, format: ErdFormat) -> Result<LoadedErd, CliError> {
+    Ok(match format {
+        ErdFormat::Relationships => LoadedErd::Relationships(serde_json::from_str(input)?),
+        ErdFormat::Erd => LoadedErd::Erd(serde_json::from_str(input)?),
+        ErdFormat::Schema => return Err(CliError::UnsupportedErdFormat(format)),
+    })
+}
+
+fn load_pipeline_args(
+    args: &PipelineArgs,
+    default_passes: &[Pass],
+) -> Result<(Pipeline, Vec<Pass>, LoadedErd), CliError> {
+    let pipeline: Pipeline = serde_json::from_str(&read_input(&args.file)?)?;
+    let passes = args
+        .passes
+        .clone()
+        .unwrap_or_else(|| default_passes.to_vec());
+    let erd = if passes.contains(&Pass::Join) {
+        parse_erd(&std::fs::read_to_string(&args.erd)?, args.erd_format)?
+    } else {
+        // the ERD is only needed for joins, an empty one avoids requiring the file otherwise
+        LoadedErd::Relationships(serde_json::from_str("{}")?)
+    };
+    Ok((pipeline, passes, erd))
+}
+
 fn run() -> Result<(), CliError> {
     let args = Cli::parse();
 
-    if let Some(erd_file) = &args.erd_file {
-        let erd = std::fs::read_to_string(erd_file)?;
-        let erd: Erd = serde_json::from_str(&erd)?;
-        println!("{:?}", erd);
-    } else if let Some(pipeline_file) = &args.pipeline_file {
-        let pipeline = std::fs::read_to_string(pipeline_file)?;
-        let pipeline: Pipeline = serde_json::from_str(&pipeline)?;
-        let pipeline = conjure_rewrite::rewrite_pipeline(pipeline)?;
-        let pipeline = join_rewrite::rewrite_pipeline(pipeline)?;
-        let pipeline = match_movement_rewrite::rewrite_match_move(pipeline);
-        let pipeline_json = serde_json::to_string_pretty(&pipeline)?;
-        println!("{}", pipeline_json);
-    } else if let Some(match_move) = &args.match_move {
-        let match_move = std::fs::read_to_string(match_move)?;
-        let match_move: Pipeline = serde_json::from_str(&match_move)?;
-        let match_move = match_movement_rewrite::rewrite_match_move(match_move);
-        let match_move_json = serde_json::to_string_pretty(&match_move)?;
-        println!("{}", match_move_json);
-    } else if let Some(nerd_file) = &args.nerd_file {
-        let nerd = std::fs::read_to_string(nerd_file)?;
-        let nerd: babelfish::erd::Erd = serde_json::from_str(&nerd)?;
-        println!("{:?}", nerd);
+    match &args.command {
+        Command::Rewrite(args) => {
+            let (pipeline, passes, erd) = load_pipeline_args(args, DEFAULT_PASSES)?;
+            let options = args.join.options();
+            let plan = with_erd!(&erd, |erd| passes::plan(pipeline, &passes, erd, options))?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&plan.aggregate_command()?)?
+            );
+        }
+        Command::MatchMove(args) => {
+            let (pipeline, passes, erd) = load_pipeline_args(args, &[Pass::MatchMove])?;
+            let options = args.join.options();
+            let pipeline = with_erd!(&erd, |erd| passes::run_passes(
+                pipeline, &passes, erd, options
+            ))?;
+            println!("{}", serde_json::to_string_pretty(&pipeline)?);
+        }
+        Command::Explain { args, format } => {
+            let (pipeline, passes, erd) = load_pipeline_args(args, DEFAULT_PASSES)?;
+            let options = args.join.options();
+            let explain = with_erd!(&erd, |erd| explain::explain_pipeline(
+                pipeline, &passes, erd, options
+            ))?;
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&explain)?),
+                OutputFormat::Text => print!("{}", explain),
+            }
+        }
+        Command::ValidateErd(args) => {
+            let input = read_input(&args.file)?;
+            if let ErdFormat::Schema = args.erd_format {
+                let erd: schema::Erd = serde_json::from_str(&input)?;
+                println!("ERD is valid: {} entities", erd.entities.len());
+                return Ok(());
+            }
+            let erd = parse_erd(&input, args.erd_format)?;
+            let errors = with_erd!(&erd, |erd| erd_validation::validate(erd));
+            if !errors.is_empty() {
+                return Err(CliError::InvalidErd(errors));
+            }
+            let size = with_erd!(&erd, |erd| erd.size());
+            println!("ERD is valid: {} entities", size);
+        }
+        Command::Graph { args, format } => {
+            let erd = parse_erd(&read_input(&args.file)?, args.erd_format)?;
+            let graph = with_erd!(&erd, |erd| ErdGraph::new(erd))
+                .map_err(|e| CliError::InvalidErd(vec![e]))?;
+            match format {
+                GraphFormat::Dot => print!("{}", graph_export::to_dot(&graph)),
+                GraphFormat::Mermaid => print!("{}", graph_export::to_mermaid(&graph)),
+                GraphFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&graph_export::to_json(&graph))?
+                ),
+            }
+        }
+        Command::Materialize(args) => {
+            let data: materialize::EntityData = serde_json::from_str(&read_input(&args.file)?)?;
+            let erd = parse_erd(&std::fs::read_to_string(&args.erd)?, args.erd_format)?;
+            let collections = with_erd!(&erd, |erd| materialize::materialize(erd, &data))?;
+            println!("{}", serde_json::to_string_pretty(&collections)?);
+        }
+        Command::Migrate(args) => {
+            let old = parse_erd(&std::fs::read_to_string(&args.from)?, args.erd_format)?;
+            let new = parse_erd(&std::fs::read_to_string(&args.to)?, args.erd_format)?;
+            let pipelines = args
+                .pipelines
+                .iter()
+                .map(|file| {
+                    let pipeline: Pipeline = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+                    Ok((file.clone(), pipeline))
+                })
+                .collect::<Result<Vec<_>, CliError>>()?;
+            let (plan, changed) = with_erd!(&old, |old| with_erd!(&new, |new| (
+                migration::plan_migration(old, new),
+                migration::changed_plans(old, new, &pipelines, args.join.options())
+            )));
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "steps": plan.steps,
+                    "notes": plan.notes,
+                    "changedPipelines": changed,
+                }))?
+            );
+        }
     }
     Ok(())
 }
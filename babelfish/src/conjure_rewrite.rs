@@ -35,7 +35,7 @@ impl Visitor for ConjureRewrite {
         match stage {
             Stage::Conjure(ref v) => {
                 let (entites, project_stage) = v
-                    .into_iter()
+                    .iter()
                     .filter_map(|item| {
                         let sp: Vec<_> = item.split('.').collect();
                         if sp.len() < 2 {
@@ -72,6 +72,8 @@ impl Visitor for ConjureRewrite {
                             root: Some(root),
                             args,
                             condition: None,
+                            exclude_implicit: None,
+                            read_concern: None,
                         }))),
                         Stage::Project(ProjectStage {
                             items: project_stage,
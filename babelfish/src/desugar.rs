@@ -0,0 +1,470 @@
+use ast::definitions::{
+    Cond, Convert, Expression, Group, LiteralValue, Lookup, LookupFrom, Map, Namespace, Pipeline,
+    Ref, RegexAggExpression, SQLAccumulator, SQLConvert, SQLDivide, Stage, Subquery,
+    SubqueryComparison, SubqueryExists, SubqueryLookup, TaggedOperator, Unset, UntaggedOperator,
+    UntaggedOperatorName, visitor::Visitor,
+};
+use linked_hash_map::LinkedHashMap;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error("$like pattern must be a literal string, found: {0}")]
+    NonLiteralLikePattern(String),
+    #[error("Unknown $subqueryComparison operator: {0}")]
+    UnknownComparisonOperator(String),
+    #[error("Unknown $subqueryComparison modifier: {0}, expected any or all")]
+    UnknownComparisonModifier(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// SUBQUERY_PREFIX prefixes the temporary fields that lifted subqueries are looked up into.
+const SUBQUERY_PREFIX: &str = "__subquery_";
+// ACCUMULATOR_PREFIX prefixes the temporary fields that SQL accumulators need to restore null
+// semantics after the $group.
+const ACCUMULATOR_PREFIX: &str = "__sql_accumulator_";
+
+/// Desugar rewrites the MongoSQL extension operators, which a mongod rejects, into standard MQL.
+///
+/// - `$sqlConvert` becomes `$convert`.
+/// - `$sqlDivide` becomes a `$cond` that returns `onError` when the divisor is 0.
+/// - `$like` becomes an anchored `$regexMatch` with the pattern translated and escaped.
+/// - `$subquery`, `$subqueryExists` and `$subqueryComparison` are lifted into a `$lookup` before
+///   the stage using them, and replaced with an expression over the looked up array. The
+///   temporary fields are `$unset` after the stage.
+/// - The `$sql*` accumulators become their MQL counterparts. `distinct` accumulators collect
+///   with `$addToSet` and are finished in an `$addFields` after the `$group`, and `$sqlSum` and
+///   `$sqlCount` keep SQL semantics: the sum of no non-null values is null, and count ignores
+///   nulls.
+pub struct Desugar {
+    error: Option<Error>,
+    counter: usize,
+}
+
+pub fn desugar_pipeline(pipeline: Pipeline) -> Result<Pipeline> {
+    let mut visitor = Desugar {
+        error: None,
+        counter: 0,
+    };
+    let pipeline = visitor.visit_pipeline(pipeline);
+    if let Some(e) = visitor.error {
+        Err(e)
+    } else {
+        Ok(pipeline)
+    }
+}
+
+fn field(path: impl Into<String>) -> Expression {
+    Expression::Ref(Ref::FieldRef(path.into()))
+}
+
+fn variable(name: impl Into<String>) -> Expression {
+    Expression::Ref(Ref::VariableRef(name.into()))
+}
+
+fn string(s: impl Into<String>) -> Expression {
+    Expression::Literal(LiteralValue::String(s.into()))
+}
+
+fn null() -> Expression {
+    Expression::Literal(LiteralValue::Null)
+}
+
+fn op(op: UntaggedOperatorName, args: Vec<Expression>) -> Expression {
+    Expression::UntaggedOperator(UntaggedOperator { op, args })
+}
+
+fn cond(_if: Expression, then: Expression, _else: Expression) -> Expression {
+    Expression::TaggedOperator(TaggedOperator::Cond(Cond {
+        _if: Box::new(_if),
+        then: Box::new(then),
+        _else: Box::new(_else),
+    }))
+}
+
+// is_null is true for null and missing values, matching SQL's single NULL.
+fn is_null(expr: Expression) -> Expression {
+    op(
+        UntaggedOperatorName::In,
+        vec![
+            op(UntaggedOperatorName::Type, vec![expr]),
+            Expression::Array(vec![string("missing"), string("null")]),
+        ],
+    )
+}
+
+/// like_to_regex translates a SQL LIKE pattern to an anchored regular expression: `%` matches
+/// any string, `_` any single character, and the escape character makes the following character
+/// literal. Every other regular expression metacharacter is escaped.
+pub fn like_to_regex(pattern: &str, escape: Option<char>) -> String {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if Some(c) == escape => {
+                if let Some(next) = chars.next() {
+                    push_escaped(&mut regex, next);
+                }
+            }
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            c => push_escaped(&mut regex, c),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn push_escaped(regex: &mut String, c: char) {
+    if "\\^$.|?*+()[]{}".contains(c) {
+        regex.push('\\');
+    }
+    regex.push(c);
+}
+
+impl Desugar {
+    fn next_name(&mut self, prefix: &str) -> String {
+        let name = format!("{}{}", prefix, self.counter);
+        self.counter += 1;
+        name
+    }
+
+    fn desugar_tagged_operator(&mut self, op: TaggedOperator) -> Expression {
+        match op {
+            TaggedOperator::SQLConvert(SQLConvert {
+                input,
+                to,
+                on_null,
+                on_error,
+            }) => Expression::TaggedOperator(TaggedOperator::Convert(Convert {
+                input,
+                to: Box::new(string(to)),
+                format: None,
+                on_null: Some(on_null),
+                on_error: Some(on_error),
+            })),
+            TaggedOperator::SQLDivide(SQLDivide {
+                dividend,
+                divisor,
+                on_error,
+            }) => cond(
+                self::op(
+                    UntaggedOperatorName::Eq,
+                    vec![
+                        (*divisor).clone(),
+                        Expression::Literal(LiteralValue::Int32(0)),
+                    ],
+                ),
+                *on_error,
+                self::op(UntaggedOperatorName::Divide, vec![*dividend, *divisor]),
+            ),
+            TaggedOperator::Like(like) => {
+                let Expression::Literal(LiteralValue::String(pattern)) = *like.pattern else {
+                    self.error = Some(Error::NonLiteralLikePattern(
+                        serde_json::to_string(&like.pattern).unwrap_or_default(),
+                    ));
+                    return null();
+                };
+                Expression::TaggedOperator(TaggedOperator::Regex(RegexAggExpression {
+                    input: like.input,
+                    regex: Box::new(string(like_to_regex(&pattern, like.escape))),
+                    // s lets . match newlines, as % and _ do in SQL
+                    options: Some(Box::new(string("s"))),
+                }))
+            }
+            op => Expression::TaggedOperator(op),
+        }
+    }
+
+    // desugar_group replaces the SQL accumulators of the group. Accumulators that cannot be
+    // computed by a single MQL accumulator are finished by the returned $addFields, and their
+    // temporary fields removed by the returned $unset.
+    fn desugar_group(&mut self, group: Group) -> Stage {
+        let mut aggregations = LinkedHashMap::new();
+        let mut finish = LinkedHashMap::new();
+        let mut temporaries = Vec::new();
+        for (name, aggregation) in group.aggregations.into_iter() {
+            let Expression::TaggedOperator(tagged) = aggregation else {
+                aggregations.insert(name, aggregation);
+                continue;
+            };
+            let (accumulator, sql) = match tagged {
+                TaggedOperator::SQLAvg(sql) => (UntaggedOperatorName::Avg, sql),
+                TaggedOperator::SQLCount(sql) => (UntaggedOperatorName::Count, sql),
+                TaggedOperator::SQLFirst(sql) => (UntaggedOperatorName::First, sql),
+                TaggedOperator::SQLLast(sql) => (UntaggedOperatorName::Last, sql),
+                TaggedOperator::SQLMax(sql) => (UntaggedOperatorName::Max, sql),
+                TaggedOperator::SQLMergeObjects(sql) => (UntaggedOperatorName::MergeObjects, sql),
+                TaggedOperator::SQLMin(sql) => (UntaggedOperatorName::Min, sql),
+                TaggedOperator::SQLStdDevPop(sql) => (UntaggedOperatorName::StdDevPop, sql),
+                TaggedOperator::SQLStdDevSamp(sql) => (UntaggedOperatorName::StdDevSamp, sql),
+                TaggedOperator::SQLSum(sql) => (UntaggedOperatorName::Sum, sql),
+                tagged => {
+                    aggregations.insert(name, Expression::TaggedOperator(tagged));
+                    continue;
+                }
+            };
+            let SQLAccumulator { distinct, var, .. } = sql;
+            if distinct {
+                // collect the distinct non-null values, and compute the accumulator over them
+                // afterwards. $$REMOVE is missing, which $addToSet ignores.
+                aggregations.insert(
+                    name.clone(),
+                    op(
+                        UntaggedOperatorName::AddToSet,
+                        vec![cond(is_null((*var).clone()), variable("REMOVE"), *var)],
+                    ),
+                );
+                let values = field(name.clone());
+                let finished = match accumulator {
+                    UntaggedOperatorName::Count => op(UntaggedOperatorName::Size, vec![values]),
+                    UntaggedOperatorName::Sum => cond(
+                        op(
+                            UntaggedOperatorName::Eq,
+                            vec![
+                                op(UntaggedOperatorName::Size, vec![values.clone()]),
+                                Expression::Literal(LiteralValue::Int32(0)),
+                            ],
+                        ),
+                        null(),
+                        op(UntaggedOperatorName::Sum, vec![values]),
+                    ),
+                    accumulator => op(accumulator, vec![values]),
+                };
+                finish.insert(name, finished);
+                continue;
+            }
+            match accumulator {
+                UntaggedOperatorName::Count => {
+                    aggregations.insert(
+                        name,
+                        op(
+                            UntaggedOperatorName::Sum,
+                            vec![cond(
+                                is_null(*var),
+                                Expression::Literal(LiteralValue::Int32(0)),
+                                Expression::Literal(LiteralValue::Int32(1)),
+                            )],
+                        ),
+                    );
+                }
+                UntaggedOperatorName::Sum => {
+                    // $sum is 0 when there are no numbers to sum, but SQL's SUM is null, so we
+                    // also count the non-null values
+                    let count = self.next_name(ACCUMULATOR_PREFIX);
+                    aggregations.insert(
+                        count.clone(),
+                        op(
+                            UntaggedOperatorName::Sum,
+                            vec![cond(
+                                is_null((*var).clone()),
+                                Expression::Literal(LiteralValue::Int32(0)),
+                                Expression::Literal(LiteralValue::Int32(1)),
+                            )],
+                        ),
+                    );
+                    aggregations.insert(name.clone(), op(UntaggedOperatorName::Sum, vec![*var]));
+                    finish.insert(
+                        name.clone(),
+                        cond(
+                            op(
+                                UntaggedOperatorName::Eq,
+                                vec![
+                                    field(count.clone()),
+                                    Expression::Literal(LiteralValue::Int32(0)),
+                                ],
+                            ),
+                            null(),
+                            field(name),
+                        ),
+                    );
+                    temporaries.push(count);
+                }
+                accumulator => {
+                    aggregations.insert(name, op(accumulator, vec![*var]));
+                }
+            }
+        }
+        let group = Stage::Group(Group {
+            keys: group.keys,
+            aggregations,
+        });
+        if finish.is_empty() {
+            return group;
+        }
+        let mut pipeline = vec![group, Stage::AddFields(finish)];
+        if !temporaries.is_empty() {
+            pipeline.push(Stage::Unset(Unset::Multiple(temporaries)));
+        }
+        Stage::SubPipeline(Pipeline { pipeline })
+    }
+}
+
+impl Visitor for Desugar {
+    // visit_pipeline lifts the subqueries of every stage into $lookups before the stage, and
+    // flattens the SubPipelines introduced by desugaring.
+    fn visit_pipeline(&mut self, pipeline: Pipeline) -> Pipeline {
+        let mut out = Vec::with_capacity(pipeline.len());
+        for stage in pipeline.pipeline.into_iter() {
+            let stage = self.visit_stage(stage);
+            let mut lifter = SubqueryLifter {
+                desugar: self,
+                lookups: Vec::new(),
+                temporaries: Vec::new(),
+            };
+            let stage = lifter.visit_stage(stage);
+            let (lookups, temporaries) = (lifter.lookups, lifter.temporaries);
+            out.extend(lookups);
+            match stage {
+                Stage::SubPipeline(pipeline) => out.extend(pipeline.pipeline),
+                stage => out.push(stage),
+            }
+            if !temporaries.is_empty() {
+                out.push(Stage::Unset(Unset::Multiple(temporaries)));
+            }
+        }
+        Pipeline { pipeline: out }
+    }
+
+    fn visit_stage(&mut self, stage: Stage) -> Stage {
+        match stage.walk(self) {
+            Stage::Group(group) => self.desugar_group(group),
+            stage => stage,
+        }
+    }
+
+    fn visit_expression(&mut self, expression: Expression) -> Expression {
+        match expression.walk(self) {
+            Expression::TaggedOperator(op) => self.desugar_tagged_operator(op),
+            expression => expression,
+        }
+    }
+}
+
+// SubqueryLifter replaces the subquery operators in a single stage with expressions over
+// temporary fields, and collects the $lookups that fill those fields. Subqueries nested in the
+// subquery pipelines have already been lifted into those pipelines by Desugar.
+struct SubqueryLifter<'a> {
+    desugar: &'a mut Desugar,
+    lookups: Vec<Stage>,
+    temporaries: Vec<String>,
+}
+
+impl SubqueryLifter<'_> {
+    fn lift(
+        &mut self,
+        db: Option<String>,
+        collection: Option<String>,
+        let_body: Option<LinkedHashMap<String, Expression>>,
+        pipeline: Pipeline,
+    ) -> String {
+        let name = self.desugar.next_name(SUBQUERY_PREFIX);
+        let from = match (db, collection) {
+            (Some(db), Some(coll)) => Some(LookupFrom::Namespace(Namespace { db, coll })),
+            (None, Some(coll)) => Some(LookupFrom::Collection(coll)),
+            (_, None) => None,
+        };
+        self.lookups
+            .push(Stage::Lookup(Lookup::Subquery(SubqueryLookup {
+                from,
+                let_body,
+                pipeline,
+                as_var: name.clone(),
+                is_left_join: None,
+            })));
+        self.temporaries.push(name.clone());
+        name
+    }
+
+    // subquery_value lifts the subquery and returns the expression for its result: the value at
+    // the output path of its first document.
+    fn subquery_values(&mut self, subquery: Subquery) -> Expression {
+        let name = self.lift(
+            subquery.db,
+            subquery.collection,
+            subquery.let_bindings,
+            subquery.pipeline,
+        );
+        match subquery.output_path {
+            Some(path) if !path.is_empty() => field(format!("{}.{}", name, path.join("."))),
+            _ => field(name),
+        }
+    }
+}
+
+// comparison_operator returns the operator of a subquery comparison, named without its `$`.
+fn comparison_operator(name: &str) -> Option<UntaggedOperatorName> {
+    Some(match name {
+        "eq" => UntaggedOperatorName::Eq,
+        "ne" => UntaggedOperatorName::Ne,
+        "lt" => UntaggedOperatorName::Lt,
+        "lte" => UntaggedOperatorName::Lte,
+        "gt" => UntaggedOperatorName::Gt,
+        "gte" => UntaggedOperatorName::Gte,
+        _ => return None,
+    })
+}
+
+impl Visitor for SubqueryLifter<'_> {
+    fn visit_expression(&mut self, expression: Expression) -> Expression {
+        match expression {
+            Expression::TaggedOperator(TaggedOperator::Subquery(subquery)) => {
+                let values = self.subquery_values(subquery);
+                op(
+                    UntaggedOperatorName::ArrayElemAt,
+                    vec![values, Expression::Literal(LiteralValue::Int32(0))],
+                )
+            }
+            Expression::TaggedOperator(TaggedOperator::SubqueryExists(SubqueryExists {
+                db,
+                collection,
+                let_bindings,
+                mut pipeline,
+            })) => {
+                // one document is enough to know the subquery is not empty
+                pipeline.push(Stage::Limit(1));
+                let name = self.lift(db, collection, let_bindings, pipeline);
+                op(
+                    UntaggedOperatorName::Gt,
+                    vec![
+                        op(UntaggedOperatorName::Size, vec![field(name)]),
+                        Expression::Literal(LiteralValue::Int32(0)),
+                    ],
+                )
+            }
+            Expression::TaggedOperator(TaggedOperator::SubqueryComparison(
+                SubqueryComparison {
+                    op: comparison,
+                    modifier,
+                    arg,
+                    subquery,
+                },
+            )) => {
+                let name = comparison.trim_start_matches('$');
+                let Some(comparison) = comparison_operator(name) else {
+                    self.desugar.error = Some(Error::UnknownComparisonOperator(name.to_string()));
+                    return null();
+                };
+                let quantifier = match modifier.as_str() {
+                    "any" => UntaggedOperatorName::AnyElementTrue,
+                    "all" => UntaggedOperatorName::AllElementsTrue,
+                    _ => {
+                        self.desugar.error = Some(Error::UnknownComparisonModifier(modifier));
+                        return null();
+                    }
+                };
+                let values = self.subquery_values(*subquery);
+                op(
+                    quantifier,
+                    vec![Expression::TaggedOperator(TaggedOperator::Map(Map {
+                        input: Box::new(values),
+                        _as: None,
+                        inside: Box::new(op(comparison, vec![*arg, variable("this")])),
+                    }))],
+                )
+            }
+            expression => expression.walk(self),
+        }
+    }
+}
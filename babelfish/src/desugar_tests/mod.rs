@@ -0,0 +1,133 @@
+macro_rules! test_desugar {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::desugar::desugar_pipeline;
+            use ast::definitions::Pipeline;
+
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let result = desugar_pipeline(input).unwrap();
+            assert_eq!(expected, serde_json::to_value(result).unwrap());
+        }
+    };
+}
+
+macro_rules! test_desugar_error {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::desugar::desugar_pipeline;
+            use ast::definitions::Pipeline;
+
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let error = desugar_pipeline(input).unwrap_err();
+            assert_eq!($expected, error.to_string());
+        }
+    };
+}
+
+test_desugar!(
+    like_to_regex_match,
+    expected = r#"[{"$match": {"$expr": {"$regexMatch": {"input": "$name", "regex": "^A.a.*\\.$", "options": "s"}}}}]"#,
+    input = r#"[{"$match": {"$expr": {"$like": {"input": "$name", "pattern": "A_a%.", "escape": null}}}}]"#
+);
+
+test_desugar!(
+    like_escape,
+    expected = r#"[{"$project": {"discount": {"$regexMatch": {"input": "$code", "regex": "^100%$", "options": "s"}}}}]"#,
+    input = r#"[{"$project": {"discount": {"$like": {"input": "$code", "pattern": "100!%", "escape": "!"}}}}]"#
+);
+
+test_desugar!(
+    sql_sum_null_on_empty,
+    expected = r#"[{"$group": {"_id": "$customerId", "__sql_accumulator_0": {"$sum": {"$cond": {"if": {"$in": [{"$type": ["$amount"]}, ["missing", "null"]]}, "then": 0, "else": 1}}}, "total": {"$sum": "$amount"}}}, {"$addFields": {"total": {"$cond": {"if": {"$eq": ["$__sql_accumulator_0", 0]}, "then": null, "else": "$total"}}}}, {"$unset": ["__sql_accumulator_0"]}]"#,
+    input = r#"[{"$group": {"_id": "$customerId", "total": {"$sqlSum": {"var": "$amount", "distinct": false}}}}]"#
+);
+
+test_desugar!(
+    sql_count_ignores_nulls,
+    expected = r#"[{"$group": {"_id": null, "orders": {"$sum": {"$cond": {"if": {"$in": [{"$type": ["$orderId"]}, ["missing", "null"]]}, "then": 0, "else": 1}}}}}]"#,
+    input = r#"[{"$group": {"_id": null, "orders": {"$sqlCount": {"var": "$orderId", "distinct": false}}}}]"#
+);
+
+test_desugar!(
+    distinct_accumulators_add_to_set,
+    expected = r#"[{"$group": {"_id": null, "total": {"$addToSet": {"$cond": {"if": {"$in": [{"$type": ["$amount"]}, ["missing", "null"]]}, "then": "$$REMOVE", "else": "$amount"}}}, "customers": {"$addToSet": {"$cond": {"if": {"$in": [{"$type": ["$customerId"]}, ["missing", "null"]]}, "then": "$$REMOVE", "else": "$customerId"}}}, "average": {"$addToSet": {"$cond": {"if": {"$in": [{"$type": ["$amount"]}, ["missing", "null"]]}, "then": "$$REMOVE", "else": "$amount"}}}}}, {"$addFields": {"total": {"$cond": {"if": {"$eq": [{"$size": ["$total"]}, 0]}, "then": null, "else": {"$sum": ["$total"]}}}, "customers": {"$size": ["$customers"]}, "average": {"$avg": ["$average"]}}}]"#,
+    input = r#"[{"$group": {
+        "_id": null,
+        "total": {"$sqlSum": {"var": "$amount", "distinct": true}},
+        "customers": {"$sqlCount": {"var": "$customerId", "distinct": true}},
+        "average": {"$sqlAvg": {"var": "$amount", "distinct": true}}
+    }}]"#
+);
+
+test_desugar!(
+    subquery_lifted_into_lookup,
+    expected = r#"[{"$lookup": {"from": {"db": "shop", "coll": "orders"}, "let": {"id": "$_id"}, "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}, {"$limit": 1}], "as": "__subquery_0"}}, {"$project": {"latest": {"$arrayElemAt": ["$__subquery_0.total", 0]}}}, {"$unset": ["__subquery_0"]}]"#,
+    input = r#"[{"$project": {"latest": {"$subquery": {
+        "db": "shop",
+        "collection": "orders",
+        "let": {"id": "$_id"},
+        "outputPath": ["total"],
+        "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}, {"$limit": 1}]
+    }}}}]"#
+);
+
+test_desugar!(
+    subquery_exists_lifted_into_lookup,
+    expected = r#"[{"$lookup": {"from": "orders", "let": {"id": "$_id"}, "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}, {"$limit": 1}], "as": "__subquery_0"}}, {"$match": {"$expr": {"$gt": [{"$size": ["$__subquery_0"]}, 0]}}}, {"$unset": ["__subquery_0"]}]"#,
+    input = r#"[{"$match": {"$expr": {"$subqueryExists": {
+        "collection": "orders",
+        "let": {"id": "$_id"},
+        "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}]
+    }}}}]"#
+);
+
+test_desugar!(
+    sql_convert_to_convert,
+    expected = r#"[{"$project": {"total": {"$convert": {"input": "$total", "to": "double", "onNull": null, "onError": 0}}}}]"#,
+    input = r#"[{"$project": {"total": {"$sqlConvert": {
+        "input": "$total",
+        "to": "double",
+        "onNull": null,
+        "onError": 0
+    }}}}]"#
+);
+
+test_desugar!(
+    sql_divide_by_zero_is_null,
+    expected = r#"[{"$project": {"ratio": {"$cond": {"if": {"$eq": ["$views", 0]}, "then": null, "else": {"$divide": ["$clicks", "$views"]}}}}}]"#,
+    input = r#"[{"$project": {"ratio": {"$sqlDivide": {
+        "dividend": "$clicks",
+        "divisor": "$views",
+        "onError": null
+    }}}}]"#
+);
+
+test_desugar!(
+    subquery_comparison_any,
+    expected = r#"[{"$lookup": {"from": "orders", "let": {"id": "$_id"}, "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}], "as": "__subquery_0"}}, {"$match": {"$expr": {"$anyElementTrue": [{"$map": {"input": "$__subquery_0.total", "in": {"$gt": ["$budget", "$$this"]}}}]}}}, {"$unset": ["__subquery_0"]}]"#,
+    input = r#"[{"$match": {"$expr": {"$subqueryComparison": {
+        "op": "gt",
+        "modifier": "any",
+        "arg": "$budget",
+        "subquery": {
+            "collection": "orders",
+            "let": {"id": "$_id"},
+            "outputPath": ["total"],
+            "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}]
+        }
+    }}}}]"#
+);
+
+test_desugar_error!(
+    subquery_comparison_unknown_operator,
+    expected = "Unknown $subqueryComparison operator: like",
+    input = r#"[{"$match": {"$expr": {"$subqueryComparison": {
+        "op": "like",
+        "modifier": "all",
+        "arg": "$name",
+        "subquery": {"collection": "customers", "outputPath": ["name"], "pipeline": []}
+    }}}}]"#
+);
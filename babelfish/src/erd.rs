@@ -1,6 +1,10 @@
+use ast::definitions::ReadConcern;
 use schema::Schema;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, MapAccess, SeqAccess, Visitor},
+};
+use std::{collections::HashMap, fmt};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Erd(HashMap<String, ErdItem>);
@@ -10,6 +14,7 @@ impl Erd {
         self.0
             .get(entity)
             .and_then(|item| item.relationships.get(foreign_entity))
+            .and_then(|relationships| relationships.default_relationship(self.size()))
     }
 
     pub fn get_primary_key(&self, entity: &str) -> Option<&String> {
@@ -20,6 +25,26 @@ impl Erd {
         self.0.get(entity).map(|item| &item.source)
     }
 
+    pub fn get_schema(&self, entity: &str) -> Option<&Schema> {
+        self.0.get(entity).map(|item| &item.json_schema)
+    }
+
+    pub fn get_discriminator(&self, entity: &str) -> Option<&Discriminator> {
+        self.0
+            .get(entity)
+            .and_then(|item| item.discriminator.as_ref())
+    }
+
+    pub fn get_relationships(
+        &self,
+        entity: &str,
+    ) -> impl Iterator<Item = (&String, &ErdRelationship)> {
+        self.0
+            .get(entity)
+            .into_iter()
+            .flat_map(|item| flatten_relationships(&item.relationships))
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&String, &ErdItem)> {
         self.0.iter()
     }
@@ -30,16 +55,27 @@ impl Erd {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-pub struct Relationships(HashMap<String, HashMap<String, ErdRelationship>>);
+pub struct Relationships(HashMap<String, HashMap<String, ErdRelationships>>);
 
 impl Relationships {
     pub fn get_relationship(&self, entity: &str, foreign_entity: &str) -> Option<&ErdRelationship> {
         self.0
             .get(entity)
             .and_then(|rels| rels.get(foreign_entity))
+            .and_then(|relationships| relationships.default_relationship(self.size()))
+    }
+
+    pub fn get_relationships(
+        &self,
+        entity: &str,
+    ) -> impl Iterator<Item = (&String, &ErdRelationship)> {
+        self.0
+            .get(entity)
+            .into_iter()
+            .flat_map(flatten_relationships)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &HashMap<String, ErdRelationship>)> {
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &HashMap<String, ErdRelationships>)> {
         self.0.iter()
     }
 
@@ -55,13 +91,25 @@ pub struct ErdItem {
     pub primary_key: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
-    pub relationships: HashMap<String, ErdRelationship>,
+    pub relationships: HashMap<String, ErdRelationships>,
     #[serde(serialize_with = "schema::serialize_json_schema")]
     #[serde(deserialize_with = "schema::deserialize_json_schema")]
     pub json_schema: Schema,
+    /// discriminator tells the entity apart from the other entities stored in the same
+    /// collection or embedded array.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discriminator: Option<Discriminator>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+/// Discriminator identifies the records of an entity in a collection or embedded array shared by
+/// several entities, such as `Click` and `View` in `events`: its records have value at field.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Discriminator {
+    pub field: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Source {
     pub db: String,
@@ -72,9 +120,88 @@ pub struct Source {
     pub projection: Option<Vec<String>>,
 }
 
+/// ErdRelationships are the relationships from one entity to another: a single relationship, or a
+/// list of named relationships, such as `billingAddress` and `shippingAddress` from `Order` to
+/// `Address`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ErdRelationships {
+    One(Box<ErdRelationship>),
+    Many(Vec<ErdRelationship>),
+}
+
+impl<'de> Deserialize<'de> for ErdRelationships {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // a list is several relationships and anything else a single one, so that errors in a
+        // relationship are reported as they are instead of as matching neither form
+        struct ErdRelationshipsVisitor;
+
+        impl<'de> Visitor<'de> for ErdRelationshipsVisitor {
+            type Value = ErdRelationships;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a relationship or a list of named relationships")
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                Vec::deserialize(de::value::SeqAccessDeserializer::new(seq))
+                    .map(ErdRelationships::Many)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                ErdRelationship::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(|relationship| ErdRelationships::One(Box::new(relationship)))
+            }
+        }
+
+        deserializer.deserialize_any(ErdRelationshipsVisitor)
+    }
+}
+
+impl ErdRelationships {
+    pub fn iter(&self) -> std::slice::Iter<'_, ErdRelationship> {
+        match self {
+            ErdRelationships::One(relationship) => {
+                std::slice::from_ref(relationship.as_ref()).iter()
+            }
+            ErdRelationships::Many(relationships) => relationships.iter(),
+        }
+    }
+
+    /// default_relationship is the relationship used when a join does not name one: the
+    /// cheapest, and of those the first one listed, as in the ERD graph. erd_size is the number
+    /// of entities in the ERD.
+    pub fn default_relationship(&self, erd_size: usize) -> Option<&ErdRelationship> {
+        self.iter()
+            .min_by_key(|relationship| relationship.weight(erd_size))
+    }
+}
+
+// flatten_relationships pairs every relationship with the name of the entity it targets.
+fn flatten_relationships(
+    relationships: &HashMap<String, ErdRelationships>,
+) -> impl Iterator<Item = (&String, &ErdRelationship)> {
+    relationships
+        .iter()
+        .flat_map(|(target, relationships)| relationships.iter().map(move |r| (target, r)))
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct ErdRelationship {
+    /// name identifies the relationship in `$join` arguments that use `via`, which is needed to
+    /// join an entity to itself or to join the same entity more than once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub relationship_type: RelationshipType,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
@@ -84,6 +211,36 @@ pub struct ErdRelationship {
     pub projection: Option<Vec<String>>,
 }
 
+impl ErdRelationship {
+    /// weight is the cost of joining through the relationship in an ERD of erd_size entities.
+    /// Foreign and bucket relationships cost a $lookup, so they are weighted by the size of the
+    /// ERD to be more expensive than any embedded path.
+    pub fn weight(&self, erd_size: usize) -> usize {
+        let weight = match (self.constraint.constraint_type, self.relationship_type) {
+            // datas should actually be based of cardinality with a large constant factor for
+            // being foreign vs embeded
+            (ConstraintType::Embedded, RelationshipType::OneToOne) => 1,
+            (ConstraintType::Embedded, RelationshipType::ManyToOne) => 2,
+            (ConstraintType::Embedded, RelationshipType::ManyToMany) => 4,
+            (ConstraintType::Foreign, RelationshipType::OneToOne) => erd_size,
+            (ConstraintType::Foreign, RelationshipType::ManyToOne) => erd_size * 2,
+            (ConstraintType::Foreign, RelationshipType::ManyToMany) => erd_size * 4,
+            // a bucket takes a $lookup and two $unwinds
+            (ConstraintType::Bucket, RelationshipType::OneToOne) => erd_size + 1,
+            (ConstraintType::Bucket, RelationshipType::ManyToOne) => erd_size * 2 + 1,
+            (ConstraintType::Bucket, RelationshipType::ManyToMany) => erd_size * 4 + 1,
+        };
+        // a junction collection takes a second $lookup
+        if self.constraint.constraint_type == ConstraintType::Foreign
+            && self.constraint.junction.is_some()
+        {
+            weight * 2
+        } else {
+            weight
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum RelationshipType {
     #[serde(rename = "one-to-one")]
@@ -94,6 +251,16 @@ pub enum RelationshipType {
     ManyToMany,
 }
 
+impl std::fmt::Display for RelationshipType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelationshipType::OneToOne => write!(f, "one-to-one"),
+            RelationshipType::ManyToOne => write!(f, "many-to-one"),
+            RelationshipType::ManyToMany => write!(f, "many-to-many"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Consistency {
     #[serde(rename = "strong")]
@@ -104,6 +271,38 @@ pub enum Consistency {
     Eventual,
 }
 
+impl Consistency {
+    /// satisfies returns whether reading with this consistency meets the required one.
+    pub fn satisfies(self, required: Consistency) -> bool {
+        let rank = |consistency| match consistency {
+            Consistency::Eventual => 0,
+            Consistency::Weak => 1,
+            Consistency::Strong => 2,
+        };
+        rank(self) >= rank(required)
+    }
+}
+
+impl From<ReadConcern> for Consistency {
+    fn from(read_concern: ReadConcern) -> Self {
+        match read_concern {
+            ReadConcern::Strong => Consistency::Strong,
+            ReadConcern::Weak => Consistency::Weak,
+            ReadConcern::Eventual => Consistency::Eventual,
+        }
+    }
+}
+
+impl std::fmt::Display for Consistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Consistency::Strong => write!(f, "strong"),
+            Consistency::Weak => write!(f, "weak"),
+            Consistency::Eventual => write!(f, "eventual"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Constraint {
@@ -118,17 +317,136 @@ pub struct Constraint {
     pub local_key: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub foreign_key: Option<String>,
+    /// keys are the key pairs of a composite foreign key, used instead of localKey and
+    /// foreignKey.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<KeyPair>>,
+    /// junction is the collection that stores a foreign many-to-many relationship as one
+    /// document per related pair.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub junction: Option<Junction>,
+    /// reference_array is the side of a foreign relationship whose key is an array of
+    /// references to the other side.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference_array: Option<ReferenceArray>,
+    /// index_field is the field of joined documents that records the position of the
+    /// reference in its array of references.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_field: Option<String>,
+    /// bucket_bounds are the fields of a bucket document holding the bounds of the items in
+    /// its array. They are metadata for explain and graph output only: joins do not filter
+    /// buckets by their bounds, they unwind every item.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_bounds: Option<BucketBounds>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target_path: Option<String>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub projection: Vec<String>,
 }
 
+impl Constraint {
+    /// key_pairs returns the composite keys if given, and otherwise the single localKey and
+    /// foreignKey pair, or nothing if either is missing.
+    pub fn key_pairs(&self) -> Vec<KeyPair> {
+        match (&self.keys, &self.local_key, &self.foreign_key) {
+            (Some(keys), _, _) => keys.clone(),
+            (None, Some(local_key), Some(foreign_key)) => vec![KeyPair {
+                local_key: local_key.clone(),
+                foreign_key: foreign_key.clone(),
+            }],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyPair {
+    pub local_key: String,
+    pub foreign_key: String,
+}
+
+/// Junction is a collection relating the localKey of the source entity, stored in the junction
+/// document's localKey, to the foreignKey of the target entity, stored in its foreignKey.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct Junction {
+    pub db: String,
+    pub collection: String,
+    pub local_key: String,
+    pub foreign_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct BucketBounds {
+    pub min: String,
+    pub max: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ReferenceArray {
+    Local,
+    Foreign,
+}
+
+/// describe_keys formats the keys of a foreign relationship joined by arrow, marking array keys
+/// with `[]` and naming the junction collection in between if there is one. Composite keys are
+/// given as parenthesized lists.
+pub fn describe_keys(
+    keys: &[KeyPair],
+    junction: Option<&Junction>,
+    reference_array: Option<ReferenceArray>,
+    arrow: &str,
+) -> String {
+    let mut local = key_list(keys, |pair| &pair.local_key);
+    let mut foreign = key_list(keys, |pair| &pair.foreign_key);
+    match reference_array {
+        Some(ReferenceArray::Local) => local.push_str("[]"),
+        Some(ReferenceArray::Foreign) => foreign.push_str("[]"),
+        None => {}
+    }
+    match junction {
+        Some(junction) => format!(
+            "{}{}{}.{}({}, {}){}{}",
+            local,
+            arrow,
+            junction.db,
+            junction.collection,
+            junction.local_key,
+            junction.foreign_key,
+            arrow,
+            foreign
+        ),
+        None => format!("{}{}{}", local, arrow, foreign),
+    }
+}
+
+/// describe_bounds formats the bounds fields of a bucket, if it has them.
+pub fn describe_bounds(bounds: Option<&BucketBounds>) -> String {
+    match bounds {
+        Some(bounds) => format!(" [{}, {}]", bounds.min, bounds.max),
+        None => String::new(),
+    }
+}
+
+fn key_list(keys: &[KeyPair], key: impl Fn(&KeyPair) -> &str) -> String {
+    match keys {
+        [pair] => key(pair).to_string(),
+        _ => format!("({})", keys.iter().map(key).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+/// ConstraintType is how a relationship is stored. Bucket relationships store the target
+/// entities grouped into the `targetPath` array of bucket documents in `db.collection`, which
+/// reference their parent through `foreignKey`.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
 #[serde(rename_all = "camelCase")]
 pub enum ConstraintType {
     Foreign,
     Embedded,
+    Bucket,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
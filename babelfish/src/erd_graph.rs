@@ -1,34 +1,103 @@
-use crate::erd::{ConstraintType, Erd, ErdRelationship, RelationshipType, Relationships, Source};
+use crate::{
+    erd::{
+        BucketBounds, Consistency, ConstraintType, Discriminator, Erd, ErdRelationship, Junction,
+        KeyPair, ReferenceArray, RelationshipType, Relationships, Source,
+    },
+    erd_validation::Error,
+    graph_export,
+};
 use petgraph::{
-    algo,
-    dot::Dot,
-    graph::{DiGraph, NodeIndex},
+    Direction, algo,
+    graph::{DiGraph, EdgeIndex, NodeIndex},
+    visit::EdgeRef,
 };
+use schema::Schema;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet},
+};
 
+/// ErdGraph is a multigraph: every relationship is its own edge, so an entity pair may be
+/// connected by several named relationships.
 pub struct ErdGraph {
     pub graph: DiGraph<String, usize>,
     pub node_indices: HashMap<String, NodeIndex>,
-    pub edge_data: HashMap<NodeIndex, HashMap<NodeIndex, EdgeData>>,
+    pub edge_data: HashMap<EdgeIndex, EdgeData>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EdgeData {
     Embedded {
+        name: Option<String>,
         source_entity: String,
         target_path: String,
         relationship_type: RelationshipType,
+        consistency: Option<Consistency>,
     },
     Foreign {
+        name: Option<String>,
+        db: String,
+        collection: String,
+        keys: Vec<KeyPair>,
+        junction: Option<Junction>,
+        reference_array: Option<ReferenceArray>,
+        index_field: Option<String>,
+        relationship_type: RelationshipType,
+        consistency: Option<Consistency>,
+    },
+    Bucket {
+        name: Option<String>,
         db: String,
         collection: String,
-        foreign_key: String,
         local_key: String,
+        foreign_key: String,
+        array_path: String,
+        // bounds are metadata for explain and graph output, joins unwind every item
+        bounds: Option<BucketBounds>,
         relationship_type: RelationshipType,
+        consistency: Option<Consistency>,
     },
 }
 
+impl EdgeData {
+    pub fn relationship_type(&self) -> RelationshipType {
+        match self {
+            EdgeData::Embedded {
+                relationship_type, ..
+            }
+            | EdgeData::Foreign {
+                relationship_type, ..
+            }
+            | EdgeData::Bucket {
+                relationship_type, ..
+            } => *relationship_type,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            EdgeData::Embedded { name, .. }
+            | EdgeData::Foreign { name, .. }
+            | EdgeData::Bucket { name, .. } => name.as_deref(),
+        }
+    }
+
+    /// delivered_consistency is the consistency of reading through the edge. Relationships
+    /// that do not declare one are strongly consistent.
+    pub fn delivered_consistency(&self) -> Consistency {
+        self.consistency().unwrap_or(Consistency::Strong)
+    }
+
+    pub fn consistency(&self) -> Option<Consistency> {
+        match self {
+            EdgeData::Embedded { consistency, .. }
+            | EdgeData::Foreign { consistency, .. }
+            | EdgeData::Bucket { consistency, .. } => *consistency,
+        }
+    }
+}
+
 // TODO: remove this trait once we settle on an ERD format
 pub trait GetErdData {
     fn get_relationship(
@@ -39,9 +108,48 @@ pub trait GetErdData {
 
     fn get_source(&self, entity_name: &str) -> Option<&Source>;
 
+    /// get_schema returns the json schema of the entity, if the ERD format has one.
+    fn get_schema(&self, entity_name: &str) -> Option<&Schema>;
+
+    /// get_discriminator returns the discriminator of the entity, if the ERD format has them.
+    fn get_discriminator(&self, entity_name: &str) -> Option<&Discriminator>;
+
+    fn get_relationships(
+        &self,
+        entity_name: &str,
+    ) -> impl Iterator<Item = (&String, &ErdRelationship)>;
+
     fn iter(&self) -> impl Iterator<Item = &String>;
 
     fn size(&self) -> usize;
+
+    /// get_collections returns the (db, collection) pairs that store the entity as top level
+    /// documents: its source, unless the source is embedded, and the collection of every foreign
+    /// relationship that targets the entity.
+    fn get_collections(&self, entity_name: &str) -> BTreeSet<(String, String)> {
+        let mut collections = BTreeSet::new();
+        if let Some(source) = self
+            .get_source(entity_name)
+            .filter(|source| source.target_path.is_none())
+        {
+            collections.insert((source.db.clone(), source.collection.clone()));
+        }
+        let incoming = self.iter().flat_map(|source_entity_name| {
+            self.get_relationships(source_entity_name)
+                .filter(|(target_entity_name, _)| *target_entity_name == entity_name)
+        });
+        for (_, relationship) in incoming {
+            let constraint = &relationship.constraint;
+            if let (ConstraintType::Foreign, Some(db), Some(collection)) = (
+                constraint.constraint_type,
+                constraint.db.as_ref(),
+                constraint.collection.as_ref(),
+            ) {
+                collections.insert((db.clone(), collection.clone()));
+            }
+        }
+        collections
+    }
 }
 
 impl GetErdData for Erd {
@@ -57,9 +165,23 @@ impl GetErdData for Erd {
         self.get_source(entity_name)
     }
 
+    fn get_schema(&self, entity_name: &str) -> Option<&Schema> {
+        self.get_schema(entity_name)
+    }
+
+    fn get_discriminator(&self, entity_name: &str) -> Option<&Discriminator> {
+        self.get_discriminator(entity_name)
+    }
+
+    fn get_relationships(
+        &self,
+        entity_name: &str,
+    ) -> impl Iterator<Item = (&String, &ErdRelationship)> {
+        self.get_relationships(entity_name)
+    }
+
     fn iter(&self) -> impl Iterator<Item = &String> {
-        self.iter()
-            .map(|(name, _)| name)
+        self.iter().map(|(name, _)| name)
     }
 
     fn size(&self) -> usize {
@@ -80,9 +202,23 @@ impl GetErdData for Relationships {
         None
     }
 
+    fn get_schema(&self, _entity_name: &str) -> Option<&Schema> {
+        None
+    }
+
+    fn get_discriminator(&self, _entity_name: &str) -> Option<&Discriminator> {
+        None
+    }
+
+    fn get_relationships(
+        &self,
+        entity_name: &str,
+    ) -> impl Iterator<Item = (&String, &ErdRelationship)> {
+        self.get_relationships(entity_name)
+    }
+
     fn iter(&self) -> impl Iterator<Item = &String> {
-        self.iter()
-            .map(|(name, _)| name)
+        self.iter().map(|(name, _)| name)
     }
 
     fn size(&self) -> usize {
@@ -91,43 +227,73 @@ impl GetErdData for Relationships {
 }
 
 impl ErdGraph {
-    pub fn new<T>(erd: &T) -> Self
-    where 
-        T: GetErdData
+    /// new builds the graph of the ERD. Relationships to entities that are not in the ERD are
+    /// left out, and foreign and bucket relationships that miss a field they are joined by are an
+    /// error.
+    pub fn new<T>(erd: &T) -> Result<Self, Error>
+    where
+        T: GetErdData,
     {
         let mut graph = DiGraph::default();
         let mut node_indices = HashMap::new();
-        let mut edge_data: HashMap<_, HashMap<_, _>> = HashMap::new();
+        let mut edge_data = HashMap::new();
 
-        // Add entities as nodes
-        for entity_name in erd.iter() {
+        // Add entities as nodes, sorted so that node and edge indices, and therefore the
+        // choice between paths of equal cost, do not depend on hash order
+        let mut entity_names: Vec<&String> = erd.iter().collect();
+        entity_names.sort();
+        for entity_name in entity_names.iter() {
             // TODO: we may not want to add the names as node labels for efficiency
             let node_index = graph.add_node(entity_name.to_string());
             node_indices.insert(entity_name.to_string(), node_index);
         }
 
-        // convert to Vec to induce a stable order
-        let node_indices_vec: Vec<_> = node_indices.iter().map(|(s, n)| (s, *n)).collect();
-        for (source_entity_name, source_index) in node_indices_vec.iter() {
-            for (target_entity_name, target_index) in node_indices_vec.iter() {
-                if source_entity_name == target_entity_name {
-                    continue; // Skip self-loops
-                }
-                if let Some((weight, constraint)) =
-                    get_edge_from_data(erd, source_entity_name, target_entity_name)
-                {
-                    graph.add_edge(*source_index, *target_index, weight);
-                    edge_data
-                        .entry(*source_index)
-                        .or_default()
-                        .insert(*target_index, constraint);
-                }
+        for source_entity_name in entity_names {
+            let source_index = node_indices[source_entity_name];
+            // the sort is stable, so relationships between the same pair keep the order they
+            // are listed in
+            let mut relationships: Vec<_> = erd.get_relationships(source_entity_name).collect();
+            relationships.sort_by_key(|(target_entity_name, _)| *target_entity_name);
+            for (target_entity_name, relationship) in relationships {
+                // self-loops are kept for self-joins, path search never takes them since they
+                // only add cost
+                let Some(&target_index) = node_indices.get(target_entity_name) else {
+                    continue;
+                };
+                let (weight, constraint) =
+                    get_edge_from_data(erd, source_entity_name, target_entity_name, relationship)?;
+                let edge_index = graph.add_edge(source_index, target_index, weight);
+                edge_data.insert(edge_index, constraint);
             }
         }
-        Self {
+        Ok(Self {
             graph,
             node_indices,
             edge_data,
+        })
+    }
+
+    /// retain_edges returns the graph with only the edges that keep accepts. Node indices are
+    /// unchanged.
+    pub fn retain_edges(&self, keep: impl Fn(&EdgeData) -> bool) -> ErdGraph {
+        let graph = self.graph.filter_map(
+            |_, node| Some(node.clone()),
+            |edge, weight| keep(&self.edge_data[&edge]).then_some(*weight),
+        );
+        // filter_map keeps the remaining edges in order, renumbering them
+        let kept = self
+            .graph
+            .edge_indices()
+            .filter(|edge| keep(&self.edge_data[edge]));
+        let edge_data = graph
+            .edge_indices()
+            .zip(kept)
+            .map(|(edge, old_edge)| (edge, self.edge_data[&old_edge].clone()))
+            .collect();
+        ErdGraph {
+            graph,
+            node_indices: self.node_indices.clone(),
+            edge_data,
         }
     }
 
@@ -139,6 +305,15 @@ impl ErdGraph {
         self.node_indices.get(entity_name).cloned()
     }
 
+    /// get_edge returns the edge taken between two entities when no relationship is named: the
+    /// cheapest one, and of those the first one listed in the ERD.
+    pub fn get_edge(&self, source_index: NodeIndex, target_index: NodeIndex) -> Option<EdgeIndex> {
+        self.graph
+            .edges_connecting(source_index, target_index)
+            .min_by_key(|edge| (*edge.weight(), edge.id()))
+            .map(|edge| edge.id())
+    }
+
     pub fn get_edge_data_by_names(
         &self,
         source_entity_name: &str,
@@ -155,8 +330,17 @@ impl ErdGraph {
         target_index: NodeIndex,
     ) -> Option<&EdgeData> {
         self.edge_data
-            .get(&source_index)
-            .and_then(|edges| edges.get(&target_index))
+            .get(&self.get_edge(source_index, target_index)?)
+    }
+
+    pub fn get_edge_weight(
+        &self,
+        source_index: NodeIndex,
+        target_index: NodeIndex,
+    ) -> Option<usize> {
+        self.get_edge(source_index, target_index)
+            .and_then(|edge| self.graph.edge_weight(edge))
+            .cloned()
     }
 
     pub fn path_to(
@@ -175,6 +359,117 @@ impl ErdGraph {
         Some(path)
     }
 
+    /// path_edges returns the edges taken along a path of nodes.
+    pub fn path_edges(&self, path: &[NodeIndex]) -> Option<Vec<EdgeIndex>> {
+        path.windows(2)
+            .map(|hop| self.get_edge(hop[0], hop[1]))
+            .collect()
+    }
+
+    /// named_edges_to returns every edge into the target that belongs to the relationship with
+    /// the given name.
+    pub fn named_edges_to<'a>(
+        &'a self,
+        target_index: NodeIndex,
+        name: &'a str,
+    ) -> impl Iterator<Item = EdgeIndex> + 'a {
+        self.graph
+            .edges_directed(target_index, Direction::Incoming)
+            .filter(move |edge| {
+                self.edge_data
+                    .get(&edge.id())
+                    .is_some_and(|data| data.name() == Some(name))
+            })
+            .map(|edge| edge.id())
+    }
+
+    /// shortest_paths returns, for every node reachable from any of the sources, the cost of the
+    /// cheapest path to it from the sources and its predecessor on that path. Sources have no
+    /// predecessor.
+    pub fn shortest_paths(
+        &self,
+        sources: &HashSet<NodeIndex>,
+    ) -> HashMap<NodeIndex, (usize, Option<NodeIndex>)> {
+        let mut paths = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for &source in sources.iter() {
+            paths.insert(source, (0, None));
+            heap.push(Reverse((0, source)));
+        }
+        while let Some(Reverse((cost, node))) = heap.pop() {
+            if paths.get(&node).is_some_and(|&(best, _)| best < cost) {
+                continue;
+            }
+            for edge in self.graph.edges(node) {
+                let next_cost = cost + *edge.weight();
+                let target = edge.target();
+                if paths.get(&target).is_none_or(|&(best, _)| next_cost < best) {
+                    paths.insert(target, (next_cost, Some(node)));
+                    heap.push(Reverse((next_cost, target)));
+                }
+            }
+        }
+        paths
+    }
+
+    /// path_from returns the cheapest path to the target from any of the sources, starting with
+    /// the source it leaves from.
+    pub fn path_from(
+        &self,
+        sources: &HashSet<NodeIndex>,
+        target_index: NodeIndex,
+    ) -> Option<Vec<NodeIndex>> {
+        let paths = self.shortest_paths(sources);
+        let mut path = vec![target_index];
+        let mut current = paths.get(&target_index)?;
+        while let (_, Some(predecessor)) = current {
+            path.push(*predecessor);
+            current = &paths[predecessor];
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// steiner_tree approximates the cheapest tree that connects every terminal to the sources,
+    /// by repeatedly adding the cheapest path from the tree built so far to the closest remaining
+    /// terminal. Intermediate hops are therefore shared between terminals. It returns the parent
+    /// of every node added to the tree; terminals that cannot be reached are left out.
+    pub fn steiner_tree(
+        &self,
+        sources: &HashSet<NodeIndex>,
+        terminals: &[NodeIndex],
+    ) -> HashMap<NodeIndex, NodeIndex> {
+        let mut tree = sources.clone();
+        let mut parents = HashMap::new();
+        let mut remaining: Vec<NodeIndex> = terminals
+            .iter()
+            .filter(|terminal| !tree.contains(terminal))
+            .copied()
+            .collect();
+        while !remaining.is_empty() {
+            let paths = self.shortest_paths(&tree);
+            // ties are broken by the order of the terminals
+            let Some((position, _)) = remaining
+                .iter()
+                .enumerate()
+                .filter_map(|(position, terminal)| {
+                    paths.get(terminal).map(|&(cost, _)| (position, cost))
+                })
+                .min_by_key(|&(position, cost)| (cost, position))
+            else {
+                break;
+            };
+            let mut node = remaining.remove(position);
+            while let Some(&(_, Some(parent))) = paths.get(&node) {
+                tree.insert(node);
+                parents.insert(node, parent);
+                node = parent;
+            }
+            remaining.retain(|terminal| !tree.contains(terminal));
+        }
+        parents
+    }
+
     pub fn path_to_by_names(
         &self,
         source_entity_name: &str,
@@ -192,62 +487,122 @@ impl ErdGraph {
 
 impl std::fmt::Display for ErdGraph {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", Dot::with_config(&self.graph, &[]))
+        write!(f, "{}", graph_export::to_dot(self))
     }
 }
 
-fn get_edge_from_data<T>
-(
+fn get_edge_from_data<T>(
     ed: &T,
     source_entity_name: &str,
     target_entity_name: &str,
-) -> Option<(usize, EdgeData)> 
-where 
+    relationship: &ErdRelationship,
+) -> Result<(usize, EdgeData), Error>
+where
     T: GetErdData,
 {
-    let get_relationship_weight = |relationship_type, constraint_type| {
-        match (relationship_type, constraint_type) {
-            // datas should actually be based of cardinality with a large constant factor for
-            // being foreign vs embeded
-            (ConstraintType::Embedded, RelationshipType::OneToOne) => 1,
-            (ConstraintType::Embedded, RelationshipType::ManyToOne) => 2,
-            (ConstraintType::Embedded, RelationshipType::ManyToMany) => 4,
-            (ConstraintType::Foreign, RelationshipType::OneToOne) => ed.size(),
-            (ConstraintType::Foreign, RelationshipType::ManyToOne) => ed.size() * 2,
-            (ConstraintType::Foreign, RelationshipType::ManyToMany) => ed.size() * 4,
-        }
+    let constraint = &relationship.constraint;
+    let foreign_field = |value: &Option<String>, field| {
+        value.clone().ok_or_else(|| {
+            Error::MissingForeignField(
+                source_entity_name.to_string(),
+                target_entity_name.to_string(),
+                field,
+            )
+        })
     };
-    let get_relationship_constraint = |entity_name: &str, relationship: &ErdRelationship| {
-        match relationship.constraint.constraint_type {
-            ConstraintType::Embedded => EdgeData::Embedded {
-                source_entity: entity_name.to_string(),
-                target_path: relationship
-                    .constraint
-                    .target_path
-                    .clone()
-                    .unwrap_or_default(),
-                relationship_type: relationship.relationship_type, // Default to ManyToOne if no relationship found
-            },
-            ConstraintType::Foreign => {
-                EdgeData::Foreign {
-                    db: relationship.constraint.db.clone().unwrap(),
-                    collection: relationship.constraint.collection.clone().unwrap(),
-                    relationship_type: relationship.relationship_type,
-                    foreign_key: relationship.constraint.foreign_key.clone().unwrap(),
-                    local_key: relationship.constraint.local_key.clone().unwrap(),
-                }
-            }
-        }
+    let bucket_field = |value: &Option<String>, field| {
+        value.clone().ok_or_else(|| {
+            Error::MissingBucketField(
+                source_entity_name.to_string(),
+                target_entity_name.to_string(),
+                field,
+            )
+        })
     };
-    if let Some(relationship) = ed.get_relationship(source_entity_name, target_entity_name) {
-        Some((
-            get_relationship_weight(
-                relationship.constraint.constraint_type,
-                relationship.relationship_type,
+    let edge = match constraint.constraint_type {
+        ConstraintType::Embedded => EdgeData::Embedded {
+            name: relationship.name.clone(),
+            source_entity: source_entity_name.to_string(),
+            target_path: constraint.target_path.clone().unwrap_or_default(),
+            relationship_type: relationship.relationship_type,
+            consistency: relationship.consistency,
+        },
+        ConstraintType::Foreign => EdgeData::Foreign {
+            name: relationship.name.clone(),
+            db: foreign_field(&constraint.db, "db")?,
+            collection: foreign_field(&constraint.collection, "collection")?,
+            relationship_type: relationship.relationship_type,
+            keys: constraint.key_pairs(),
+            junction: constraint.junction.clone(),
+            reference_array: reference_array(
+                ed,
+                source_entity_name,
+                target_entity_name,
+                relationship,
             ),
-            get_relationship_constraint(source_entity_name, relationship),
-        ))
+            index_field: constraint.index_field.clone(),
+            consistency: relationship.consistency,
+        },
+        ConstraintType::Bucket => EdgeData::Bucket {
+            name: relationship.name.clone(),
+            db: bucket_field(&constraint.db, "db")?,
+            collection: bucket_field(&constraint.collection, "collection")?,
+            local_key: bucket_field(&constraint.local_key, "localKey")?,
+            foreign_key: bucket_field(&constraint.foreign_key, "foreignKey")?,
+            array_path: bucket_field(&constraint.target_path, "targetPath")?,
+            bounds: constraint.bucket_bounds.clone(),
+            relationship_type: relationship.relationship_type,
+            consistency: relationship.consistency,
+        },
+    };
+    let weight = relationship.weight(ed.size());
+    Ok((weight, edge))
+}
+
+/// reference_array returns the side of a foreign relationship whose key is an array of
+/// references: the one the constraint names, or else the one whose key the entity schemas type
+/// as an array. Only single keys can be arrays of references.
+pub fn reference_array<T: GetErdData>(
+    erd: &T,
+    source_entity_name: &str,
+    target_entity_name: &str,
+    relationship: &ErdRelationship,
+) -> Option<ReferenceArray> {
+    let constraint = &relationship.constraint;
+    if constraint.reference_array.is_some() {
+        return constraint.reference_array;
+    }
+    let [pair] = constraint.key_pairs().try_into().ok()?;
+    let is_array = |entity_name: &str, key: &str| {
+        erd.get_schema(entity_name)
+            .is_some_and(|schema| is_array_path(schema, key))
+    };
+    if is_array(source_entity_name, &pair.local_key) {
+        Some(ReferenceArray::Local)
+    } else if is_array(target_entity_name, &pair.foreign_key) {
+        Some(ReferenceArray::Foreign)
     } else {
         None
     }
 }
+
+// is_array_path returns whether the schema types the dotted path as an array, possibly among
+// other alternatives such as null.
+fn is_array_path(schema: &Schema, path: &str) -> bool {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    match schema {
+        Schema::AnyOf(schemas) => schemas.iter().any(|schema| is_array_path(schema, path)),
+        Schema::Document(document) => match (document.keys.get(head), rest) {
+            (Some(Schema::Array(_)), None) => true,
+            (Some(Schema::AnyOf(schemas)), None) => schemas
+                .iter()
+                .any(|schema| matches!(schema, Schema::Array(_))),
+            (Some(child), Some(rest)) => is_array_path(child, rest),
+            _ => false,
+        },
+        _ => false,
+    }
+}
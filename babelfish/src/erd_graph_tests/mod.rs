@@ -0,0 +1,245 @@
+macro_rules! test_steiner_tree {
+    ($func_name:ident, expected = $expected:expr, sources = $sources:expr, terminals = $terminals:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::{erd::Relationships, erd_graph::ErdGraph};
+            use std::collections::{BTreeMap, HashSet};
+
+            let erd: Relationships = serde_json::from_str(crate::erd_graph_tests::ERD).unwrap();
+            let graph = ErdGraph::new(&erd).unwrap();
+            let index = |name: &str| graph.get_index(name).unwrap();
+            let name = |index| graph.get_entity_name(index).unwrap().as_str();
+            let sources: HashSet<_> = $sources.into_iter().map(index).collect();
+            let terminals: Vec<_> = $terminals.into_iter().map(index).collect();
+            let parents: BTreeMap<&str, &str> = graph
+                .steiner_tree(&sources, &terminals)
+                .into_iter()
+                .map(|(node, parent)| (name(node), name(parent)))
+                .collect();
+            let expected: BTreeMap<&str, &str> = $expected.into_iter().collect();
+            assert_eq!(expected, parents);
+        }
+    };
+}
+
+// A reaches D through B and through C at the same cost, and E only through a foreign
+// relationship. F is not connected.
+const ERD: &str = r#"{
+    "A": {
+        "B": {
+            "relationshipType": "one-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "b", "projection": []}
+        },
+        "C": {
+            "relationshipType": "one-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "c", "projection": []}
+        },
+        "E": {
+            "relationshipType": "one-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "test",
+                "collection": "e",
+                "localKey": "_id",
+                "foreignKey": "aId",
+                "projection": []
+            }
+        }
+    },
+    "B": {
+        "D": {
+            "relationshipType": "one-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "d", "projection": []}
+        }
+    },
+    "C": {
+        "D": {
+            "relationshipType": "one-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "d", "projection": []}
+        }
+    },
+    "D": {},
+    "E": {},
+    "F": {}
+}"#;
+
+#[test]
+fn shortest_paths_costs_and_predecessors() {
+    use crate::{erd::Relationships, erd_graph::ErdGraph};
+    use std::collections::BTreeMap;
+
+    let erd: Relationships = serde_json::from_str(ERD).unwrap();
+    let graph = ErdGraph::new(&erd).unwrap();
+    let name = |index| graph.get_entity_name(index).unwrap().as_str();
+    let paths: BTreeMap<&str, (usize, Option<&str>)> = graph
+        .shortest_paths(&[graph.get_index("A").unwrap()].into_iter().collect())
+        .into_iter()
+        .map(|(node, (cost, predecessor))| (name(node), (cost, predecessor.map(name))))
+        .collect();
+    // the foreign relationship costs the number of entities, and F is unreachable
+    let expected: BTreeMap<&str, (usize, Option<&str>)> = [
+        ("A", (0, None)),
+        ("B", (1, Some("A"))),
+        ("C", (1, Some("A"))),
+        ("D", (2, Some("B"))),
+        ("E", (6, Some("A"))),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(expected, paths);
+}
+
+test_steiner_tree!(
+    equal_cost_paths_take_lowest_index,
+    expected = [("B", "A"), ("D", "B")],
+    sources = ["A"],
+    terminals = ["D"]
+);
+
+test_steiner_tree!(
+    connecting_entity_is_added,
+    expected = [("B", "A"), ("D", "B"), ("E", "A")],
+    sources = ["A"],
+    terminals = ["E", "D"]
+);
+
+test_steiner_tree!(
+    cheapest_terminal_first_shares_hops,
+    expected = [("C", "A"), ("D", "C")],
+    sources = ["A"],
+    terminals = ["D", "C"]
+);
+
+test_steiner_tree!(
+    unreachable_terminal_is_left_out,
+    expected = [("B", "A")],
+    sources = ["A"],
+    terminals = ["F", "B"]
+);
+
+// the bucket relationship has no targetPath naming its array of items
+const INCOMPLETE_BUCKET_ERD: &str = r#"{
+    "Sensor": {
+        "Reading": {
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "bucket",
+                "db": "iot",
+                "collection": "reading_buckets",
+                "localKey": "_id",
+                "foreignKey": "sensorId",
+                "projection": []
+            }
+        }
+    },
+    "Reading": {}
+}"#;
+
+#[test]
+fn incomplete_bucket_is_an_error() {
+    use crate::{erd::Relationships, erd_graph::ErdGraph, erd_validation::Error};
+
+    let erd: Relationships = serde_json::from_str(INCOMPLETE_BUCKET_ERD).unwrap();
+    assert_eq!(
+        Some(Error::MissingBucketField(
+            "Sensor".to_string(),
+            "Reading".to_string(),
+            "targetPath"
+        )),
+        ErdGraph::new(&erd).err()
+    );
+}
+
+#[test]
+fn incomplete_bucket_fails_rewrite() {
+    use crate::{erd::Relationships, join_rewrite::rewrite_pipeline_with_erd};
+    use ast::definitions::Pipeline;
+
+    let erd: Relationships = serde_json::from_str(INCOMPLETE_BUCKET_ERD).unwrap();
+    let input: Pipeline =
+        serde_json::from_str(r#"[{"$join": {"$inner": {"root": "Sensor", "args": ["Reading"]}}}]"#)
+            .unwrap();
+    assert_eq!(
+        "Invalid ERD: Bucket relationship Sensor -> Reading is missing targetPath",
+        rewrite_pipeline_with_erd(input, &erd)
+            .unwrap_err()
+            .to_string()
+    );
+}
+
+// the billing address is stored in another collection and the shipping address embedded, so the
+// shipping address is cheaper although it is listed second
+const NAMED_RELATIONSHIPS_ERD: &str = r#"{
+    "Order": {
+        "Address": [
+            {
+                "name": "billingAddress",
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "addresses",
+                    "localKey": "billingAddressId",
+                    "foreignKey": "_id",
+                    "projection": []
+                }
+            },
+            {
+                "name": "shippingAddress",
+                "relationshipType": "one-to-one",
+                "constraint": {
+                    "constraintType": "embedded",
+                    "targetPath": "shippingAddress",
+                    "projection": []
+                }
+            }
+        ]
+    },
+    "Address": {}
+}"#;
+
+#[test]
+fn named_relationships_are_parsed_in_order() {
+    use crate::erd::Relationships;
+
+    let erd: Relationships = serde_json::from_str(NAMED_RELATIONSHIPS_ERD).unwrap();
+    let names: Vec<_> = erd
+        .get_relationships("Order")
+        .map(|(_, relationship)| relationship.name.as_deref())
+        .collect();
+    assert_eq!(vec![Some("billingAddress"), Some("shippingAddress")], names);
+}
+
+#[test]
+fn default_relationship_is_the_cheapest_edge() {
+    use crate::{erd::Relationships, erd_graph::ErdGraph};
+
+    let erd: Relationships = serde_json::from_str(NAMED_RELATIONSHIPS_ERD).unwrap();
+    let graph = ErdGraph::new(&erd).unwrap();
+    assert_eq!(
+        Some("shippingAddress"),
+        graph
+            .get_edge_data_by_names("Order", "Address")
+            .and_then(|edge| edge.name())
+    );
+    assert_eq!(
+        Some("shippingAddress"),
+        erd.get_relationship("Order", "Address")
+            .and_then(|relationship| relationship.name.as_deref())
+    );
+}
+
+#[test]
+fn malformed_relationship_reports_its_error() {
+    use crate::erd::Relationships;
+
+    let error = serde_json::from_str::<Relationships>(
+        r#"{"Order": {"Address": {"constraint": {"constraintType": "embedded", "targetPath": "a", "projection": []}}}}"#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(
+        error.starts_with("missing field `relationshipType`"),
+        "{error}"
+    );
+}
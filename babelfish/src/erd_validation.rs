@@ -0,0 +1,305 @@
+use crate::{
+    erd::{ConstraintType, ErdRelationship},
+    erd_graph::{GetErdData, reference_array},
+};
+use schema::Schema;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error("Relationship {0} -> {1} targets an entity that is not in the ERD")]
+    UnknownTargetEntity(String, String),
+    #[error("Foreign relationship {0} -> {1} is missing {2}")]
+    MissingForeignField(String, String, &'static str),
+    #[error("Embedded relationship {0} -> {1} is missing targetPath")]
+    MissingTargetPath(String, String),
+    #[error("Bucket relationship {0} -> {1} is missing {2}")]
+    MissingBucketField(String, String, &'static str),
+    #[error("Relationship {0} -> {1} reuses the name {2} of another relationship into {1}")]
+    DuplicateRelationshipName(String, String, String),
+    #[error("Relationships {0} -> {1} must all be named, since there are several")]
+    UnnamedParallelRelationship(String, String),
+    #[error("Relationship {0} -> {1} has both localKey/foreignKey and keys")]
+    AmbiguousKeys(String, String),
+    #[error("Relationship {0} -> {1} uses key {3}, which is not in the schema of {2}")]
+    KeyNotInSchema(String, String, String, String),
+    #[error("Relationship {0} -> {1} uses {2}, which needs a single localKey and foreignKey")]
+    UnsupportedCompositeKeys(String, String, &'static str),
+    #[error("Relationship {0} -> {1} has both a junction and a referenceArray")]
+    JunctionWithReferenceArray(String, String),
+    #[error("Relationship {0} -> {1} has an indexField but no array of references")]
+    IndexFieldWithoutReferenceArray(String, String),
+    #[error("Entity {0} shares {1} with entities that have a discriminator, but has none")]
+    MissingDiscriminator(String, String),
+    #[error("Entities {1} and {2} share {0} but have different discriminator fields")]
+    DiscriminatorFieldMismatch(String, String, String),
+    #[error("Entities {1} and {2} share {0} and the discriminator value {3}")]
+    DuplicateDiscriminatorValue(String, String, String, String),
+    #[error("Entity {0} has discriminator field {1}, which is not in its schema")]
+    DiscriminatorNotInSchema(String, String),
+}
+
+/// validate checks that every relationship in the ERD can be turned into an ERD graph edge, that
+/// the keys of foreign relationships are in the schemas of the entities they join, and that
+/// entities sharing a collection or embedded array are told apart by their discriminators. It
+/// returns every problem found, ordered by source and then target entity, followed by the
+/// discriminator problems.
+pub fn validate<T: GetErdData>(erd: &T) -> Vec<Error> {
+    let entities: HashSet<&String> = erd.iter().collect();
+    let mut sources: Vec<&String> = entities.iter().cloned().collect();
+    sources.sort();
+    let mut errors = Vec::new();
+    // relationship names must be unique per target entity, since `via` picks a relationship by
+    // its name and the entity it joins
+    let mut names = HashSet::new();
+    for source in sources {
+        let mut relationships: Vec<_> = erd.get_relationships(source).collect();
+        relationships.sort_by_key(|(target, _)| *target);
+        for pair in relationships.chunk_by(|(a, _), (b, _)| a == b) {
+            if pair.len() > 1
+                && pair
+                    .iter()
+                    .any(|(_, relationship)| relationship.name.is_none())
+            {
+                let (target, _) = pair[0];
+                errors.push(Error::UnnamedParallelRelationship(
+                    source.clone(),
+                    target.clone(),
+                ));
+            }
+        }
+        for (target, relationship) in relationships {
+            if !entities.contains(target) {
+                errors.push(Error::UnknownTargetEntity(source.clone(), target.clone()));
+            }
+            errors.extend(validate_constraint(source, target, relationship));
+            errors.extend(validate_keys(erd, source, target, relationship));
+            if let Some(name) = &relationship.name
+                && !names.insert((target, name))
+            {
+                errors.push(Error::DuplicateRelationshipName(
+                    source.clone(),
+                    target.clone(),
+                    name.clone(),
+                ));
+            }
+        }
+    }
+    errors.extend(validate_discriminators(erd));
+    errors
+}
+
+// validate_discriminators checks every collection and embedded array that holds several
+// entities, one of which has a discriminator: all of them need a discriminator on the same
+// field, with distinct values.
+fn validate_discriminators<T: GetErdData>(erd: &T) -> Vec<Error> {
+    let mut entities: Vec<&String> = erd.iter().collect();
+    entities.sort();
+    let mut errors = Vec::new();
+    let mut locations: BTreeMap<String, BTreeSet<&String>> = BTreeMap::new();
+    for entity in entities.iter().copied() {
+        if let Some(discriminator) = erd.get_discriminator(entity)
+            && erd
+                .get_schema(entity)
+                .is_some_and(|schema| !schema_has_path(schema, &discriminator.field))
+        {
+            errors.push(Error::DiscriminatorNotInSchema(
+                entity.clone(),
+                discriminator.field.clone(),
+            ));
+        }
+        for (db, collection) in erd.get_collections(entity) {
+            locations
+                .entry(format!("collection {}.{}", db, collection))
+                .or_default()
+                .insert(entity);
+        }
+        if let Some(source) = erd.get_source(entity)
+            && let Some(target_path) = &source.target_path
+        {
+            locations
+                .entry(format!(
+                    "collection {}.{} at {}",
+                    source.db, source.collection, target_path
+                ))
+                .or_default()
+                .insert(entity);
+        }
+        for (target, relationship) in erd.get_relationships(entity) {
+            if relationship.constraint.constraint_type == ConstraintType::Embedded
+                && let Some(target_path) = &relationship.constraint.target_path
+            {
+                locations
+                    .entry(format!("embedded array {}.{}", entity, target_path))
+                    .or_default()
+                    .insert(target);
+            }
+        }
+    }
+    for (location, entities) in locations {
+        let Some((first, first_discriminator)) = entities
+            .iter()
+            .find_map(|entity| Some((*entity, erd.get_discriminator(entity)?)))
+        else {
+            continue;
+        };
+        if entities.len() < 2 {
+            continue;
+        }
+        let mut values: HashMap<&str, &String> = HashMap::new();
+        for entity in entities {
+            let Some(discriminator) = erd.get_discriminator(entity) else {
+                errors.push(Error::MissingDiscriminator(
+                    entity.clone(),
+                    location.clone(),
+                ));
+                continue;
+            };
+            if discriminator.field != first_discriminator.field {
+                errors.push(Error::DiscriminatorFieldMismatch(
+                    location.clone(),
+                    first.clone(),
+                    entity.clone(),
+                ));
+            } else if let Some(other) = values.insert(&discriminator.value, entity) {
+                errors.push(Error::DuplicateDiscriminatorValue(
+                    location.clone(),
+                    other.clone(),
+                    entity.clone(),
+                    discriminator.value.clone(),
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn validate_constraint(source: &str, target: &str, relationship: &ErdRelationship) -> Vec<Error> {
+    let constraint = &relationship.constraint;
+    match constraint.constraint_type {
+        ConstraintType::Foreign => [
+            ("db", constraint.db.is_none()),
+            ("collection", constraint.collection.is_none()),
+            (
+                "localKey",
+                constraint.keys.is_none() && constraint.local_key.is_none(),
+            ),
+            (
+                "foreignKey",
+                constraint.keys.is_none() && constraint.foreign_key.is_none(),
+            ),
+            ("keys", constraint.keys.as_ref().is_some_and(Vec::is_empty)),
+        ]
+        .into_iter()
+        .filter(|(_, missing)| *missing)
+        .map(|(field, _)| Error::MissingForeignField(source.to_string(), target.to_string(), field))
+        .collect(),
+        ConstraintType::Bucket => [
+            ("db", constraint.db.is_none()),
+            ("collection", constraint.collection.is_none()),
+            ("localKey", constraint.local_key.is_none()),
+            ("foreignKey", constraint.foreign_key.is_none()),
+            ("targetPath", constraint.target_path.is_none()),
+        ]
+        .into_iter()
+        .filter(|(_, missing)| *missing)
+        .map(|(field, _)| Error::MissingBucketField(source.to_string(), target.to_string(), field))
+        .collect(),
+        ConstraintType::Embedded => {
+            if constraint.target_path.is_none() {
+                vec![Error::MissingTargetPath(
+                    source.to_string(),
+                    target.to_string(),
+                )]
+            } else {
+                vec![]
+            }
+        }
+    }
+}
+
+// validate_keys checks that the keys of a foreign relationship fit how it is stored, and that
+// every key pair exists in the schemas of both entities. ERD formats without schemas are not
+// checked against schemas.
+fn validate_keys<T: GetErdData>(
+    erd: &T,
+    source: &str,
+    target: &str,
+    relationship: &ErdRelationship,
+) -> Vec<Error> {
+    let constraint = &relationship.constraint;
+    if constraint.constraint_type != ConstraintType::Foreign {
+        return vec![];
+    }
+    if constraint.keys.is_some()
+        && (constraint.local_key.is_some() || constraint.foreign_key.is_some())
+    {
+        return vec![Error::AmbiguousKeys(source.to_string(), target.to_string())];
+    }
+    let mut errors = Vec::new();
+    if constraint.junction.is_some() && constraint.reference_array.is_some() {
+        errors.push(Error::JunctionWithReferenceArray(
+            source.to_string(),
+            target.to_string(),
+        ));
+    }
+    if constraint.index_field.is_some()
+        && reference_array(erd, source, target, relationship).is_none()
+    {
+        errors.push(Error::IndexFieldWithoutReferenceArray(
+            source.to_string(),
+            target.to_string(),
+        ));
+    }
+    if constraint.key_pairs().len() > 1 {
+        for (field, used) in [
+            ("junction", constraint.junction.is_some()),
+            ("referenceArray", constraint.reference_array.is_some()),
+        ] {
+            if used {
+                errors.push(Error::UnsupportedCompositeKeys(
+                    source.to_string(),
+                    target.to_string(),
+                    field,
+                ));
+            }
+        }
+    }
+    for pair in constraint.key_pairs() {
+        for (entity, key) in [(source, &pair.local_key), (target, &pair.foreign_key)] {
+            if erd
+                .get_schema(entity)
+                .is_some_and(|schema| !schema_has_path(schema, key))
+            {
+                errors.push(Error::KeyNotInSchema(
+                    source.to_string(),
+                    target.to_string(),
+                    entity.to_string(),
+                    key.clone(),
+                ));
+            }
+        }
+    }
+    errors
+}
+
+// schema_has_path returns whether a document of the schema may have a value at the dotted path,
+// looking through arrays and alternatives.
+fn schema_has_path(schema: &Schema, path: &str) -> bool {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    match schema {
+        Schema::Any => true,
+        Schema::AnyOf(schemas) => schemas.iter().any(|schema| schema_has_path(schema, path)),
+        Schema::Array(items) => schema_has_path(items, path),
+        Schema::Document(document) => match (document.keys.get(head), rest) {
+            (Some(_), None) => true,
+            (Some(child), Some(rest)) => schema_has_path(child, rest),
+            (None, _) => document.additional_properties,
+        },
+        Schema::Unsat | Schema::Missing | Schema::Atomic(_) => false,
+    }
+}
@@ -0,0 +1,454 @@
+macro_rules! test_validate {
+    ($func_name:ident, expected = $expected:expr, erd = $erd:expr) => {
+        test_validate!(
+            $func_name,
+            expected = $expected,
+            format = crate::erd::Erd,
+            erd = $erd
+        );
+    };
+    ($func_name:ident, expected = $expected:expr, format = $format:ty, erd = $erd:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::erd_validation::validate;
+
+            let erd: $format = serde_json::from_str($erd).unwrap();
+            let errors: Vec<String> = validate(&erd).iter().map(ToString::to_string).collect();
+            let expected: Vec<&str> = $expected;
+            assert_eq!(expected, errors);
+        }
+    };
+}
+
+test_validate!(
+    distinct_discriminators_are_valid,
+    expected = vec![],
+    erd = r#"{
+        "Click": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {"bsonType": "object", "properties": {"type": {"bsonType": "string"}}},
+            "discriminator": {"field": "type", "value": "click"}
+        },
+        "View": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {},
+            "discriminator": {"field": "type", "value": "view"}
+        }
+    }"#
+);
+
+test_validate!(
+    missing_discriminator,
+    expected = vec![
+        "Entity View shares collection web.events with entities that have a discriminator, but has none"
+    ],
+    erd = r#"{
+        "Click": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {},
+            "discriminator": {"field": "type", "value": "click"}
+        },
+        "View": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {}
+        }
+    }"#
+);
+
+test_validate!(
+    discriminator_field_mismatch,
+    expected = vec![
+        "Entities Click and View share collection web.events but have different discriminator fields"
+    ],
+    erd = r#"{
+        "Click": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {},
+            "discriminator": {"field": "type", "value": "click"}
+        },
+        "View": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {},
+            "discriminator": {"field": "kind", "value": "view"}
+        }
+    }"#
+);
+
+test_validate!(
+    duplicate_discriminator_value_in_embedded_array,
+    expected = vec![
+        "Entities Click and View share collection web.sessions at events and the discriminator value event",
+        "Entities Click and View share embedded array Session.events and the discriminator value event"
+    ],
+    erd = r#"{
+        "Session": {
+            "source": {"db": "web", "collection": "sessions"},
+            "primaryKey": "_id",
+            "relationships": {
+                "Click": {
+                    "relationshipType": "many-to-one",
+                    "constraint": {"constraintType": "embedded", "targetPath": "events", "projection": []}
+                },
+                "View": {
+                    "relationshipType": "many-to-one",
+                    "constraint": {"constraintType": "embedded", "targetPath": "events", "projection": []}
+                }
+            },
+            "jsonSchema": {}
+        },
+        "Click": {
+            "source": {"db": "web", "collection": "sessions", "targetPath": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {},
+            "discriminator": {"field": "type", "value": "event"}
+        },
+        "View": {
+            "source": {"db": "web", "collection": "sessions", "targetPath": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {},
+            "discriminator": {"field": "type", "value": "event"}
+        }
+    }"#
+);
+
+test_validate!(
+    discriminator_not_in_schema,
+    expected = vec!["Entity Click has discriminator field type, which is not in its schema"],
+    erd = r#"{
+        "Click": {
+            "source": {"db": "web", "collection": "events"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {
+                "bsonType": "object",
+                "properties": {"kind": {"bsonType": "string"}},
+                "additionalProperties": false
+            },
+            "discriminator": {"field": "type", "value": "click"}
+        }
+    }"#
+);
+
+test_validate!(
+    unknown_target_entity,
+    expected = vec!["Relationship Customer -> Address targets an entity that is not in the ERD"],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Customer": {
+            "Address": {
+                "relationshipType": "one-to-one",
+                "constraint": {"constraintType": "embedded", "targetPath": "address", "projection": []}
+            }
+        }
+    }"#
+);
+
+test_validate!(
+    missing_constraint_fields,
+    expected = vec![
+        "Foreign relationship Customer -> Order is missing collection",
+        "Foreign relationship Customer -> Order is missing foreignKey",
+        "Embedded relationship Customer -> Phone is missing targetPath",
+        "Bucket relationship Sensor -> Reading is missing localKey",
+        "Bucket relationship Sensor -> Reading is missing targetPath"
+    ],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Customer": {
+            "Order": {
+                "relationshipType": "many-to-one",
+                "constraint": {"constraintType": "foreign", "db": "shop", "localKey": "_id", "projection": []}
+            },
+            "Phone": {
+                "relationshipType": "many-to-one",
+                "constraint": {"constraintType": "embedded", "projection": []}
+            }
+        },
+        "Sensor": {
+            "Reading": {
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "bucket",
+                    "db": "iot",
+                    "collection": "readings",
+                    "foreignKey": "sensorId",
+                    "projection": []
+                }
+            }
+        },
+        "Order": {},
+        "Phone": {},
+        "Reading": {}
+    }"#
+);
+
+test_validate!(
+    parallel_relationship_names,
+    expected = vec![
+        "Relationships Order -> Address must all be named, since there are several",
+        "Relationship Order -> Address reuses the name billing of another relationship into Address"
+    ],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Order": {
+            "Address": [
+                {
+                    "name": "billing",
+                    "relationshipType": "one-to-one",
+                    "constraint": {"constraintType": "embedded", "targetPath": "billing", "projection": []}
+                },
+                {
+                    "name": "billing",
+                    "relationshipType": "one-to-one",
+                    "constraint": {"constraintType": "embedded", "targetPath": "shipping", "projection": []}
+                },
+                {
+                    "relationshipType": "one-to-one",
+                    "constraint": {"constraintType": "embedded", "targetPath": "returns", "projection": []}
+                }
+            ]
+        },
+        "Address": {}
+    }"#
+);
+
+test_validate!(
+    junction_with_reference_array,
+    expected = vec!["Relationship Student -> Course has both a junction and a referenceArray"],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Student": {
+            "Course": {
+                "relationshipType": "many-to-many",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "school",
+                    "collection": "courses",
+                    "localKey": "_id",
+                    "foreignKey": "_id",
+                    "junction": {
+                        "db": "school",
+                        "collection": "enrollments",
+                        "localKey": "studentId",
+                        "foreignKey": "courseId"
+                    },
+                    "referenceArray": "local",
+                    "projection": []
+                }
+            }
+        },
+        "Course": {}
+    }"#
+);
+
+test_validate!(
+    key_not_in_schema,
+    expected = vec![
+        "Relationship Order -> Customer uses key customer_id, which is not in the schema of Order"
+    ],
+    erd = r#"{
+        "Order": {
+            "source": {"db": "shop", "collection": "orders"},
+            "primaryKey": "_id",
+            "relationships": {
+                "Customer": {
+                    "relationshipType": "many-to-one",
+                    "constraint": {
+                        "constraintType": "foreign",
+                        "db": "shop",
+                        "collection": "customers",
+                        "localKey": "customer_id",
+                        "foreignKey": "_id",
+                        "projection": []
+                    }
+                }
+            },
+            "jsonSchema": {
+                "bsonType": "object",
+                "properties": {"_id": {"bsonType": "int"}, "customerId": {"bsonType": "int"}},
+                "additionalProperties": false
+            }
+        },
+        "Customer": {
+            "source": {"db": "shop", "collection": "customers"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {
+                "bsonType": "object",
+                "properties": {"_id": {"bsonType": "int"}},
+                "additionalProperties": false
+            }
+        }
+    }"#
+);
+
+test_validate!(
+    index_field_without_reference_array,
+    expected = vec!["Relationship Customer -> Order has an indexField but no array of references"],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Customer": {
+            "Order": {
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "orders",
+                    "localKey": "_id",
+                    "foreignKey": "customerId",
+                    "indexField": "position",
+                    "projection": []
+                }
+            }
+        },
+        "Order": {}
+    }"#
+);
+
+test_validate!(
+    empty_composite_keys,
+    expected = vec!["Foreign relationship Order -> Shipment is missing keys"],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Order": {
+            "Shipment": {
+                "relationshipType": "one-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "shipments",
+                    "keys": [],
+                    "projection": []
+                }
+            }
+        },
+        "Shipment": {}
+    }"#
+);
+
+test_validate!(
+    composite_keys_with_local_key,
+    expected = vec!["Relationship Order -> Shipment has both localKey/foreignKey and keys"],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Order": {
+            "Shipment": {
+                "relationshipType": "one-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "shipments",
+                    "localKey": "orderNo",
+                    "keys": [
+                        {"localKey": "tenantId", "foreignKey": "tenant"},
+                        {"localKey": "orderNo", "foreignKey": "order.number"}
+                    ],
+                    "projection": []
+                }
+            }
+        },
+        "Shipment": {}
+    }"#
+);
+
+test_validate!(
+    composite_keys_with_junction,
+    expected = vec![
+        "Relationship Order -> Shipment uses junction, which needs a single localKey and foreignKey"
+    ],
+    format = crate::erd::Relationships,
+    erd = r#"{
+        "Order": {
+            "Shipment": {
+                "relationshipType": "many-to-many",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "shipments",
+                    "keys": [
+                        {"localKey": "tenantId", "foreignKey": "tenant"},
+                        {"localKey": "orderNo", "foreignKey": "order.number"}
+                    ],
+                    "junction": {
+                        "db": "shop",
+                        "collection": "order_shipments",
+                        "localKey": "orderId",
+                        "foreignKey": "shipmentId"
+                    },
+                    "projection": []
+                }
+            }
+        },
+        "Shipment": {}
+    }"#
+);
+
+// the nested foreign key of the second pair is misspelled
+test_validate!(
+    composite_key_not_in_schema,
+    expected = vec![
+        "Relationship Order -> Shipment uses key order.num, which is not in the schema of Shipment"
+    ],
+    erd = r#"{
+        "Order": {
+            "source": {"db": "shop", "collection": "orders"},
+            "primaryKey": "_id",
+            "relationships": {
+                "Shipment": {
+                    "relationshipType": "one-to-one",
+                    "constraint": {
+                        "constraintType": "foreign",
+                        "db": "shop",
+                        "collection": "shipments",
+                        "keys": [
+                            {"localKey": "tenantId", "foreignKey": "tenant"},
+                            {"localKey": "orderNo", "foreignKey": "order.num"}
+                        ],
+                        "projection": []
+                    }
+                }
+            },
+            "jsonSchema": {
+                "bsonType": "object",
+                "properties": {
+                    "_id": {"bsonType": "int"},
+                    "tenantId": {"bsonType": "int"},
+                    "orderNo": {"bsonType": "int"}
+                },
+                "additionalProperties": false
+            }
+        },
+        "Shipment": {
+            "source": {"db": "shop", "collection": "shipments"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {
+                "bsonType": "object",
+                "properties": {
+                    "_id": {"bsonType": "int"},
+                    "tenant": {"bsonType": "int"},
+                    "order": {
+                        "bsonType": "object",
+                        "properties": {"number": {"bsonType": "int"}},
+                        "additionalProperties": false
+                    }
+                },
+                "additionalProperties": false
+            }
+        }
+    }"#
+);
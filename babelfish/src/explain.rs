@@ -0,0 +1,395 @@
+use crate::{
+    erd::{
+        BucketBounds, Consistency, ConstraintType, Junction, KeyPair, ReferenceArray,
+        RelationshipType, describe_bounds, describe_keys,
+    },
+    erd_graph::{EdgeData, GetErdData},
+    join_rewrite::{self, JoinOptions},
+    match_movement_rewrite,
+    passes::{Pass, Plan, Result},
+};
+use ast::definitions::{
+    ConciseSubqueryLookup, EqualityLookup, Expression, Lookup, Pipeline, Ref, Stage,
+    SubqueryLookup, Unwind, UnwindExpr,
+};
+use serde::Serialize;
+use std::fmt;
+
+/// Explain is the annotated trace of rewriting a pipeline: the ERD paths chosen for every
+/// `$join`, the stages each edge produced, and every `$match` movement, followed by the final
+/// pipeline and the namespace to run it on, as a Plan gives them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Explain {
+    pub joins: Vec<JoinExplain>,
+    pub match_movements: Vec<MatchMovement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    pub pipeline: Pipeline,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinExplain {
+    pub root: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_concern: Option<Consistency>,
+    pub entities: Vec<EntityPath>,
+    /// implicit_entities are the entities that were joined only because they are on the path to
+    /// a requested entity.
+    pub implicit_entities: Vec<String>,
+}
+
+/// EntityPath is the ERD path chosen to bring one entity into scope.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityPath {
+    pub entity: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub path: Vec<String>,
+    pub hops: Vec<Hop>,
+}
+
+/// Hop is a single ERD edge on an EntityPath. Hops to entities that were already in scope
+/// produce no stages.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hop {
+    pub source: String,
+    pub target: String,
+    pub weight: usize,
+    pub edge: HopEdge,
+    /// consistency is the consistency the hop delivers.
+    pub consistency: Consistency,
+    pub already_in_scope: bool,
+    pub stages: Vec<Stage>,
+}
+
+/// HopEdge is the ERD edge a Hop follows, flattened so that every kind of edge reports the same
+/// fields. Bucket edges give their localKey and foreignKey as the single pair of keys, and their
+/// array as targetPath.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HopEdge {
+    pub constraint_type: ConstraintType,
+    pub relationship_type: RelationshipType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// source_entity is the entity that embeds the target of an embedded edge.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_entity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<KeyPair>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub junction: Option<Junction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference_array: Option<ReferenceArray>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_bounds: Option<BucketBounds>,
+}
+
+impl From<EdgeData> for HopEdge {
+    fn from(edge: EdgeData) -> Self {
+        let empty = HopEdge {
+            constraint_type: ConstraintType::Embedded,
+            relationship_type: edge.relationship_type(),
+            name: edge.name().map(str::to_string),
+            source_entity: None,
+            db: None,
+            collection: None,
+            keys: Vec::new(),
+            target_path: None,
+            junction: None,
+            reference_array: None,
+            index_field: None,
+            bucket_bounds: None,
+        };
+        match edge {
+            EdgeData::Embedded {
+                source_entity,
+                target_path,
+                ..
+            } => HopEdge {
+                source_entity: Some(source_entity),
+                target_path: Some(target_path),
+                ..empty
+            },
+            EdgeData::Foreign {
+                db,
+                collection,
+                keys,
+                junction,
+                reference_array,
+                index_field,
+                ..
+            } => HopEdge {
+                constraint_type: ConstraintType::Foreign,
+                db: Some(db),
+                collection: Some(collection),
+                keys,
+                junction,
+                reference_array,
+                index_field,
+                ..empty
+            },
+            EdgeData::Bucket {
+                db,
+                collection,
+                local_key,
+                foreign_key,
+                array_path,
+                bounds,
+                ..
+            } => HopEdge {
+                constraint_type: ConstraintType::Bucket,
+                db: Some(db),
+                collection: Some(collection),
+                keys: vec![KeyPair {
+                    local_key,
+                    foreign_key,
+                }],
+                target_path: Some(array_path),
+                bucket_bounds: bounds,
+                ..empty
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MovementKind {
+    /// The `$match` moved earlier within the same pipeline.
+    Moved,
+    /// The `$match` moved out of a `$lookup` subpipeline into the parent pipeline, before the
+    /// `$lookup`.
+    Hoisted,
+    /// The `$match` moved into the `$lookup` subpipeline before it.
+    Pushed,
+}
+
+/// MatchMovement is a single movement of a `$match`. Positions are taken in the pipeline as it
+/// was when the `$match` moved, and change as later movements move, insert and remove stages, so
+/// the stages passed are also given by name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchMovement {
+    pub kind: MovementKind,
+    pub predicate: Expression,
+    /// from is the position of the `$match` before it moved. It is a position in the
+    /// subpipeline for Hoisted movements.
+    pub from: usize,
+    /// to is the position of the `$match` after it moved. It is a position in the subpipeline
+    /// for Pushed movements.
+    pub to: usize,
+    /// past describes the stages the `$match` moved past, in the order it passed them.
+    pub past: Vec<String>,
+    /// lookup describes the `$lookup` the `$match` was hoisted out of or pushed into.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookup: Option<String>,
+}
+
+/// explain_pipeline runs the given passes in order, recording the trace of every `$join` and
+/// match movement pass along the way.
+pub fn explain_pipeline<T: GetErdData>(
+    mut pipeline: Pipeline,
+    passes: &[Pass],
+    erd: &T,
+    options: JoinOptions,
+) -> Result<Explain> {
+    let mut joins = Vec::new();
+    let mut match_movements = Vec::new();
+    for pass in passes {
+        pipeline = match pass {
+            Pass::Join => {
+                let (pipeline, explain) =
+                    join_rewrite::rewrite_pipeline_with_explain(pipeline, erd, options)?;
+                joins.extend(explain);
+                pipeline
+            }
+            Pass::MatchMove => {
+                let (pipeline, movements) =
+                    match_movement_rewrite::rewrite_match_move_with_explain(pipeline);
+                match_movements.extend(movements);
+                pipeline
+            }
+            pass => pass.run(pipeline, erd, options)?,
+        };
+    }
+    let Plan {
+        db,
+        collection,
+        pipeline,
+    } = Plan::from_pipeline(pipeline);
+    Ok(Explain {
+        joins,
+        match_movements,
+        db,
+        collection,
+        pipeline,
+    })
+}
+
+/// flatten_stages removes any SubPipeline nesting so that explain output only contains real
+/// stages.
+pub fn flatten_stages(stage: &Stage) -> Vec<Stage> {
+    match stage {
+        Stage::SubPipeline(pipeline) => pipeline.pipeline.iter().flat_map(flatten_stages).collect(),
+        stage => vec![stage.clone()],
+    }
+}
+
+/// describe_stage names the stage by its operator, followed by the output of a `$lookup` and the
+/// path of an `$unwind`, which tell apart the stages generated for different entities.
+pub fn describe_stage(stage: &Stage) -> String {
+    let name = serde_json::to_value(stage)
+        .ok()
+        .and_then(|value| value.as_object()?.keys().next().cloned())
+        .unwrap_or_else(|| "$subPipeline".to_string());
+    let detail = match stage {
+        Stage::Lookup(Lookup::Equality(EqualityLookup { as_var, .. }))
+        | Stage::Lookup(Lookup::ConciseSubquery(ConciseSubqueryLookup { as_var, .. }))
+        | Stage::Lookup(Lookup::Subquery(SubqueryLookup { as_var, .. })) => Some(as_var),
+        Stage::Unwind(Unwind::FieldPath(Expression::Ref(Ref::FieldRef(path)))) => Some(path),
+        Stage::Unwind(Unwind::Document(UnwindExpr { path, .. })) => match path.as_ref() {
+            Expression::Ref(Ref::FieldRef(path)) => Some(path),
+            _ => None,
+        },
+        _ => None,
+    };
+    match detail {
+        Some(detail) => format!("{} {}", name, detail),
+        None => name,
+    }
+}
+
+fn describe_edge(edge: &HopEdge) -> String {
+    let location = match (&edge.db, &edge.collection) {
+        (Some(db), Some(collection)) => format!("{}.{}", db, collection),
+        _ => String::new(),
+    };
+    let target_path = edge.target_path.as_deref().unwrap_or_default();
+    match edge.constraint_type {
+        ConstraintType::Embedded => format!(
+            "embedded at {}.{} ({:?})",
+            edge.source_entity.as_deref().unwrap_or_default(),
+            target_path,
+            edge.relationship_type
+        ),
+        ConstraintType::Foreign => format!(
+            "foreign {} {} ({:?})",
+            location,
+            describe_keys(
+                &edge.keys,
+                edge.junction.as_ref(),
+                edge.reference_array,
+                " -> "
+            ),
+            edge.relationship_type
+        ),
+        ConstraintType::Bucket => format!(
+            "bucket {} {} at {}{} ({:?})",
+            location,
+            describe_keys(&edge.keys, None, None, " -> "),
+            target_path,
+            describe_bounds(edge.bucket_bounds.as_ref()),
+            edge.relationship_type
+        ),
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| format!("<{}>", e))
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for join in self.joins.iter() {
+            match join.read_concern {
+                Some(read_concern) => writeln!(
+                    f,
+                    "$join rooted at {} with {} read concern",
+                    join.root, read_concern
+                )?,
+                None => writeln!(f, "$join rooted at {}", join.root)?,
+            }
+            for entity in join.entities.iter() {
+                match &entity.alias {
+                    Some(alias) => writeln!(
+                        f,
+                        "  {} as {}: {}",
+                        entity.entity,
+                        alias,
+                        entity.path.join(" -> ")
+                    )?,
+                    None => writeln!(f, "  {}: {}", entity.entity, entity.path.join(" -> "))?,
+                }
+                for hop in entity.hops.iter() {
+                    writeln!(
+                        f,
+                        "    {} -> {} [weight {}, {}] {}",
+                        hop.source,
+                        hop.target,
+                        hop.weight,
+                        hop.consistency,
+                        describe_edge(&hop.edge)
+                    )?;
+                    if hop.already_in_scope {
+                        writeln!(f, "      already in scope")?;
+                    }
+                    for stage in hop.stages.iter() {
+                        writeln!(f, "      {}", to_json(stage))?;
+                    }
+                }
+            }
+            if !join.implicit_entities.is_empty() {
+                writeln!(
+                    f,
+                    "  implicit entities: {}",
+                    join.implicit_entities.join(", ")
+                )?;
+            }
+        }
+        if !self.match_movements.is_empty() {
+            writeln!(f, "match movements")?;
+            for movement in self.match_movements.iter() {
+                let kind = match movement.kind {
+                    MovementKind::Moved => "moved",
+                    MovementKind::Hoisted => "hoisted",
+                    MovementKind::Pushed => "pushed",
+                };
+                let mut description = format!("{} {} -> {}", kind, movement.from, movement.to);
+                if !movement.past.is_empty() {
+                    description = format!("{} past {}", description, movement.past.join(", "));
+                }
+                match (movement.kind, &movement.lookup) {
+                    (MovementKind::Hoisted, Some(lookup)) => {
+                        description = format!("{} out of {}", description, lookup)
+                    }
+                    (_, Some(lookup)) => description = format!("{} into {}", description, lookup),
+                    (_, None) => {}
+                }
+                writeln!(f, "  {}: {}", description, to_json(&movement.predicate))?;
+            }
+        }
+        match (&self.db, &self.collection) {
+            (Some(db), Some(collection)) => writeln!(f, "pipeline on {}.{}", db, collection)?,
+            _ => writeln!(f, "pipeline")?,
+        }
+        for (i, stage) in self.pipeline.pipeline.iter().enumerate() {
+            writeln!(f, "  {}: {}", i, to_json(stage))?;
+        }
+        Ok(())
+    }
+}
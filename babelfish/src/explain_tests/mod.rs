@@ -0,0 +1,176 @@
+macro_rules! test_explain {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        test_explain!(
+            $func_name,
+            expected = $expected,
+            output = ToString::to_string,
+            input = $input
+        );
+    };
+    ($func_name:ident, expected = $expected:expr, output = $output:path, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::{
+                erd::Relationships, explain::explain_pipeline, join_rewrite::JoinOptions,
+                passes::DEFAULT_PASSES,
+            };
+            use ast::definitions::Pipeline;
+
+            let erd: Relationships = serde_json::from_str(crate::explain_tests::ERD).unwrap();
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let explain =
+                explain_pipeline(input, DEFAULT_PASSES, &erd, JoinOptions::default()).unwrap();
+            assert_eq!($expected, $output(&explain));
+        }
+    };
+}
+
+const ERD: &str = r#"{
+    "Customer": {
+        "Order": {
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "orders",
+                "localKey": "_id",
+                "foreignKey": "customerId",
+                "projection": []
+            }
+        }
+    },
+    "Order": {
+        "Product": {
+            "relationshipType": "one-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "products",
+                "localKey": "productId",
+                "foreignKey": "_id",
+                "projection": []
+            }
+        }
+    },
+    "Product": {}
+}"#;
+
+test_explain!(
+    match_movements_name_stages,
+    expected = r#"$join rooted at Customer
+  Order: Customer -> Order
+    Customer -> Order [weight 6, strong] foreign shop.orders _id -> customerId (ManyToOne)
+      {"$lookup":{"from":"orders","localField":"Customer._id","foreignField":"customerId","as":"Order"}}
+      {"$unwind":{"path":"$Order","preserveNullAndEmptyArrays":false}}
+match movements
+  moved 4 -> 0 past $unwind Order, $lookup Order, $project: {"$eq":["$name","Ada"]}
+  pushed 4 -> 1 past $unwind Order into $lookup Order: {"$gt":["$total",500]}
+pipeline
+  0: {"$match":{"$expr":{"$and":[{"$eq":["$name","Ada"]}]}}}
+  1: {"$project":{"Customer":"$$ROOT","_id":false}}
+  2: {"$lookup":{"from":"orders","localField":"Customer._id","foreignField":"customerId","pipeline":[{"$match":{"$expr":{"$gt":["$total",500]}}}],"as":"Order"}}
+  3: {"$unwind":{"path":"$Order","preserveNullAndEmptyArrays":false}}
+  4: {"$limit":10}
+"#,
+    input = r#"[
+        {"$join": {"$inner": {
+            "root": "Customer",
+            "args": ["Order"],
+            "condition": {"$and": [{"$gt": ["$Order.total", 500]}, {"$eq": ["$Customer.name", "Ada"]}]}
+        }}},
+        {"$limit": 10}
+    ]"#
+);
+
+test_explain!(
+    hoisted_match_names_lookup,
+    expected = r#"match movements
+  hoisted 0 -> 0 out of $lookup orders: {"$eq":["$Customer.tier","gold"]}
+pipeline
+  0: {"$match":{"$expr":{"$and":[{"$eq":["$Customer.tier","gold"]}]}}}
+  1: {"$lookup":{"from":"orders","let":{"tier":"$Customer.tier"},"pipeline":[],"as":"orders"}}
+"#,
+    input = r#"[
+        {"$lookup": {
+            "from": "orders",
+            "let": {"tier": "$Customer.tier"},
+            "pipeline": [{"$match": {"$expr": {"$eq": ["$$tier", "gold"]}}}],
+            "as": "orders"
+        }}
+    ]"#
+);
+
+test_explain!(
+    internal_variables_are_not_reported,
+    expected = false,
+    output = crate::explain_tests::reports_local_field_var,
+    input = r#"[
+        {"$join": {"$inner": {
+            "root": "Customer",
+            "args": ["Order"],
+            "condition": {"$gt": ["$Order.total", 500]}
+        }}}
+    ]"#
+);
+
+fn reports_local_field_var(explain: &crate::explain::Explain) -> bool {
+    let json = serde_json::to_string(explain).unwrap();
+    let text = explain.to_string();
+    [json, text]
+        .iter()
+        .any(|output| output.contains(crate::lookup_normalize::LOCAL_FIELD_VAR))
+}
+
+test_explain!(
+    hop_edge_is_camel_case,
+    expected = serde_json::json!([{
+        "source": "Customer",
+        "target": "Order",
+        "weight": 6,
+        "edge": {
+            "constraintType": "foreign",
+            "relationshipType": "many-to-one",
+            "db": "shop",
+            "collection": "orders",
+            "keys": [{"localKey": "_id", "foreignKey": "customerId"}]
+        },
+        "consistency": "strong",
+        "alreadyInScope": false,
+        "stages": [
+            {"$lookup": {"from": "orders", "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}},
+            {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}
+        ]
+    }]),
+    output = crate::explain_tests::hops_json,
+    input = r#"[
+        {"$join": {"$inner": {"root": "Customer", "args": ["Order"]}}}
+    ]"#
+);
+
+fn hops_json(explain: &crate::explain::Explain) -> serde_json::Value {
+    serde_json::to_value(&explain.joins[0].entities[0].hops).unwrap()
+}
+
+test_explain!(
+    implicit_entities_are_reported,
+    expected = r#"$join rooted at Customer
+  Product: Customer -> Order -> Product
+    Customer -> Order [weight 6, strong] foreign shop.orders _id -> customerId (ManyToOne)
+      {"$lookup":{"from":"orders","localField":"Customer._id","foreignField":"customerId","as":"Order"}}
+      {"$unwind":{"path":"$Order","preserveNullAndEmptyArrays":false}}
+    Order -> Product [weight 3, strong] foreign shop.products productId -> _id (OneToOne)
+      {"$lookup":{"from":"products","localField":"Order.productId","foreignField":"_id","as":"Product"}}
+      {"$unwind":{"path":"$Product","preserveNullAndEmptyArrays":false}}
+  implicit entities: Order
+pipeline
+  0: {"$project":{"Customer":"$$ROOT","_id":false}}
+  1: {"$lookup":{"from":"orders","localField":"Customer._id","foreignField":"customerId","as":"Order"}}
+  2: {"$unwind":{"path":"$Order","preserveNullAndEmptyArrays":false}}
+  3: {"$lookup":{"from":"products","localField":"Order.productId","foreignField":"_id","as":"Product"}}
+  4: {"$unwind":{"path":"$Product","preserveNullAndEmptyArrays":false}}
+  5: {"$project":{"Order":false}}
+"#,
+    input = r#"[
+        {"$join": {"$inner": {"root": "Customer", "args": ["Product"], "excludeImplicit": true}}}
+    ]"#
+);
@@ -0,0 +1,193 @@
+use crate::{
+    erd::{Consistency, RelationshipType, describe_bounds, describe_keys},
+    erd_graph::{EdgeData, ErdGraph},
+};
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+
+/// GraphJson is a renderer agnostic node/edge list of an ErdGraph, suitable for feeding to
+/// diagramming tools.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphJson {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphNode {
+    pub id: String,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    pub label: String,
+    pub constraint_type: String,
+    pub relationship_type: RelationshipType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consistency: Option<Consistency>,
+    pub weight: usize,
+}
+
+impl GraphEdge {
+    fn is_embedded(&self) -> bool {
+        self.constraint_type == "embedded"
+    }
+
+    // details is the secondary edge label line: cardinality and, if given, consistency.
+    fn details(&self) -> String {
+        match self.consistency {
+            Some(consistency) => format!("{}, {}", self.relationship_type, consistency),
+            None => self.relationship_type.to_string(),
+        }
+    }
+}
+
+/// edge_label is the storage description of an edge: the embedded target path, the foreign
+/// namespace and the keys joined on, or the bucket namespace, keys and array path, prefixed by
+/// the relationship name if it has one.
+pub fn edge_label(edge: &EdgeData) -> String {
+    let label = match edge {
+        EdgeData::Embedded { target_path, .. } => target_path.clone(),
+        EdgeData::Foreign {
+            db,
+            collection,
+            keys,
+            junction,
+            reference_array,
+            ..
+        } => format!(
+            "{}.{} {}",
+            db,
+            collection,
+            describe_keys(keys, junction.as_ref(), *reference_array, "→")
+        ),
+        EdgeData::Bucket {
+            db,
+            collection,
+            local_key,
+            foreign_key,
+            array_path,
+            bounds,
+            ..
+        } => format!(
+            "{}.{} {}→{} {}{}",
+            db,
+            collection,
+            local_key,
+            foreign_key,
+            array_path,
+            describe_bounds(bounds.as_ref())
+        ),
+    };
+    match edge.name() {
+        Some(name) => format!("{}: {}", name, label),
+        None => label,
+    }
+}
+
+/// to_json builds the node/edge list for the graph. Nodes and edges are sorted by name so the
+/// output is stable across runs.
+pub fn to_json(graph: &ErdGraph) -> GraphJson {
+    let mut nodes: Vec<_> = graph
+        .graph
+        .node_weights()
+        .map(|name| GraphNode {
+            id: name.clone(),
+            label: name.clone(),
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut edges: Vec<_> = graph
+        .graph
+        .edge_references()
+        .filter_map(|edge| {
+            let data = graph.edge_data.get(&edge.id())?;
+            Some(GraphEdge {
+                source: graph.get_entity_name(edge.source())?.clone(),
+                target: graph.get_entity_name(edge.target())?.clone(),
+                label: edge_label(data),
+                constraint_type: match data {
+                    EdgeData::Embedded { .. } => "embedded".to_string(),
+                    EdgeData::Foreign { .. } => "foreign".to_string(),
+                    EdgeData::Bucket { .. } => "bucket".to_string(),
+                },
+                relationship_type: data.relationship_type(),
+                consistency: data.consistency(),
+                weight: *edge.weight(),
+            })
+        })
+        .collect();
+    edges.sort_by(|a, b| (&a.source, &a.target).cmp(&(&b.source, &b.target)));
+    GraphJson { nodes, edges }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// to_dot renders the graph in graphviz DOT. Embedded edges are solid and foreign and bucket
+/// edges are dashed.
+pub fn to_dot(graph: &ErdGraph) -> String {
+    let json = to_json(graph);
+    let mut out = String::from("digraph {\n    node [shape = box]\n");
+    for node in json.nodes.iter() {
+        out.push_str(&format!(
+            "    \"{}\" [ label = \"{}\" ]\n",
+            escape(&node.id),
+            escape(&node.label)
+        ));
+    }
+    for edge in json.edges.iter() {
+        out.push_str(&format!(
+            "    \"{}\" -> \"{}\" [ label = \"{}\\n{}\", style = {} ]\n",
+            escape(&edge.source),
+            escape(&edge.target),
+            escape(&edge.label),
+            escape(&edge.details()),
+            if edge.is_embedded() {
+                "solid"
+            } else {
+                "dashed"
+            }
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn mermaid_id(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// to_mermaid renders the graph as a mermaid flowchart. Embedded edges are solid and foreign and
+/// bucket edges are dotted.
+pub fn to_mermaid(graph: &ErdGraph) -> String {
+    let json = to_json(graph);
+    let mut out = String::from("flowchart LR\n");
+    for node in json.nodes.iter() {
+        out.push_str(&format!(
+            "    {}[\"{}\"]\n",
+            mermaid_id(&node.id),
+            node.label.replace('"', "#quot;")
+        ));
+    }
+    for edge in json.edges.iter() {
+        out.push_str(&format!(
+            "    {} {}|\"{}<br/>{}\"| {}\n",
+            mermaid_id(&edge.source),
+            if edge.is_embedded() { "-->" } else { "-.->" },
+            edge.label.replace('"', "#quot;"),
+            edge.details(),
+            mermaid_id(&edge.target)
+        ));
+    }
+    out
+}
@@ -0,0 +1,135 @@
+macro_rules! test_graph_export {
+    ($func_name:ident, expected = $expected:expr, export = $export:path) => {
+        #[test]
+        fn $func_name() {
+            use crate::{erd::Relationships, erd_graph::ErdGraph};
+
+            let erd: Relationships = serde_json::from_str(crate::graph_export_tests::ERD).unwrap();
+            let graph = ErdGraph::new(&erd).unwrap();
+            assert_eq!($expected, $export(&graph));
+        }
+    };
+}
+
+const ERD: &str = r#"{
+    "Customer": {
+        "Address": {
+            "relationshipType": "one-to-one",
+            "constraint": {
+                "constraintType": "embedded",
+                "targetPath": "address",
+                "projection": []
+            }
+        },
+        "Order": {
+            "relationshipType": "many-to-one",
+            "consistency": "eventual",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "orders",
+                "localKey": "_id",
+                "foreignKey": "customerId",
+                "projection": []
+            }
+        }
+    },
+    "Sensor": {
+        "Reading": {
+            "name": "readings",
+            "relationshipType": "many-to-one",
+            "consistency": "weak",
+            "constraint": {
+                "constraintType": "bucket",
+                "db": "iot",
+                "collection": "reading_buckets",
+                "localKey": "_id",
+                "foreignKey": "sensorId",
+                "targetPath": "readings",
+                "bucketBounds": {"min": "start", "max": "end"},
+                "projection": []
+            }
+        }
+    },
+    "Address": {},
+    "Order": {},
+    "Reading": {}
+}"#;
+
+test_graph_export!(
+    dot,
+    expected = r#"digraph {
+    node [shape = box]
+    "Address" [ label = "Address" ]
+    "Customer" [ label = "Customer" ]
+    "Order" [ label = "Order" ]
+    "Reading" [ label = "Reading" ]
+    "Sensor" [ label = "Sensor" ]
+    "Customer" -> "Address" [ label = "address\none-to-one", style = solid ]
+    "Customer" -> "Order" [ label = "shop.orders _id→customerId\nmany-to-one, eventual", style = dashed ]
+    "Sensor" -> "Reading" [ label = "readings: iot.reading_buckets _id→sensorId readings [start, end]\nmany-to-one, weak", style = dashed ]
+}
+"#,
+    export = crate::graph_export::to_dot
+);
+
+test_graph_export!(
+    mermaid,
+    expected = r#"flowchart LR
+    Address["Address"]
+    Customer["Customer"]
+    Order["Order"]
+    Reading["Reading"]
+    Sensor["Sensor"]
+    Customer -->|"address<br/>one-to-one"| Address
+    Customer -.->|"shop.orders _id→customerId<br/>many-to-one, eventual"| Order
+    Sensor -.->|"readings: iot.reading_buckets _id→sensorId readings [start, end]<br/>many-to-one, weak"| Reading
+"#,
+    export = crate::graph_export::to_mermaid
+);
+
+test_graph_export!(
+    json,
+    expected = serde_json::json!({
+        "nodes": [
+            {"id": "Address", "label": "Address"},
+            {"id": "Customer", "label": "Customer"},
+            {"id": "Order", "label": "Order"},
+            {"id": "Reading", "label": "Reading"},
+            {"id": "Sensor", "label": "Sensor"}
+        ],
+        "edges": [
+            {
+                "source": "Customer",
+                "target": "Address",
+                "label": "address",
+                "constraintType": "embedded",
+                "relationshipType": "one-to-one",
+                "weight": 1
+            },
+            {
+                "source": "Customer",
+                "target": "Order",
+                "label": "shop.orders _id→customerId",
+                "constraintType": "foreign",
+                "relationshipType": "many-to-one",
+                "consistency": "eventual",
+                "weight": 10
+            },
+            {
+                "source": "Sensor",
+                "target": "Reading",
+                "label": "readings: iot.reading_buckets _id→sensorId readings [start, end]",
+                "constraintType": "bucket",
+                "relationshipType": "many-to-one",
+                "consistency": "weak",
+                "weight": 11
+            }
+        ]
+    }),
+    export = crate::graph_export_tests::to_json_value
+);
+
+fn to_json_value(graph: &crate::erd_graph::ErdGraph) -> serde_json::Value {
+    serde_json::to_value(crate::graph_export::to_json(graph)).unwrap()
+}
@@ -1,12 +1,29 @@
-use crate::{erd::{Relationships}, erd_graph::{GetErdData, EdgeData, ErdGraph}};
+use crate::{
+    erd::{
+        Consistency, Discriminator, Junction, KeyPair, ReferenceArray, RelationshipType,
+        Relationships, Source,
+    },
+    erd_graph::{EdgeData, ErdGraph, GetErdData},
+    erd_validation,
+    explain::{EntityPath, Hop, JoinExplain, flatten_stages},
+    match_movement_rewrite::let_variable,
+};
 use ast::{
     definitions::{
-        visitor::Visitor, Derived, EqualityLookup, Expression, Join, JoinExpression, Lookup, LookupFrom, MatchExpr, MatchExpression, MatchStage, Pipeline, ProjectItem, ProjectStage, Ref, Stage, Unwind, UnwindExpr
+        Alias, Collection, ConciseSubqueryLookup, Cond, Derived, EqualityLookup, Expression,
+        Filter, GraphLookup, Join, JoinExpression, LiteralValue, Lookup, LookupFrom, Map,
+        MatchExpr, MatchExpression, MatchStage, Namespace, Pipeline, ProjectItem, ProjectStage,
+        Recursive, Reduce, Ref, Stage, SubqueryLookup, TaggedOperator, Unset, UntaggedOperator,
+        UntaggedOperatorName, Unwind, UnwindExpr, visitor::Visitor,
     },
-    map,
+    map, set,
+};
+use linked_hash_map::LinkedHashMap;
+use petgraph::{
+    graph::{EdgeIndex, NodeIndex},
+    visit::EdgeRef,
 };
-use petgraph::graph::NodeIndex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -53,25 +70,114 @@ pub enum Error {
     DerivedEntityAlreadyInScope(String),
     #[error("No path to entity: {0}")]
     NoPathToEntity(String),
+    #[error("No path to entity {0} with {1} consistency")]
+    NoConsistentPathToEntity(String, Consistency),
+    #[error("Entity or alias {0} is already in scope")]
+    AliasAlreadyInScope(String),
+    #[error("Alias {0} of entity {1} needs via, since {1} is already in scope")]
+    AliasRequiresVia(String, String),
+    #[error("No relationship named {0} to entity {1} from an entity in scope")]
+    NamedRelationshipNotInScope(String, String),
+    #[error("Foreign relationship to {0} has no localKey and foreignKey or keys")]
+    MissingForeignKeys(String),
+    #[error("Foreign relationship to {0} uses {1}, which needs a single localKey and foreignKey")]
+    UnsupportedCompositeKeys(String, &'static str),
+    #[error(
+        "Entity {0} is stored in db {1}, but cross-db lookups from db {2} are not allowed: query {1} separately and combine the results with $unionWith, or allow cross-db lookups"
+    )]
+    CrossDbLookupNotAllowed(String, String, String),
+    #[error("Entity {0} has no relationship to itself to join recursively")]
+    NoRecursiveRelationship(String),
+    #[error("Entity {0} has more than one relationship to itself, a recursive join needs via")]
+    RecursiveRequiresVia(String),
+    #[error("Recursive join of {0} needs as, since its relationship has no name")]
+    RecursiveRequiresAs(String),
+    #[error("Recursive join of {0} along an embedded relationship needs maxDepth")]
+    RecursiveEmbeddedRequiresMaxDepth(String),
+    #[error("Recursive join of {0} does not support {1} relationships")]
+    UnsupportedRecursiveRelationship(String, &'static str),
+    #[error("$graphLookup cannot join entity {0} from db {1}, which is not the db of the root")]
+    GraphLookupAcrossDbs(String, String),
+    #[error("Left join of entity {0} from a bucket cannot filter on its discriminator")]
+    DiscriminatorInLeftBucketJoin(String),
+    #[error("Left join of entity {0} has no $lookup into {1} to filter on its discriminator")]
+    NoLookupToDiscriminate(String, String),
+    #[error("Invalid ERD: {0}")]
+    InvalidErd(#[from] erd_validation::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub struct JoinRewrite {
+/// JoinOptions configures how `$join` stages are rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinOptions {
+    /// cross_db_lookups allows `$lookup`s from a db other than the root entity's. Deployments
+    /// that do not support them can disable this to get an error instead of a failing pipeline.
+    pub cross_db_lookups: bool,
+}
+
+impl Default for JoinOptions {
+    fn default() -> Self {
+        JoinOptions {
+            cross_db_lookups: true,
+        }
+    }
+}
+
+pub struct JoinRewrite<'a, T: GetErdData> {
+    erd: &'a T,
+    options: JoinOptions,
     error: Option<Error>,
+    explain: Vec<JoinExplain>,
+}
+
+pub const DEFAULT_ERD_PATH: &str = "assets/rel.json";
+
+pub fn load_erd(path: &str) -> Result<Relationships> {
+    let erd_json =
+        std::fs::read_to_string(path).map_err(|_| Error::CouldNotFindErd(path.to_string()))?;
+    serde_json::from_str(&erd_json).map_err(Error::CouldNotParseErd)
 }
 
 pub fn rewrite_pipeline(pipeline: Pipeline) -> Result<Pipeline> {
-    let mut visitor = JoinRewrite { error: None };
+    let erd = load_erd(DEFAULT_ERD_PATH)?;
+    rewrite_pipeline_with_erd(pipeline, &erd)
+}
+
+pub fn rewrite_pipeline_with_erd<T: GetErdData>(pipeline: Pipeline, erd: &T) -> Result<Pipeline> {
+    rewrite_pipeline_with_options(pipeline, erd, JoinOptions::default())
+}
+
+pub fn rewrite_pipeline_with_options<T: GetErdData>(
+    pipeline: Pipeline,
+    erd: &T,
+    options: JoinOptions,
+) -> Result<Pipeline> {
+    rewrite_pipeline_with_explain(pipeline, erd, options).map(|(pipeline, _)| pipeline)
+}
+
+/// rewrite_pipeline_with_explain rewrites every `$join` in the pipeline and also returns the
+/// ERD paths that were chosen for each one.
+pub fn rewrite_pipeline_with_explain<T: GetErdData>(
+    pipeline: Pipeline,
+    erd: &T,
+    options: JoinOptions,
+) -> Result<(Pipeline, Vec<JoinExplain>)> {
+    let mut visitor = JoinRewrite {
+        erd,
+        options,
+        error: None,
+        explain: Vec::new(),
+    };
     let pipeline = visitor.visit_pipeline(pipeline);
     if let Some(e) = visitor.error {
         Err(e)
     } else {
-        Ok(pipeline)
+        Ok((pipeline, visitor.explain))
     }
 }
 
-impl Visitor for JoinRewrite {
+impl<T: GetErdData> Visitor for JoinRewrite<'_, T> {
     // visit_stage is here to handle Join stages and replace them with SubPipelines
     fn visit_stage(&mut self, stage: Stage) -> Stage {
         if self.error.is_some() {
@@ -94,14 +200,14 @@ impl Visitor for JoinRewrite {
         }
         match stage {
             Stage::Join(j) => {
-                let erd_json = handle_error!(
-                    std::fs::read_to_string("assets/rel.json")
-                        .map_err(|_| Error::CouldNotFindErd("assets/rel.json".to_string()))
-                );
-                let erd: Relationships =
-                    handle_error!(serde_json::from_str(&erd_json).map_err(Error::CouldNotParseErd));
-                let mut generator = JoinGenerator::new(erd);
-                handle_error!(generator.generate_join(*j));
+                let mut generator = handle_error!(JoinGenerator::new(self.erd, self.options));
+                let root = handle_error!(generator.generate_join(*j));
+                self.explain.push(JoinExplain {
+                    root,
+                    read_concern: generator.read_concern,
+                    entities: generator.explain,
+                    implicit_entities: generator.implicit,
+                });
                 Stage::SubPipeline(generator.pipeline)
             }
             _ => stage,
@@ -125,288 +231,846 @@ impl Visitor for JoinRewrite {
 }
 
 struct JoinGenerator {
-    entities: Relationships,
     erd_graph: ErdGraph,
     nodes_in_scope: HashSet<NodeIndex>,
     pipeline: Pipeline,
+    explain: Vec<EntityPath>,
+    // implicit holds the entities that were brought into scope because they are on the path to
+    // a requested entity, in the order they were joined.
+    implicit: Vec<String>,
+    // aliases holds the aliases in scope. Aliased entities are not in nodes_in_scope, paths to
+    // other entities never go through them.
+    aliases: HashSet<String>,
+    // read_concern is the consistency every edge taken must deliver, edges that deliver less
+    // are removed from erd_graph.
+    read_concern: Option<Consistency>,
+    // sources holds the source of every entity that has one.
+    sources: HashMap<String, Source>,
+    // discriminators holds the discriminator of every entity that has one.
+    discriminators: HashMap<String, Discriminator>,
+    // dbs holds the db of every entity stored in a single db: the db of its source, or, for
+    // ERD formats without sources, of the foreign relationships that target it.
+    dbs: HashMap<String, String>,
+    // root_db is the db of the root entity, lookups into other dbs use namespaces.
+    root_db: Option<String>,
+    options: JoinOptions,
 }
 
 impl JoinGenerator {
-    fn new(entities: Relationships) -> Self {
-        let erd_graph = ErdGraph::new(&entities);
-        println!("{}", erd_graph);
-        JoinGenerator {
-            entities,
-            erd_graph,
+    fn new<T: GetErdData>(entities: &T, options: JoinOptions) -> Result<Self> {
+        Ok(JoinGenerator {
+            erd_graph: ErdGraph::new(entities)?,
             nodes_in_scope: HashSet::new(),
             pipeline: Pipeline::default(),
-        }
+            explain: Vec::new(),
+            implicit: Vec::new(),
+            aliases: HashSet::new(),
+            read_concern: None,
+            sources: entities
+                .iter()
+                .filter_map(|entity| Some((entity.clone(), entities.get_source(entity)?.clone())))
+                .collect(),
+            discriminators: entities
+                .iter()
+                .filter_map(|entity| {
+                    Some((entity.clone(), entities.get_discriminator(entity)?.clone()))
+                })
+                .collect(),
+            dbs: entities
+                .iter()
+                .filter_map(|entity| Some((entity.clone(), entity_db(entities, entity)?)))
+                .collect(),
+            root_db: None,
+            options,
+        })
     }
 
-    fn generate_for_derived(
-        &mut self,
-        is_left: bool,
-        root: NodeIndex,
-        derived: &Derived,
-    ) -> Result<()> {
+    fn generate_for_derived(&mut self, is_left: bool, derived: &Derived) -> Result<()> {
         let entity = &derived.entity;
-        let entity_index = self.erd_graph
+        let entity_index = self
+            .erd_graph
             .get_index(entity)
             .ok_or_else(|| Error::EntityMissingFromErd(entity.to_string()))?;
         if self.nodes_in_scope.contains(&entity_index) {
             // already in scope, this will be an error, derived entities must be unique in scope.
             return Err(Error::DerivedEntityAlreadyInScope(entity.to_string()));
         }
-        let Some(path) = self.erd_graph.path_to(root, entity_index) else {
+        let Some(path) = self.erd_graph.path_from(&self.nodes_in_scope, entity_index) else {
             return Err(Error::NoPathToEntity(entity.to_string()));
         };
-        let mut current_index = root;
-        for target_index in path.into_iter() {
-            if self.nodes_in_scope.contains(&target_index) {
-                current_index = target_index;
+        let edges = self.erd_graph.path_edges(&path).unwrap_or_default();
+        self.generate_for_path(is_left, path[0], edges, Some(derived), None)
+    }
+
+    // generate_for_alias joins the entity under its alias, or under its own name if it has none.
+    // With via, the entity is joined along the named relationship from an entity in scope, which
+    // may be the entity itself, otherwise along the cheapest path from the entities in scope.
+    fn generate_for_alias(&mut self, is_left: bool, alias: &Alias) -> Result<()> {
+        let entity_index = self
+            .erd_graph
+            .get_index(&alias.entity)
+            .ok_or_else(|| Error::EntityMissingFromErd(alias.entity.clone()))?;
+        let output = alias.as_var.as_ref().unwrap_or(&alias.entity);
+        let shadows_entity = self
+            .erd_graph
+            .get_index(output)
+            .is_some_and(|index| self.nodes_in_scope.contains(&index));
+        if shadows_entity || self.aliases.contains(output) {
+            return Err(Error::AliasAlreadyInScope(output.clone()));
+        }
+        let (start, edges) = match &alias.via {
+            Some(via) => {
+                // edge indices follow the order of the ERD, so the first one is deterministic
+                let Some(edge) = self
+                    .erd_graph
+                    .named_edges_to(entity_index, via)
+                    .filter(|edge| {
+                        self.erd_graph
+                            .graph
+                            .edge_endpoints(*edge)
+                            .is_some_and(|(source, _)| self.nodes_in_scope.contains(&source))
+                    })
+                    .min()
+                else {
+                    return Err(Error::NamedRelationshipNotInScope(
+                        via.clone(),
+                        alias.entity.clone(),
+                    ));
+                };
+                let (source, _) = self.erd_graph.graph.edge_endpoints(edge).unwrap();
+                (source, vec![edge])
+            }
+            None => {
+                if self.nodes_in_scope.contains(&entity_index) {
+                    return Err(Error::AliasRequiresVia(
+                        output.clone(),
+                        alias.entity.clone(),
+                    ));
+                }
+                let path = self
+                    .erd_graph
+                    .path_from(&self.nodes_in_scope, entity_index)
+                    .ok_or_else(|| Error::NoPathToEntity(alias.entity.clone()))?;
+                (
+                    path[0],
+                    self.erd_graph.path_edges(&path).unwrap_or_default(),
+                )
+            }
+        };
+        self.generate_for_path(is_left, start, edges, None, alias.as_var.as_deref())
+    }
+
+    // generate_for_recursive joins the records reachable from the entity, which is in scope,
+    // along its relationship to itself. Foreign relationships become a $graphLookup. Embedded
+    // relationships nest the records in the entity's own document, so the nested levels are
+    // collected up to maxDepth instead. Either way entities without related records are kept.
+    fn generate_for_recursive(&mut self, recursive: &Recursive) -> Result<()> {
+        let entity = &recursive.entity;
+        let entity_index = self
+            .erd_graph
+            .get_index(entity)
+            .ok_or_else(|| Error::EntityMissingFromErd(entity.clone()))?;
+        let mut edges: Vec<EdgeIndex> = self
+            .erd_graph
+            .graph
+            .edges_connecting(entity_index, entity_index)
+            .map(|edge| edge.id())
+            .filter(|edge| match &recursive.via {
+                Some(via) => self.erd_graph.edge_data[edge].name() == Some(via.as_str()),
+                None => true,
+            })
+            .collect();
+        edges.sort();
+        let edge = match (edges.first(), &recursive.via) {
+            (None, Some(via)) => {
+                return Err(Error::NamedRelationshipNotInScope(
+                    via.clone(),
+                    entity.clone(),
+                ));
+            }
+            (None, None) => return Err(Error::NoRecursiveRelationship(entity.clone())),
+            (Some(_), None) if edges.len() > 1 => {
+                return Err(Error::RecursiveRequiresVia(entity.clone()));
+            }
+            (Some(&edge), _) => edge,
+        };
+        let edge_data = self.erd_graph.edge_data[&edge].clone();
+        let output = match (recursive.as_var.as_deref(), edge_data.name()) {
+            (Some(output), _) | (None, Some(output)) => output.to_string(),
+            (None, None) => return Err(Error::RecursiveRequiresAs(entity.clone())),
+        };
+        let shadows_entity = self
+            .erd_graph
+            .get_index(&output)
+            .is_some_and(|index| self.nodes_in_scope.contains(&index));
+        if shadows_entity || self.aliases.contains(&output) {
+            return Err(Error::AliasAlreadyInScope(output));
+        }
+        self.aliases.insert(output.clone());
+        let stage = match &edge_data {
+            EdgeData::Foreign {
+                junction: Some(_), ..
+            } => {
+                return Err(Error::UnsupportedRecursiveRelationship(
+                    entity.clone(),
+                    "junction",
+                ));
+            }
+            EdgeData::Bucket { .. } => {
+                return Err(Error::UnsupportedRecursiveRelationship(
+                    entity.clone(),
+                    "bucket",
+                ));
+            }
+            // $graphLookup follows arrays of references in either connect field by itself
+            EdgeData::Foreign {
+                db,
+                collection,
+                keys,
+                junction: None,
+                ..
+            } => {
+                let [pair] = keys.as_slice() else {
+                    return Err(Error::UnsupportedCompositeKeys(
+                        entity.clone(),
+                        "$recursive",
+                    ));
+                };
+                if self.root_db.as_ref().is_some_and(|root_db| root_db != db) {
+                    return Err(Error::GraphLookupAcrossDbs(entity.clone(), db.clone()));
+                }
+                Stage::GraphLookup(GraphLookup {
+                    from: collection.clone(),
+                    start_with: Box::new(Expression::Ref(Ref::FieldRef(format!(
+                        "{}.{}",
+                        entity, pair.local_key
+                    )))),
+                    connect_from_field: pair.local_key.clone(),
+                    connect_to_field: pair.foreign_key.clone(),
+                    as_var: output.clone(),
+                    max_depth: recursive.max_depth,
+                    depth_field: recursive.depth_field.clone(),
+                    restrict_search_with_match: None,
+                })
+            }
+            EdgeData::Embedded {
+                target_path,
+                relationship_type,
+                ..
+            } => {
+                let max_depth = recursive
+                    .max_depth
+                    .ok_or_else(|| Error::RecursiveEmbeddedRequiresMaxDepth(entity.clone()))?;
+                let one_to_one = *relationship_type == RelationshipType::OneToOne;
+                Stage::AddFields(map! {
+                    output.clone() => nested_levels(
+                        &format!("{}.{}", entity, target_path),
+                        target_path,
+                        one_to_one,
+                        max_depth,
+                        recursive.depth_field.as_deref(),
+                    ),
+                })
+            }
+        };
+        self.explain.push(EntityPath {
+            entity: entity.clone(),
+            alias: Some(output),
+            path: vec![entity.clone(), entity.clone()],
+            hops: vec![Hop {
+                source: entity.clone(),
+                target: entity.clone(),
+                weight: self
+                    .erd_graph
+                    .graph
+                    .edge_weight(edge)
+                    .copied()
+                    .unwrap_or_default(),
+                consistency: edge_data.delivered_consistency(),
+                edge: edge_data.into(),
+                already_in_scope: false,
+                stages: vec![stage.clone()],
+            }],
+        });
+        self.pipeline.push(stage);
+        Ok(())
+    }
+
+    // generate_for_entities brings every entity into scope along one connecting tree, so that
+    // entities share their intermediate hops with each other and with the entities already in
+    // scope. The entities are joined cheapest first: entities the condition filters on, then by
+    // the weight of the edges that are not in scope yet, so that embedded unwinds come before
+    // foreign lookups.
+    fn generate_for_entities(
+        &mut self,
+        is_left: bool,
+        entities: &[&str],
+        condition: Option<&Expression>,
+    ) -> Result<()> {
+        let mut terminals = Vec::new();
+        for entity in entities {
+            let entity_index = self
+                .erd_graph
+                .get_index(entity)
+                .ok_or_else(|| Error::EntityMissingFromErd(entity.to_string()))?;
+            // Ideally a join should have unique entities, but this is a safeguard.
+            if !self.nodes_in_scope.contains(&entity_index) && !terminals.contains(&entity_index) {
+                terminals.push(entity_index);
+            }
+        }
+        let parents = self
+            .erd_graph
+            .steiner_tree(&self.nodes_in_scope, &terminals);
+        if let Some(unreachable) = terminals
+            .iter()
+            .find(|terminal| !parents.contains_key(terminal))
+        {
+            return Err(Error::NoPathToEntity(
+                self.erd_graph
+                    .get_entity_name(*unreachable)
+                    .unwrap()
+                    .to_string(),
+            ));
+        }
+        loop {
+            // entities on the path to an earlier entity are already in scope
+            terminals.retain(|terminal| !self.nodes_in_scope.contains(terminal));
+            let Some(position) = (0..terminals.len())
+                .min_by_key(|&position| self.join_cost(&parents, terminals[position], condition))
+            else {
+                break;
+            };
+            let path = self.tree_path(&parents, terminals.remove(position));
+            let edges = self.erd_graph.path_edges(&path).unwrap_or_default();
+            self.generate_for_path(is_left, path[0], edges, None, None)?;
+        }
+        Ok(())
+    }
+
+    // tree_path returns the path in the tree from the closest entity in scope to entity_index.
+    fn tree_path(
+        &self,
+        parents: &HashMap<NodeIndex, NodeIndex>,
+        entity_index: NodeIndex,
+    ) -> Vec<NodeIndex> {
+        let mut path = vec![entity_index];
+        let mut current = entity_index;
+        while !self.nodes_in_scope.contains(&current) {
+            current = parents[&current];
+            path.push(current);
+        }
+        path.reverse();
+        path
+    }
+
+    // join_cost orders the entities of a join: entities that the condition filters on come
+    // first, since they may reduce the number of documents, then the cheapest entities to bring
+    // into scope.
+    fn join_cost(
+        &self,
+        parents: &HashMap<NodeIndex, NodeIndex>,
+        entity_index: NodeIndex,
+        condition: Option<&Expression>,
+    ) -> (bool, usize) {
+        let entity = self.erd_graph.get_entity_name(entity_index).unwrap();
+        let filtered = condition
+            .is_some_and(|condition| condition.uses().prefix_overlap(&set! {entity.clone()}));
+        let cost = self
+            .tree_path(parents, entity_index)
+            .windows(2)
+            .map(|hop| {
+                self.erd_graph
+                    .get_edge_weight(hop[0], hop[1])
+                    .unwrap_or_default()
+            })
+            .sum();
+        (!filtered, cost)
+    }
+
+    // generate_for_path brings every entity on the ERD path that follows the edges from start
+    // into scope, start is already in scope. If derived is set, its pipeline is prefixed before
+    // the stages for the final hop. If alias is set, the final hop is joined under the alias
+    // instead of the entity name.
+    fn generate_for_path(
+        &mut self,
+        is_left: bool,
+        start: NodeIndex,
+        edges: Vec<EdgeIndex>,
+        derived: Option<&Derived>,
+        alias: Option<&str>,
+    ) -> Result<()> {
+        let hops: Vec<(NodeIndex, NodeIndex, EdgeIndex)> = edges
+            .into_iter()
+            .filter_map(|edge| {
+                let (source, target) = self.erd_graph.graph.edge_endpoints(edge)?;
+                Some((source, target, edge))
+            })
+            .collect();
+        let entity_index = hops.last().map_or(start, |&(_, target, _)| target);
+        let entity = self
+            .erd_graph
+            .get_entity_name(entity_index)
+            .unwrap()
+            .to_string();
+        let hop_count = hops.len();
+        let mut entity_path = EntityPath {
+            entity,
+            alias: alias.map(str::to_string),
+            path: std::iter::once(start)
+                .chain(hops.iter().map(|&(_, target, _)| target))
+                .filter_map(|index| self.erd_graph.get_entity_name(index).cloned())
+                .collect(),
+            hops: Vec::new(),
+        };
+        for (hop_index, (current_index, target_index, edge)) in hops.into_iter().enumerate() {
+            let source_entity = self
+                .erd_graph
+                .get_entity_name(current_index)
+                .unwrap()
+                .clone();
+            let target_entity = self
+                .erd_graph
+                .get_entity_name(target_index)
+                .unwrap()
+                .clone();
+            let alias = alias.filter(|_| hop_index + 1 == hop_count);
+            let Some(edge_data) = self.erd_graph.edge_data.get(&edge).cloned() else {
+                // This should actually be impossible since every edge has data.
+                return Err(Error::RelationshipMissingBetween(
+                    source_entity,
+                    target_entity,
+                ));
+            };
+            let weight = self
+                .erd_graph
+                .graph
+                .edge_weight(edge)
+                .copied()
+                .unwrap_or_default();
+            if alias.is_none() && self.nodes_in_scope.contains(&target_index) {
+                entity_path.hops.push(Hop {
+                    source: source_entity,
+                    target: target_entity,
+                    weight,
+                    consistency: edge_data.delivered_consistency(),
+                    edge: edge_data.into(),
+                    already_in_scope: true,
+                    stages: Vec::new(),
+                });
                 continue;
             }
-            self.nodes_in_scope.insert(target_index);
-            let edge_data = self.erd_graph.get_edge_data(current_index, target_index);
-            match edge_data {
-                Some(EdgeData::Embedded {
+            // the name the target is joined under
+            let output = match alias {
+                Some(alias) => {
+                    self.aliases.insert(alias.to_string());
+                    alias.to_string()
+                }
+                None => {
+                    if self.aliases.contains(&target_entity) {
+                        return Err(Error::AliasAlreadyInScope(target_entity));
+                    }
+                    self.nodes_in_scope.insert(target_index);
+                    if target_index != entity_index {
+                        self.implicit.push(target_entity.clone());
+                    }
+                    target_entity.clone()
+                }
+            };
+            let mut stages = Vec::new();
+            if let Some(derived) = derived.filter(|_| target_index == entity_index) {
+                // If the entity is the current entity, we prefix in the pipeline
+                stages.push(Stage::SubPipeline(derived.pipeline.clone()));
+            }
+            match &edge_data {
+                EdgeData::Embedded {
                     source_entity,
                     target_path,
-                    relationship_type: _,
-                }) => {
-                    if target_index == entity_index {
-                        let pipeline = derived.pipeline.pipeline.clone();
-                        // If the entity is the current entity, we prefix in the pipeline
-                        self.pipeline.push(Stage::SubPipeline(Pipeline {pipeline}));
-                    }
-                    self.pipeline.push(self.generate_for_embedded(
-                            is_left,
-                     source_entity,
-                    self.erd_graph.get_entity_name(target_index).unwrap(),
-                        &target_path,
+                    ..
+                } => {
+                    stages.push(self.generate_for_embedded(
+                        is_left,
+                        source_entity,
+                        &output,
+                        target_path,
                     )?);
                 }
-                Some(EdgeData::Foreign {
-                    db: _,
+                EdgeData::Foreign {
+                    db,
+                    collection,
+                    keys,
+                    junction: Some(junction),
+                    ..
+                } => {
+                    stages.push(self.generate_for_junction(
+                        is_left,
+                        &source_entity,
+                        &output,
+                        self.lookup_from(&target_entity, db, collection)?,
+                        keys,
+                        junction,
+                    )?);
+                }
+                EdgeData::Foreign {
+                    db,
+                    collection,
+                    keys,
+                    junction: None,
+                    reference_array: Some(reference_array),
+                    index_field,
+                    ..
+                } => {
+                    stages.push(self.generate_for_reference_array(
+                        is_left,
+                        &source_entity,
+                        &output,
+                        self.lookup_from(&target_entity, db, collection)?,
+                        keys,
+                        *reference_array,
+                        index_field.as_deref(),
+                    )?);
+                }
+                EdgeData::Foreign {
+                    db,
+                    collection,
+                    keys,
+                    junction: None,
+                    reference_array: None,
+                    ..
+                } => {
+                    stages.push(self.generate_for_foreign(
+                        is_left,
+                        &source_entity,
+                        &output,
+                        self.lookup_from(&target_entity, db, collection)?,
+                        keys,
+                    )?);
+                }
+                EdgeData::Bucket {
+                    db,
                     collection,
-                    foreign_key,
                     local_key,
-                    relationship_type: _,
-                }) => {
-                    if target_index == entity_index {
-                        let pipeline = derived.pipeline.pipeline.clone();
-                        // If the entity is the current entity, we prefix in the pipeline
-                        self.pipeline.push(Stage::SubPipeline(Pipeline {pipeline}));
-                    }
-                    self.pipeline.push(self.generate_for_foreign(
-                            is_left,
-                    self.erd_graph.get_entity_name(current_index).unwrap(),
-                    self.erd_graph.get_entity_name(target_index).unwrap(),
-                        &collection,
-                        &local_key,
-                        &foreign_key,
+                    foreign_key,
+                    array_path,
+                    ..
+                } => {
+                    stages.push(self.generate_for_bucket(
+                        is_left,
+                        &source_entity,
+                        &output,
+                        self.lookup_from(&target_entity, db, collection)?,
+                        &KeyPair {
+                            local_key: local_key.clone(),
+                            foreign_key: foreign_key.clone(),
+                        },
+                        array_path,
                     )?);
                 }
-                // This should actually be impossible since we shouldn't be able to
-                // find a path to this entity.
-                None => 
-                    Err(Error::RelationshipMissingBetween(
-                        self.erd_graph.get_entity_name(current_index).unwrap().to_string(),
-                        self.erd_graph.get_entity_name(target_index).unwrap().to_string(),
-                    ))?,
             }
-            current_index = target_index;
+            if let Some(discriminator) = self.discriminators.get(&target_entity) {
+                let stage = stages.pop().unwrap();
+                stages.push(self.discriminate(
+                    is_left,
+                    &target_entity,
+                    &output,
+                    &edge_data,
+                    discriminator,
+                    stage,
+                )?);
+            }
+            entity_path.hops.push(Hop {
+                source: source_entity,
+                target: target_entity,
+                weight,
+                consistency: edge_data.delivered_consistency(),
+                edge: edge_data.into(),
+                already_in_scope: false,
+                stages: stages.iter().flat_map(flatten_stages).collect(),
+            });
+            self.pipeline.pipeline.extend(stages);
         }
+        self.explain.push(entity_path);
         Ok(())
     }
 
-    fn generate_for_entity(
-        &mut self,
-        is_left: bool,
-        root: NodeIndex,
-        entity: &str,
-    ) -> Result<()> {
-          let entity_index = self.erd_graph
-              .get_index(entity)
-              .ok_or_else(|| Error::EntityMissingFromErd(entity.to_string()))?;
-          if self.nodes_in_scope.contains(&entity_index) {
-              // already in scope, skip.
-              // Ideally a join should have unique entities, but this is a safeguard.
-              return Ok(());
-          }
-          let Some(path) = self.erd_graph.path_to(root, entity_index) else {
-              return Err(Error::NoPathToEntity(entity.to_string()));
-          };
-          let mut current_index = root;
-          for target_index in path.into_iter() {
-              if self.nodes_in_scope.contains(&target_index) {
-                  current_index = target_index;
-                  continue;
-              }
-              self.nodes_in_scope.insert(target_index);
-              let edge_data = self.erd_graph.get_edge_data(current_index, target_index);
-              match edge_data {
-                  Some(EdgeData::Embedded {
-                      source_entity,
-                      target_path,
-                      relationship_type: _,
-                  }) => {
-                      self.pipeline.push(self.generate_for_embedded(
-                              is_left,
-                       source_entity,
-                      self.erd_graph.get_entity_name(target_index).unwrap(),
-                          &target_path,
-                      )?);
-                  }
-                  Some(EdgeData::Foreign {
-                      db: _,
-                      collection,
-                      foreign_key,
-                      local_key,
-                      relationship_type: _,
-                  }) => {
-                      self.pipeline.push(self.generate_for_foreign(
-                              is_left,
-                      self.erd_graph.get_entity_name(current_index).unwrap(),
-                      self.erd_graph.get_entity_name(target_index).unwrap(),
-                          &collection,
-                          &local_key,
-                          &foreign_key,
-                      )?);
-                  }
-                  // This should actually be impossible since we shouldn't be able to
-                  // find a path to this entity.
-                  None => 
-                      Err(Error::RelationshipMissingBetween(
-                          self.erd_graph.get_entity_name(current_index).unwrap().to_string(),
-                          self.erd_graph.get_entity_name(target_index).unwrap().to_string(),
-                      ))?,
-              }
-              current_index = target_index;
-          }
-          Ok(())
-    }
-
     fn generate_join_aux(
         &mut self,
         is_left: bool,
-        root_entity: &str,
         args: &[Join],
         condition: Option<Expression>,
     ) -> Result<()> {
-        let root = self.erd_graph
-            .get_index(root_entity)
-            .ok_or_else(|| Error::EntityMissingFromErd(root_entity.to_string())).unwrap();
-            for arg in args {
-                match arg {
-                    Join::Entity(entity) => {
-                        self.generate_for_entity(
-                            is_left,
-                            root,
-                            entity.as_str(),
-                        )?
-                    }
-                    Join::Derived(derived) => {
-                        self.generate_for_derived(
-                            is_left,
-                            root,
-                            derived,
-                        )?;
-                    }
-                    Join::Inner(JoinExpression {
-                        root,
-                        args,
-                        condition,
-                    }) => {
-                        if root.is_some() {
-                            return Err(Error::RootInSubjoin);
-                        }
-                        self.generate_join_aux(
-                            false,
-                            root_entity,
-                            args,
-                            condition.clone(),
-                        )?;
+        // derived entities come first, since they must not be brought into scope by the path to
+        // another entity, then the entities of this join are planned together, followed by the
+        // aliases, which may start from any of them, and the nested joins in order.
+        for arg in args {
+            if let Join::Derived(derived) = arg {
+                self.generate_for_derived(is_left, derived)?;
+            }
+        }
+        let entities: Vec<&str> = args
+            .iter()
+            .filter_map(|arg| match arg {
+                Join::Entity(entity) => Some(entity.as_str()),
+                Join::Recursive(recursive) => Some(recursive.entity.as_str()),
+                _ => None,
+            })
+            .collect();
+        self.generate_for_entities(is_left, &entities, condition.as_ref())?;
+        for arg in args {
+            if let Join::Alias(alias) = arg {
+                self.generate_for_alias(is_left, alias)?;
+            }
+        }
+        for arg in args {
+            if let Join::Recursive(recursive) = arg {
+                self.generate_for_recursive(recursive)?;
+            }
+        }
+        for arg in args {
+            match arg {
+                Join::Entity(_) | Join::Derived(_) | Join::Alias(_) | Join::Recursive(_) => {}
+                Join::Inner(JoinExpression {
+                    root,
+                    args,
+                    condition,
+                    ..
+                }) => {
+                    if root.is_some() {
+                        return Err(Error::RootInSubjoin);
                     }
-                    Join::Left(JoinExpression {
-                        root,
-                        args,
-                        condition,
-                    }) => {
-                        if root.is_some() {
-                            return Err(Error::RootInSubjoin);
-                        }
-                        self.generate_join_aux(
-                            true,
-                            root_entity,
-                            args,
-                            condition.clone(),
-                        )?;
+                    self.generate_join_aux(false, args, condition.clone())?;
+                }
+                Join::Left(JoinExpression {
+                    root,
+                    args,
+                    condition,
+                    ..
+                }) => {
+                    if root.is_some() {
+                        return Err(Error::RootInSubjoin);
                     }
+                    self.generate_join_aux(true, args, condition.clone())?;
                 }
             }
-            if let Some(condition) = condition {
+        }
+        if let Some(condition) = condition {
+            // one $match per conjunct, so that match movement can push the conjuncts that
+            // only reference a foreign entity into its $lookup
+            let conjuncts = match condition.get_conjunctive_normal_form() {
+                Expression::UntaggedOperator(UntaggedOperator {
+                    op: UntaggedOperatorName::And,
+                    args,
+                }) => args,
+                condition => vec![condition],
+            };
+            for conjunct in conjuncts {
                 self.pipeline.push(Stage::Match(MatchStage {
                     expr: vec![MatchExpression::Expr(MatchExpr {
-                        expr: Box::new(condition),
+                        expr: Box::new(conjunct),
                     })],
                     numbering: None,
                 }));
             }
+        }
         Ok(())
     }
 
-    fn generate_join(&mut self, join: Join) -> Result<()> {
+    // generate_join returns the name of the root entity of the join.
+    fn generate_join(&mut self, join: Join) -> Result<String> {
         // assume only inner and only one level
-        let (is_left, root_entity, args, condition) = match join {
-            Join::Inner(JoinExpression { root, args, condition }) => (false, root, args, condition),
-            Join::Left(JoinExpression { root, args, condition }) => (true, root, args, condition),
-            _ => unreachable!("Only handling Inner and Left joins right now!")
+        let (is_left, join) = match join {
+            Join::Inner(join) => (false, join),
+            Join::Left(join) => (true, join),
+            _ => unreachable!("Only handling Inner and Left joins right now!"),
         };
-        let root_entity = root_entity.ok_or(Error::NoRoot)?;
-        let root = self.erd_graph
+        self.read_concern = read_concern(&join);
+        if let Some(read_concern) = self.read_concern {
+            self.erd_graph = self
+                .erd_graph
+                .retain_edges(|edge| edge.delivered_consistency().satisfies(read_concern));
+        }
+        let root_entity = join.root.ok_or(Error::NoRoot)?;
+        self.root_db = self.dbs.get(&root_entity).cloned();
+        let root = self
+            .erd_graph
             .get_index(&root_entity)
-            .ok_or_else(|| Error::EntityMissingFromErd(root_entity.clone())).unwrap();
+            .ok_or_else(|| Error::EntityMissingFromErd(root_entity.clone()))?;
         self.pipeline
             .push(self.generate_for_root_source(root_entity.as_str())?);
         self.nodes_in_scope.insert(root);
-        self.generate_join_aux(
-            is_left,
-            &root_entity,
-            &args,
-            condition,
-        )?;
-        Ok(())
+        self.generate_join_aux(is_left, &join.args, join.condition)
+            .map_err(|e| match (e, self.read_concern) {
+                (Error::NoPathToEntity(entity), Some(read_concern)) => {
+                    Error::NoConsistentPathToEntity(entity, read_concern)
+                }
+                (e, _) => e,
+            })?;
+        // an entity on the path to one entity may be requested itself by a later subjoin
+        let mut requested = HashSet::new();
+        requested_entities(&join.args, &mut requested);
+        self.implicit
+            .retain(|entity| !requested.contains(entity.as_str()));
+        if join.exclude_implicit == Some(true) && !self.implicit.is_empty() {
+            self.pipeline.push(Stage::Project(ProjectStage {
+                items: self
+                    .implicit
+                    .iter()
+                    .map(|entity| (entity.clone(), ProjectItem::Exclusion))
+                    .collect(),
+            }));
+        }
+        Ok(root_entity)
+    }
+
+    // lookup_from is the collection to $lookup the entity from: a namespace if it is in another
+    // db than the root entity's source, which is an error if cross-db lookups are disabled.
+    fn lookup_from(&self, entity: &str, db: &str, coll: &str) -> Result<LookupFrom> {
+        match &self.root_db {
+            Some(root_db) if root_db != db => {
+                if !self.options.cross_db_lookups {
+                    return Err(Error::CrossDbLookupNotAllowed(
+                        entity.to_string(),
+                        db.to_string(),
+                        root_db.clone(),
+                    ));
+                }
+                Ok(LookupFrom::Namespace(Namespace {
+                    db: db.to_string(),
+                    coll: coll.to_string(),
+                }))
+            }
+            _ => Ok(LookupFrom::Collection(coll.to_string())),
+        }
     }
 
+    // generate_for_root_source starts the join from the root entity's source: the collection
+    // to run on, if the ERD has sources, and, for a root embedded in another collection, the
+    // $unwind of its target path, before the root is projected into its own field.
     fn generate_for_root_source(&self, entity: &str) -> Result<Stage> {
-        // removing source from erds? Always assume root is ROOT of whatever we are running on?
-        //let source = self
-        //    .entities
-        //    .get_source(entity)
-        //    .ok_or_else(|| Error::EntityMissingFromErd(entity.to_string())).unwrap();
-        //if source.target_path.is_none() {
-        //    return 
-            Ok(Stage::Project(ProjectStage {
-                items: map! {
-                    entity.to_string() => ProjectItem::Assignment(Expression::Ref(Ref::VariableRef("ROOT".to_string()))),
-                    "_id".to_string() => ProjectItem::Exclusion,
-                },
-            }))
-        //}
-//        Ok(Stage::SubPipeline(Pipeline {
-//            pipeline: vec![
-//                Stage::Unwind(Unwind::FieldPath(Expression::Ref(Ref::FieldRef(
-//                    source.target_path.as_ref().unwrap().to_string(),
-//                )))),
-//                Stage::Project(ProjectStage {
-//                    items: map! {
-//                        entity.to_string() => ProjectItem::Assignment(Expression::Ref(Ref::FieldRef(source.target_path.as_ref().unwrap().to_string()))),
-//                        "_id".to_string() => ProjectItem::Exclusion,
-//                    },
-//                }),
-//            ],
-//        }))
+        let mut pipeline = Vec::new();
+        let mut root = Expression::Ref(Ref::VariableRef("ROOT".to_string()));
+        if let Some(source) = self.sources.get(entity) {
+            pipeline.push(Stage::Collection(Collection {
+                db: source.db.clone(),
+                collection: source.collection.clone(),
+            }));
+            if let Some(target_path) = &source.target_path {
+                pipeline.push(Stage::Unwind(Unwind::Document(UnwindExpr {
+                    path: Box::new(Expression::Ref(Ref::FieldRef(target_path.clone()))),
+                    include_array_index: None,
+                    preserve_null_and_empty_arrays: Some(false),
+                })));
+                root = Expression::Ref(Ref::FieldRef(target_path.clone()));
+            }
+        }
+        if let Some(projection) = self.source_projection(entity) {
+            root = projected_document(&root, &projection);
+        }
+        pipeline.push(Stage::Project(ProjectStage {
+            items: map! {
+                entity.to_string() => ProjectItem::Assignment(root),
+                "_id".to_string() => ProjectItem::Exclusion,
+            },
+        }));
+        if let Some(discriminator) = self.discriminators.get(entity) {
+            pipeline.push(discriminator_match(
+                &format!("{}.{}", entity, discriminator.field),
+                discriminator,
+            ));
+        }
+        Ok(Stage::SubPipeline(Pipeline { pipeline }))
+    }
+
+    // source_projection returns the fields of the root entity's source projection, if it has
+    // one, together with the fields its relationships and discriminator are joined by, so that
+    // the root only has the fields materialize stores for it.
+    fn source_projection(&self, entity: &str) -> Option<Vec<String>> {
+        let projection = self.sources.get(entity)?.projection.as_ref()?;
+        if projection.is_empty() {
+            return None;
+        }
+        let mut fields = projection.clone();
+        let entity_index = self.erd_graph.get_index(entity)?;
+        for edge in self.erd_graph.graph.edges(entity_index) {
+            match self.erd_graph.edge_data.get(&edge.id()) {
+                Some(EdgeData::Embedded { target_path, .. }) => fields.push(target_path.clone()),
+                Some(EdgeData::Foreign { keys, .. }) => {
+                    fields.extend(keys.iter().map(|pair| pair.local_key.clone()))
+                }
+                Some(EdgeData::Bucket { local_key, .. }) => fields.push(local_key.clone()),
+                None => {}
+            }
+        }
+        if let Some(discriminator) = self.discriminators.get(entity) {
+            fields.push(discriminator.field.clone());
+        }
+        Some(fields)
+    }
+
+    // discriminate keeps only the records of the entity, which shares its collection or embedded
+    // array with other entities, in the stage that joins it under output. Inner joins filter
+    // after the stage, and match movement pushes the filter into the $lookup. Left joins filter
+    // before the $unwind, inside the $lookup or in the embedded array, so that records without
+    // a match of the entity are still kept.
+    fn discriminate(
+        &self,
+        is_left: bool,
+        entity: &str,
+        output: &str,
+        edge_data: &EdgeData,
+        discriminator: &Discriminator,
+        mut stage: Stage,
+    ) -> Result<Stage> {
+        if !is_left {
+            let field = format!("{}.{}", output, discriminator.field);
+            return Ok(Stage::SubPipeline(Pipeline {
+                pipeline: vec![stage, discriminator_match(&field, discriminator)],
+            }));
+        }
+        match edge_data {
+            EdgeData::Embedded {
+                source_entity,
+                target_path,
+                relationship_type,
+                ..
+            } => {
+                let field = format!("{}.{}", source_entity, target_path);
+                let records = Expression::TaggedOperator(TaggedOperator::Filter(Filter {
+                    input: Box::new(as_array(
+                        Expression::Ref(Ref::FieldRef(field)),
+                        *relationship_type == RelationshipType::OneToOne,
+                    )),
+                    _as: Some("record".to_string()),
+                    cond: Box::new(discriminator_eq(
+                        Ref::VariableRef(format!("record.{}", discriminator.field)),
+                        discriminator,
+                    )),
+                    limit: None,
+                }));
+                Ok(Stage::SubPipeline(Pipeline {
+                    pipeline: vec![
+                        Stage::AddFields(map! { output.to_string() => records }),
+                        Stage::Unwind(Unwind::Document(UnwindExpr {
+                            path: Box::new(Expression::Ref(Ref::FieldRef(output.to_string()))),
+                            include_array_index: None,
+                            preserve_null_and_empty_arrays: Some(true),
+                        })),
+                    ],
+                }))
+            }
+            EdgeData::Bucket { .. } => {
+                Err(Error::DiscriminatorInLeftBucketJoin(entity.to_string()))
+            }
+            EdgeData::Foreign { .. } => {
+                let filter = discriminator_match(&discriminator.field, discriminator);
+                if !filter_lookup(&mut stage, output, filter) {
+                    return Err(Error::NoLookupToDiscriminate(
+                        entity.to_string(),
+                        output.to_string(),
+                    ));
+                }
+                Ok(stage)
+            }
+        }
     }
 
     fn generate_for_embedded(
@@ -419,13 +1083,11 @@ impl JoinGenerator {
         let field = format!("{}.{}", parent_entity, target_path);
         Ok(Stage::SubPipeline(Pipeline {
             pipeline: vec![
-                Stage::Unwind(Unwind::Document(
-                        UnwindExpr {
-                            path: Box::new(Expression::Ref(Ref::FieldRef(field.clone()))),
-                            include_array_index: None,
-                            preserve_null_and_empty_arrays: Some(is_left),
-                        }
-                )),
+                Stage::Unwind(Unwind::Document(UnwindExpr {
+                    path: Box::new(Expression::Ref(Ref::FieldRef(field.clone()))),
+                    include_array_index: None,
+                    preserve_null_and_empty_arrays: Some(is_left),
+                })),
                 Stage::AddFields(map! {
                     embedded_entity.to_string() => Expression::Ref(Ref::FieldRef(field)),
                 }),
@@ -438,26 +1100,516 @@ impl JoinGenerator {
         is_left: bool,
         local_entity: &str,
         foreign_entity: &str,
-        coll: &str,
-        local_key: &str,
-        foreign_key: &str,
+        from: LookupFrom,
+        keys: &[KeyPair],
     ) -> Result<Stage> {
+        let lookup = match keys {
+            [] => return Err(Error::MissingForeignKeys(foreign_entity.to_string())),
+            [pair] => Lookup::Equality(EqualityLookup {
+                from,
+                local_field: format!("{}.{}", local_entity, pair.local_key),
+                foreign_field: pair.foreign_key.clone(),
+                as_var: foreign_entity.to_string(),
+            }),
+            // composite keys need a pipeline lookup matching every key pair
+            _ => {
+                let mut let_body = LinkedHashMap::new();
+                let equalities = keys
+                    .iter()
+                    .map(|pair| {
+                        let var = let_variable(
+                            &mut let_body,
+                            &format!("{}.{}", local_entity, pair.local_key),
+                        );
+                        Expression::UntaggedOperator(UntaggedOperator {
+                            op: UntaggedOperatorName::Eq,
+                            args: vec![
+                                Expression::Ref(Ref::FieldRef(pair.foreign_key.clone())),
+                                Expression::Ref(Ref::VariableRef(var)),
+                            ],
+                        })
+                    })
+                    .collect();
+                Lookup::Subquery(SubqueryLookup {
+                    from: Some(from),
+                    let_body: Some(let_body),
+                    pipeline: expr_match(Expression::UntaggedOperator(UntaggedOperator {
+                        op: UntaggedOperatorName::And,
+                        args: equalities,
+                    })),
+                    as_var: foreign_entity.to_string(),
+                    is_left_join: None,
+                })
+            }
+        };
+        Ok(Stage::SubPipeline(Pipeline {
+            pipeline: vec![
+                Stage::Lookup(lookup),
+                Stage::Unwind(Unwind::Document(UnwindExpr {
+                    path: Box::new(Expression::Ref(Ref::FieldRef(foreign_entity.to_string()))),
+                    include_array_index: None,
+                    preserve_null_and_empty_arrays: Some(is_left),
+                })),
+            ],
+        }))
+    }
+
+    // generate_for_reference_array joins through an array of references. A local array is
+    // copied into the output field and unwound before the lookup, so that every reference
+    // yields its own row, in array order. A foreign array is matched by an equality lookup, which
+    // matches any array element. The index field is set in the lookup pipeline, so it is a field
+    // of the joined documents: for a local array it is the position the $unwind recorded in a
+    // temporary field, for a foreign array the position of the local key in it. In a left join,
+    // a missing, null or empty local array leaves the output field missing rather than looking
+    // up the foreign documents whose key is missing.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_for_reference_array(
+        &self,
+        is_left: bool,
+        local_entity: &str,
+        foreign_entity: &str,
+        from: LookupFrom,
+        keys: &[KeyPair],
+        reference_array: ReferenceArray,
+        index_field: Option<&str>,
+    ) -> Result<Stage> {
+        let [pair] = keys else {
+            return Err(Error::UnsupportedCompositeKeys(
+                foreign_entity.to_string(),
+                "referenceArray",
+            ));
+        };
+        let local_field = format!("{}.{}", local_entity, pair.local_key);
+        let index_temporary = format!("__{}_index", foreign_entity);
+        let unwind = |include_array_index: Option<String>| {
+            Stage::Unwind(Unwind::Document(UnwindExpr {
+                path: Box::new(Expression::Ref(Ref::FieldRef(foreign_entity.to_string()))),
+                include_array_index,
+                preserve_null_and_empty_arrays: Some(is_left),
+            }))
+        };
+        // the lookup is a concise subquery lookup if it needs a pipeline
+        let lookup = |local_field: String,
+                      let_body: LinkedHashMap<String, Expression>,
+                      pipeline: Vec<Stage>| {
+            Stage::Lookup(if pipeline.is_empty() {
+                Lookup::Equality(EqualityLookup {
+                    from: from.clone(),
+                    local_field,
+                    foreign_field: pair.foreign_key.clone(),
+                    as_var: foreign_entity.to_string(),
+                })
+            } else {
+                Lookup::ConciseSubquery(ConciseSubqueryLookup {
+                    from: Some(from.clone()),
+                    local_field,
+                    foreign_field: pair.foreign_key.clone(),
+                    let_body: Some(let_body),
+                    pipeline: Pipeline { pipeline },
+                    as_var: foreign_entity.to_string(),
+                })
+            })
+        };
+        let mut let_body = LinkedHashMap::new();
+        let mut lookup_pipeline = vec![];
+        let pipeline = match reference_array {
+            ReferenceArray::Local => {
+                if is_left {
+                    // the unwound reference is missing or null if the array was
+                    let reference = let_variable(&mut let_body, foreign_entity);
+                    let guard = expr_match(Expression::UntaggedOperator(UntaggedOperator {
+                        op: UntaggedOperatorName::Not,
+                        args: vec![is_null(Expression::Ref(Ref::VariableRef(reference)))],
+                    }));
+                    lookup_pipeline.extend(guard.pipeline);
+                }
+                if let Some(index_field) = index_field {
+                    let index = let_variable(&mut let_body, &index_temporary);
+                    lookup_pipeline.push(Stage::AddFields(map! {
+                        index_field.to_string() => Expression::Ref(Ref::VariableRef(index)),
+                    }));
+                }
+                let mut pipeline = vec![
+                    Stage::AddFields(map! {
+                        foreign_entity.to_string() => Expression::Ref(Ref::FieldRef(local_field)),
+                    }),
+                    unwind(index_field.map(|_| index_temporary.clone())),
+                    lookup(foreign_entity.to_string(), let_body, lookup_pipeline),
+                    unwind(None),
+                ];
+                if index_field.is_some() {
+                    pipeline.push(Stage::Unset(Unset::Single(index_temporary)));
+                }
+                pipeline
+            }
+            ReferenceArray::Foreign => {
+                if let Some(index_field) = index_field {
+                    let local_key = let_variable(&mut let_body, &local_field);
+                    lookup_pipeline.push(Stage::AddFields(map! {
+                        index_field.to_string() => Expression::UntaggedOperator(UntaggedOperator {
+                            op: UntaggedOperatorName::IndexOfArray,
+                            args: vec![
+                                Expression::Ref(Ref::FieldRef(pair.foreign_key.clone())),
+                                Expression::Ref(Ref::VariableRef(local_key)),
+                            ],
+                        }),
+                    }));
+                }
+                vec![lookup(local_field, let_body, lookup_pipeline), unwind(None)]
+            }
+        };
+        Ok(Stage::SubPipeline(Pipeline { pipeline }))
+    }
+
+    // generate_for_bucket looks up the bucket documents of the local entity, then unwinds both
+    // the buckets and their arrays of items, and replaces the bucket with its item.
+    fn generate_for_bucket(
+        &self,
+        is_left: bool,
+        local_entity: &str,
+        foreign_entity: &str,
+        from: LookupFrom,
+        pair: &KeyPair,
+        array_path: &str,
+    ) -> Result<Stage> {
+        let items = format!("{}.{}", foreign_entity, array_path);
+        let unwind = |path: &str| {
+            Stage::Unwind(Unwind::Document(UnwindExpr {
+                path: Box::new(Expression::Ref(Ref::FieldRef(path.to_string()))),
+                include_array_index: None,
+                preserve_null_and_empty_arrays: Some(is_left),
+            }))
+        };
         Ok(Stage::SubPipeline(Pipeline {
             pipeline: vec![
                 Stage::Lookup(Lookup::Equality(EqualityLookup {
-                    from: LookupFrom::Collection(coll.to_string()),
-                    local_field: format!("{}.{}", local_entity, local_key),
-                    foreign_field: foreign_key.to_string(),
+                    from: from.clone(),
+                    local_field: format!("{}.{}", local_entity, pair.local_key),
+                    foreign_field: pair.foreign_key.clone(),
                     as_var: foreign_entity.to_string(),
                 })),
-                Stage::Unwind(Unwind::Document(
-                        UnwindExpr {
-                            path: Box::new(Expression::Ref(Ref::FieldRef(foreign_entity.to_string()))),
-                            include_array_index: None,
-                            preserve_null_and_empty_arrays: Some(is_left),
-                        }
-                )),
+                unwind(foreign_entity),
+                unwind(&items),
+                Stage::AddFields(map! {
+                    foreign_entity.to_string() => Expression::Ref(Ref::FieldRef(items)),
+                }),
             ],
         }))
     }
+
+    // generate_for_junction joins through a junction collection: the junction documents of the
+    // local entity are looked up and unwound into the output field first, which the lookup of
+    // the foreign entity then replaces. In a left join, a local entity without junction documents
+    // leaves the output field missing rather than looking up the foreign documents whose key is
+    // missing.
+    fn generate_for_junction(
+        &self,
+        is_left: bool,
+        local_entity: &str,
+        foreign_entity: &str,
+        from: LookupFrom,
+        keys: &[KeyPair],
+        junction: &Junction,
+    ) -> Result<Stage> {
+        let [pair] = keys else {
+            return Err(Error::UnsupportedCompositeKeys(
+                foreign_entity.to_string(),
+                "junction",
+            ));
+        };
+        let unwind = || {
+            Stage::Unwind(Unwind::Document(UnwindExpr {
+                path: Box::new(Expression::Ref(Ref::FieldRef(foreign_entity.to_string()))),
+                include_array_index: None,
+                preserve_null_and_empty_arrays: Some(is_left),
+            }))
+        };
+        let local_field = format!("{}.{}", foreign_entity, junction.foreign_key);
+        let lookup = if is_left {
+            // the unwound junction key is missing if there were no junction documents
+            let mut let_body = LinkedHashMap::new();
+            let key = let_variable(&mut let_body, &local_field);
+            let guard = expr_match(Expression::UntaggedOperator(UntaggedOperator {
+                op: UntaggedOperatorName::Not,
+                args: vec![is_null(Expression::Ref(Ref::VariableRef(key)))],
+            }));
+            Lookup::ConciseSubquery(ConciseSubqueryLookup {
+                from: Some(from),
+                local_field,
+                foreign_field: pair.foreign_key.clone(),
+                let_body: Some(let_body),
+                pipeline: guard,
+                as_var: foreign_entity.to_string(),
+            })
+        } else {
+            Lookup::Equality(EqualityLookup {
+                from,
+                local_field,
+                foreign_field: pair.foreign_key.clone(),
+                as_var: foreign_entity.to_string(),
+            })
+        };
+        Ok(Stage::SubPipeline(Pipeline {
+            pipeline: vec![
+                Stage::Lookup(Lookup::Equality(EqualityLookup {
+                    from: self.lookup_from(foreign_entity, &junction.db, &junction.collection)?,
+                    local_field: format!("{}.{}", local_entity, pair.local_key),
+                    foreign_field: junction.local_key.clone(),
+                    as_var: foreign_entity.to_string(),
+                })),
+                unwind(),
+                Stage::Lookup(lookup),
+                unwind(),
+            ],
+        }))
+    }
+}
+
+// entity_db is the db the entity is stored in: the db of its source, or else the db of the
+// collections of the foreign relationships that target it, if they are all in one db.
+fn entity_db<T: GetErdData>(erd: &T, entity: &str) -> Option<String> {
+    if let Some(source) = erd.get_source(entity) {
+        return Some(source.db.clone());
+    }
+    let mut dbs = erd.get_collections(entity).into_iter().map(|(db, _)| db);
+    let db = dbs.next()?;
+    dbs.all(|other| other == db).then_some(db)
+}
+
+// is_null is true if the expression is missing or null.
+fn is_null(expr: Expression) -> Expression {
+    Expression::UntaggedOperator(UntaggedOperator {
+        op: UntaggedOperatorName::In,
+        args: vec![
+            Expression::UntaggedOperator(UntaggedOperator {
+                op: UntaggedOperatorName::Type,
+                args: vec![expr],
+            }),
+            Expression::Array(vec![
+                Expression::Literal(LiteralValue::String("missing".to_string())),
+                Expression::Literal(LiteralValue::String("null".to_string())),
+            ]),
+        ],
+    })
+}
+
+// projected_document is the document of the given fields of root, nested by their dotted
+// paths. A field inside another given field is covered by it.
+fn projected_document(root: &Expression, fields: &[String]) -> Expression {
+    let field_ref = |field: &str| match root {
+        Expression::Ref(Ref::FieldRef(path)) => {
+            Expression::Ref(Ref::FieldRef(format!("{}.{}", path, field)))
+        }
+        _ => Expression::Ref(Ref::FieldRef(field.to_string())),
+    };
+    // sorted, a field comes before the fields inside it
+    let mut fields: Vec<&String> = fields.iter().collect();
+    fields.sort();
+    fields.dedup();
+    let mut document = LinkedHashMap::new();
+    'fields: for field in fields {
+        let mut parts: Vec<&str> = field.split('.').collect();
+        let last = parts.pop().unwrap_or_default();
+        let mut current = &mut document;
+        for part in parts {
+            let Expression::Document(child) = current
+                .entry(part.to_string())
+                .or_insert_with(|| Expression::Document(LinkedHashMap::new()))
+            else {
+                continue 'fields;
+            };
+            current = child;
+        }
+        current.insert(last.to_string(), field_ref(field));
+    }
+    Expression::Document(document)
+}
+
+// expr_match is a pipeline with a single $match of the expression.
+fn expr_match(expr: Expression) -> Pipeline {
+    Pipeline {
+        pipeline: vec![Stage::Match(MatchStage {
+            expr: vec![MatchExpression::Expr(MatchExpr {
+                expr: Box::new(expr),
+            })],
+            numbering: None,
+        })],
+    }
+}
+
+// read_concern returns the strongest read concern of the join and its subjoins, which applies
+// to the whole join since its entities share paths.
+fn read_concern(join: &JoinExpression) -> Option<Consistency> {
+    join.args
+        .iter()
+        .filter_map(|arg| match arg {
+            Join::Inner(join) | Join::Left(join) => read_concern(join),
+            _ => None,
+        })
+        .chain(join.read_concern.map(Consistency::from))
+        .reduce(|a, b| if a.satisfies(b) { a } else { b })
+}
+
+// discriminator_eq is the expression that is true for records of the entity with the
+// discriminator, whose discriminator field is at field.
+fn discriminator_eq(field: Ref, discriminator: &Discriminator) -> Expression {
+    Expression::UntaggedOperator(UntaggedOperator {
+        op: UntaggedOperatorName::Eq,
+        args: vec![
+            Expression::Ref(field),
+            Expression::Literal(LiteralValue::String(discriminator.value.clone())),
+        ],
+    })
+}
+
+// discriminator_match is the $match that keeps the records of the entity with the
+// discriminator, whose discriminator field is at field.
+fn discriminator_match(field: &str, discriminator: &Discriminator) -> Stage {
+    Stage::Match(MatchStage {
+        expr: vec![MatchExpression::Expr(MatchExpr {
+            expr: Box::new(discriminator_eq(
+                Ref::FieldRef(field.to_string()),
+                discriminator,
+            )),
+        })],
+        numbering: None,
+    })
+}
+
+// filter_lookup appends the filter to the pipeline of the last $lookup into output in the stage,
+// and returns whether there is one. An equality $lookup becomes a concise subquery $lookup.
+fn filter_lookup(stage: &mut Stage, output: &str, filter: Stage) -> bool {
+    match stage {
+        Stage::SubPipeline(pipeline) => pipeline
+            .pipeline
+            .iter_mut()
+            .rev()
+            .any(|stage| filter_lookup(stage, output, filter.clone())),
+        Stage::Lookup(Lookup::Equality(lookup)) if lookup.as_var == output => {
+            *stage = Stage::Lookup(Lookup::ConciseSubquery(ConciseSubqueryLookup {
+                from: Some(lookup.from.clone()),
+                local_field: lookup.local_field.clone(),
+                foreign_field: lookup.foreign_field.clone(),
+                let_body: None,
+                pipeline: Pipeline {
+                    pipeline: vec![filter],
+                },
+                as_var: lookup.as_var.clone(),
+            }));
+            true
+        }
+        Stage::Lookup(Lookup::ConciseSubquery(ConciseSubqueryLookup {
+            pipeline, as_var, ..
+        }))
+        | Stage::Lookup(Lookup::Subquery(SubqueryLookup {
+            pipeline, as_var, ..
+        })) if as_var == output => {
+            pipeline.pipeline.push(filter);
+            true
+        }
+        _ => false,
+    }
+}
+
+// nested_levels collects the records embedded at target_path of the document at field, and at
+// target_path of those records in turn, up to max_depth levels below the first, into one array.
+// With depth_field every record is tagged with its level, as $graphLookup does.
+fn nested_levels(
+    field: &str,
+    target_path: &str,
+    one_to_one: bool,
+    max_depth: i32,
+    depth_field: Option<&str>,
+) -> Expression {
+    let mut level = as_array(
+        Expression::Ref(Ref::FieldRef(field.to_string())),
+        one_to_one,
+    );
+    let mut levels = Vec::new();
+    for depth in 0..=max_depth {
+        if depth > 0 {
+            // the records below the previous level: the concatenation of their target paths
+            level = Expression::TaggedOperator(TaggedOperator::Reduce(Reduce {
+                input: Box::new(level),
+                initial_value: Box::new(Expression::Array(Vec::new())),
+                inside: Box::new(Expression::UntaggedOperator(UntaggedOperator {
+                    op: UntaggedOperatorName::ConcatArrays,
+                    args: vec![
+                        Expression::Ref(Ref::VariableRef("value".to_string())),
+                        as_array(
+                            Expression::Ref(Ref::VariableRef(format!("this.{}", target_path))),
+                            one_to_one,
+                        ),
+                    ],
+                })),
+            }));
+        }
+        levels.push(match depth_field {
+            Some(depth_field) => Expression::TaggedOperator(TaggedOperator::Map(Map {
+                input: Box::new(level.clone()),
+                _as: Some("node".to_string()),
+                inside: Box::new(Expression::UntaggedOperator(UntaggedOperator {
+                    op: UntaggedOperatorName::MergeObjects,
+                    args: vec![
+                        Expression::Ref(Ref::VariableRef("node".to_string())),
+                        Expression::Document(map! {
+                            depth_field.to_string() =>
+                                Expression::Literal(LiteralValue::Int32(depth)),
+                        }),
+                    ],
+                })),
+            })),
+            None => level.clone(),
+        });
+    }
+    Expression::UntaggedOperator(UntaggedOperator {
+        op: UntaggedOperatorName::ConcatArrays,
+        args: levels,
+    })
+}
+
+// as_array is the records embedded at a path as an array: a one-to-one relationship embeds a
+// single document, which may be missing, the others an array, which may be missing too.
+fn as_array(records: Expression, one_to_one: bool) -> Expression {
+    if one_to_one {
+        Expression::TaggedOperator(TaggedOperator::Cond(Cond {
+            _if: Box::new(Expression::UntaggedOperator(UntaggedOperator {
+                op: UntaggedOperatorName::Eq,
+                args: vec![
+                    Expression::UntaggedOperator(UntaggedOperator {
+                        op: UntaggedOperatorName::Type,
+                        args: vec![records.clone()],
+                    }),
+                    Expression::Literal(LiteralValue::String("object".to_string())),
+                ],
+            })),
+            then: Box::new(Expression::Array(vec![records])),
+            _else: Box::new(Expression::Array(Vec::new())),
+        }))
+    } else {
+        Expression::UntaggedOperator(UntaggedOperator {
+            op: UntaggedOperatorName::IfNull,
+            args: vec![records, Expression::Array(Vec::new())],
+        })
+    }
+}
+
+// requested_entities collects every entity named by the join arguments, including those of
+// subjoins.
+fn requested_entities<'a>(args: &'a [Join], requested: &mut HashSet<&'a str>) {
+    for arg in args {
+        match arg {
+            Join::Entity(entity) => {
+                requested.insert(entity.as_str());
+            }
+            Join::Derived(derived) => {
+                requested.insert(derived.entity.as_str());
+            }
+            Join::Recursive(recursive) => {
+                requested.insert(recursive.entity.as_str());
+            }
+            Join::Alias(_) => {}
+            Join::Inner(join) | Join::Left(join) => requested_entities(&join.args, requested),
+        }
+    }
 }
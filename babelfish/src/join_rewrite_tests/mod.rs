@@ -0,0 +1,710 @@
+macro_rules! test_join_rewrite {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        test_join_rewrite!(
+            $func_name,
+            expected = $expected,
+            erd = crate::join_rewrite_tests::ERD,
+            options = crate::join_rewrite::JoinOptions::default(),
+            input = $input
+        );
+    };
+    ($func_name:ident, expected = $expected:expr, erd = $erd:expr, options = $options:expr, input = $input:expr) => {
+        test_join_rewrite!(
+            $func_name,
+            expected = $expected,
+            erd = $erd,
+            format = crate::erd::Relationships,
+            options = $options,
+            input = $input
+        );
+    };
+    ($func_name:ident, expected = $expected:expr, erd = $erd:expr, format = $format:ty, options = $options:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::{
+                join_rewrite::rewrite_pipeline_with_options,
+                match_movement_rewrite::flatten_pipeline,
+            };
+            use ast::definitions::Pipeline;
+
+            let erd: $format = serde_json::from_str($erd).unwrap();
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let result =
+                flatten_pipeline(rewrite_pipeline_with_options(input, &erd, $options).unwrap());
+            assert_eq!(expected, serde_json::to_value(result).unwrap());
+        }
+    };
+}
+
+macro_rules! test_join_rewrite_error {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        test_join_rewrite_error!(
+            $func_name,
+            expected = $expected,
+            erd = crate::join_rewrite_tests::ERD,
+            options = crate::join_rewrite::JoinOptions::default(),
+            input = $input
+        );
+    };
+    ($func_name:ident, expected = $expected:expr, erd = $erd:expr, options = $options:expr, input = $input:expr) => {
+        test_join_rewrite_error!(
+            $func_name,
+            expected = $expected,
+            erd = $erd,
+            format = crate::erd::Relationships,
+            options = $options,
+            input = $input
+        );
+    };
+    ($func_name:ident, expected = $expected:expr, erd = $erd:expr, format = $format:ty, options = $options:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::join_rewrite::rewrite_pipeline_with_options;
+            use ast::definitions::Pipeline;
+
+            let erd: $format = serde_json::from_str($erd).unwrap();
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let error = rewrite_pipeline_with_options(input, &erd, $options).unwrap_err();
+            assert_eq!($expected, error.to_string());
+        }
+    };
+}
+
+// Customer embeds an Address and a Phone at the same cost, and reaches Product only through
+// Order. Customer references its Tags by an array of ids, and Segments reference their
+// Customers by one. Warehouse is not connected.
+const ERD: &str = r#"{
+    "Customer": {
+        "Address": {
+            "relationshipType": "one-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "address", "projection": []}
+        },
+        "Phone": {
+            "relationshipType": "one-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "phone", "projection": []}
+        },
+        "Tag": {
+            "relationshipType": "many-to-many",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "tags",
+                "localKey": "tagIds",
+                "foreignKey": "_id",
+                "referenceArray": "local",
+                "indexField": "position",
+                "projection": []
+            }
+        },
+        "Segment": {
+            "relationshipType": "many-to-many",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "segments",
+                "localKey": "_id",
+                "foreignKey": "customerIds",
+                "referenceArray": "foreign",
+                "indexField": "rank",
+                "projection": []
+            }
+        },
+        "Order": {
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "orders",
+                "localKey": "_id",
+                "foreignKey": "customerId",
+                "projection": []
+            }
+        }
+    },
+    "Order": {
+        "Product": {
+            "relationshipType": "one-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "products",
+                "localKey": "productId",
+                "foreignKey": "_id",
+                "projection": []
+            }
+        }
+    },
+    "Address": {},
+    "Phone": {},
+    "Product": {},
+    "Segment": {},
+    "Tag": {},
+    "Warehouse": {}
+}"#;
+
+test_join_rewrite!(
+    unlisted_connecting_entity_is_joined,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$unwind": {"path": "$Customer.address", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Address": "$Customer.address"}}, {"$lookup": {"from": "orders", "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}}, {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}, {"$lookup": {"from": "products", "localField": "Order.productId", "foreignField": "_id", "as": "Product"}}, {"$unwind": {"path": "$Product", "preserveNullAndEmptyArrays": false}}]"#,
+    input = r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Product", "Address"]}}}]"#
+);
+
+test_join_rewrite!(
+    exclude_implicit_projects_connecting_entity_away,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$lookup": {"from": "orders", "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}}, {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}, {"$lookup": {"from": "products", "localField": "Order.productId", "foreignField": "_id", "as": "Product"}}, {"$unwind": {"path": "$Product", "preserveNullAndEmptyArrays": false}}, {"$project": {"Order": false}}]"#,
+    input = r#"[{"$join": {"$inner": {
+        "root": "Customer",
+        "args": ["Product"],
+        "excludeImplicit": true
+    }}}]"#
+);
+
+test_join_rewrite!(
+    equal_cost_entities_keep_listed_order,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$unwind": {"path": "$Customer.phone", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Phone": "$Customer.phone"}}, {"$unwind": {"path": "$Customer.address", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Address": "$Customer.address"}}]"#,
+    input = r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Phone", "Address"]}}}]"#
+);
+
+test_join_rewrite!(
+    filtered_entity_is_joined_first,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$lookup": {"from": "orders", "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}}, {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}, {"$unwind": {"path": "$Customer.address", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Address": "$Customer.address"}}, {"$match": {"$expr": {"$gt": ["$Order.total", 100]}}}]"#,
+    input = r#"[{"$join": {"$inner": {
+        "root": "Customer",
+        "args": ["Order", "Address"],
+        "condition": {"$gt": ["$Order.total", 100]}
+    }}}]"#
+);
+
+test_join_rewrite!(
+    local_reference_array_index_in_entity,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$addFields": {"Tag": "$Customer.tagIds"}}, {"$unwind": {"path": "$Tag", "includeArrayIndex": "__Tag_index", "preserveNullAndEmptyArrays": false}}, {"$lookup": {"from": "tags", "localField": "Tag", "foreignField": "_id", "let": {"babelfish___Tag_index": "$__Tag_index"}, "pipeline": [{"$addFields": {"position": "$$babelfish___Tag_index"}}], "as": "Tag"}}, {"$unwind": {"path": "$Tag", "preserveNullAndEmptyArrays": false}}, {"$unset": "__Tag_index"}]"#,
+    input = r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Tag"]}}}]"#
+);
+
+test_join_rewrite!(
+    left_join_local_reference_array_guards_missing_array,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$addFields": {"Tag": "$Customer.tagIds"}}, {"$unwind": {"path": "$Tag", "includeArrayIndex": "__Tag_index", "preserveNullAndEmptyArrays": true}}, {"$lookup": {"from": "tags", "localField": "Tag", "foreignField": "_id", "let": {"babelfish_Tag": "$Tag", "babelfish___Tag_index": "$__Tag_index"}, "pipeline": [{"$match": {"$expr": {"$not": [{"$in": [{"$type": ["$$babelfish_Tag"]}, ["missing", "null"]]}]}}}, {"$addFields": {"position": "$$babelfish___Tag_index"}}], "as": "Tag"}}, {"$unwind": {"path": "$Tag", "preserveNullAndEmptyArrays": true}}, {"$unset": "__Tag_index"}]"#,
+    input =
+        r#"[{"$join": {"$inner": {"root": "Customer", "args": [{"$left": {"args": ["Tag"]}}]}}}]"#
+);
+
+test_join_rewrite!(
+    foreign_reference_array_index_in_entity,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$lookup": {"from": "segments", "localField": "Customer._id", "foreignField": "customerIds", "let": {"babelfish_Customer__id": "$Customer._id"}, "pipeline": [{"$addFields": {"rank": {"$indexOfArray": ["$customerIds", "$$babelfish_Customer__id"]}}}], "as": "Segment"}}, {"$unwind": {"path": "$Segment", "preserveNullAndEmptyArrays": false}}]"#,
+    input = r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Segment"]}}}]"#
+);
+
+test_join_rewrite_error!(
+    disconnected_entity,
+    expected = "No path to entity: Warehouse",
+    input = r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Address", "Warehouse"]}}}]"#
+);
+
+// Orders are stored in shop, which is known from the relationship into them. Customers are
+// stored in crm and Reviews in reviews.
+const CROSS_DB_ERD: &str = r#"{
+    "Customer": {
+        "Order": {
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "orders",
+                "localKey": "_id",
+                "foreignKey": "customerId",
+                "projection": []
+            }
+        }
+    },
+    "Order": {
+        "Customer": {
+            "relationshipType": "one-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "crm",
+                "collection": "customers",
+                "localKey": "customerId",
+                "foreignKey": "_id",
+                "projection": []
+            }
+        },
+        "Review": {
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "reviews",
+                "collection": "reviews",
+                "localKey": "_id",
+                "foreignKey": "orderId",
+                "projection": []
+            }
+        }
+    },
+    "Review": {}
+}"#;
+
+test_join_rewrite!(
+    cross_db_lookup_uses_namespace,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$lookup": {"from": {"db": "crm", "coll": "customers"}, "localField": "Order.customerId", "foreignField": "_id", "as": "Customer"}}, {"$unwind": {"path": "$Customer", "preserveNullAndEmptyArrays": false}}, {"$lookup": {"from": {"db": "reviews", "coll": "reviews"}, "localField": "Order._id", "foreignField": "orderId", "as": "Review"}}, {"$unwind": {"path": "$Review", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = CROSS_DB_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Customer", "Review"]}}}]"#
+);
+
+test_join_rewrite!(
+    root_db_from_relationship_into_root,
+    expected = r#"[{"$project": {"Customer": "$$ROOT", "_id": false}}, {"$lookup": {"from": {"db": "shop", "coll": "orders"}, "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}}, {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = CROSS_DB_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Order"]}}}]"#
+);
+
+test_join_rewrite_error!(
+    cross_db_lookup_not_allowed,
+    expected = "Entity Review is stored in db reviews, but cross-db lookups from db shop are not allowed: query reviews separately and combine the results with $unionWith, or allow cross-db lookups",
+    erd = CROSS_DB_ERD,
+    options = crate::join_rewrite::JoinOptions {
+        cross_db_lookups: false
+    },
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Review"]}}}]"#
+);
+
+// Categories reference their parent category, Employees embed their reports, and Sensors bucket
+// the readings of their child sensors.
+const RECURSIVE_ERD: &str = r#"{
+    "Category": {
+        "Category": {
+            "name": "parent",
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "categories",
+                "localKey": "parentId",
+                "foreignKey": "_id",
+                "projection": []
+            }
+        }
+    },
+    "Employee": {
+        "Employee": {
+            "name": "reports",
+            "relationshipType": "many-to-one",
+            "constraint": {"constraintType": "embedded", "targetPath": "reports", "projection": []}
+        }
+    },
+    "Sensor": {
+        "Sensor": {
+            "name": "children",
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "bucket",
+                "db": "iot",
+                "collection": "sensor_buckets",
+                "localKey": "_id",
+                "foreignKey": "parentId",
+                "targetPath": "sensors",
+                "projection": []
+            }
+        }
+    }
+}"#;
+
+test_join_rewrite!(
+    recursive_foreign_graph_lookup,
+    expected = r#"[{"$project": {"Category": "$$ROOT", "_id": false}}, {"$graphLookup": {"from": "categories", "startWith": "$Category.parentId", "connectFromField": "parentId", "connectToField": "_id", "as": "Ancestors", "maxDepth": 5, "depthField": "depth"}}]"#,
+    erd = RECURSIVE_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Category", "args": [
+        {"$recursive": {"entity": "Category", "as": "Ancestors", "maxDepth": 5, "depthField": "depth"}}
+    ]}}}]"#
+);
+
+test_join_rewrite!(
+    recursive_embedded_one_level,
+    expected = r#"[{"$project": {"Employee": "$$ROOT", "_id": false}}, {"$addFields": {"reports": {"$concatArrays": [{"$ifNull": ["$Employee.reports", []]}]}}}]"#,
+    erd = RECURSIVE_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Employee", "args": [
+        {"$recursive": {"entity": "Employee", "maxDepth": 0}}
+    ]}}}]"#
+);
+
+test_join_rewrite!(
+    recursive_embedded_levels,
+    expected = r#"[{"$project": {"Employee": "$$ROOT", "_id": false}}, {"$addFields": {"Reports": {"$concatArrays": [{"$map": {"input": {"$ifNull": ["$Employee.reports", []]}, "as": "node", "in": {"$mergeObjects": ["$$node", {"level": 0}]}}}, {"$map": {"input": {"$reduce": {"input": {"$ifNull": ["$Employee.reports", []]}, "initialValue": [], "in": {"$concatArrays": ["$$value", {"$ifNull": ["$$this.reports", []]}]}}}, "as": "node", "in": {"$mergeObjects": ["$$node", {"level": 1}]}}}, {"$map": {"input": {"$reduce": {"input": {"$reduce": {"input": {"$ifNull": ["$Employee.reports", []]}, "initialValue": [], "in": {"$concatArrays": ["$$value", {"$ifNull": ["$$this.reports", []]}]}}}, "initialValue": [], "in": {"$concatArrays": ["$$value", {"$ifNull": ["$$this.reports", []]}]}}}, "as": "node", "in": {"$mergeObjects": ["$$node", {"level": 2}]}}}]}}}]"#,
+    erd = RECURSIVE_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Employee", "args": [
+        {"$recursive": {"entity": "Employee", "as": "Reports", "maxDepth": 2, "depthField": "level"}}
+    ]}}}]"#
+);
+
+test_join_rewrite_error!(
+    recursive_bucket_unsupported,
+    expected = "Recursive join of Sensor does not support bucket relationships",
+    erd = RECURSIVE_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Sensor", "args": [
+        {"$recursive": {"entity": "Sensor"}}
+    ]}}}]"#
+);
+
+// Clicks and Views share the events collection and the embedded events array of a Session,
+// and are told apart by their type. Readings of a Sensor are kept in buckets.
+const DISCRIMINATOR_ERD: &str = r#"{
+    "Session": {
+        "source": {"db": "web", "collection": "sessions"},
+        "primaryKey": "_id",
+        "relationships": {
+            "Click": {
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "web",
+                    "collection": "events",
+                    "localKey": "_id",
+                    "foreignKey": "sessionId",
+                    "projection": []
+                }
+            },
+            "View": {
+                "relationshipType": "many-to-one",
+                "constraint": {"constraintType": "embedded", "targetPath": "events", "projection": []}
+            },
+            "Reading": {
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "bucket",
+                    "db": "web",
+                    "collection": "reading_buckets",
+                    "localKey": "_id",
+                    "foreignKey": "sessionId",
+                    "targetPath": "readings",
+                    "projection": []
+                }
+            }
+        },
+        "jsonSchema": {}
+    },
+    "Click": {
+        "source": {"db": "web", "collection": "events"},
+        "primaryKey": "_id",
+        "relationships": {},
+        "jsonSchema": {},
+        "discriminator": {"field": "type", "value": "click"}
+    },
+    "View": {
+        "source": {"db": "web", "collection": "events"},
+        "primaryKey": "_id",
+        "relationships": {},
+        "jsonSchema": {},
+        "discriminator": {"field": "type", "value": "view"}
+    },
+    "Reading": {
+        "source": {"db": "web", "collection": "reading_buckets", "targetPath": "readings"},
+        "primaryKey": "_id",
+        "relationships": {},
+        "jsonSchema": {},
+        "discriminator": {"field": "kind", "value": "reading"}
+    }
+}"#;
+
+test_join_rewrite!(
+    inner_join_discriminator_filters_after_lookup,
+    expected = r#"[{"$collection": {"db": "web", "collection": "sessions"}}, {"$project": {"Session": "$$ROOT", "_id": false}}, {"$lookup": {"from": "events", "localField": "Session._id", "foreignField": "sessionId", "as": "Click"}}, {"$unwind": {"path": "$Click", "preserveNullAndEmptyArrays": false}}, {"$match": {"$expr": {"$eq": ["$Click.type", "click"]}}}]"#,
+    erd = DISCRIMINATOR_ERD,
+    format = crate::erd::Erd,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Session", "args": ["Click"]}}}]"#
+);
+
+test_join_rewrite!(
+    left_join_discriminator_filters_in_lookup,
+    expected = r#"[{"$collection": {"db": "web", "collection": "sessions"}}, {"$project": {"Session": "$$ROOT", "_id": false}}, {"$lookup": {"from": "events", "localField": "Session._id", "foreignField": "sessionId", "pipeline": [{"$match": {"$expr": {"$eq": ["$type", "click"]}}}], "as": "Click"}}, {"$unwind": {"path": "$Click", "preserveNullAndEmptyArrays": true}}]"#,
+    erd = DISCRIMINATOR_ERD,
+    format = crate::erd::Erd,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input =
+        r#"[{"$join": {"$inner": {"root": "Session", "args": [{"$left": {"args": ["Click"]}}]}}}]"#
+);
+
+test_join_rewrite!(
+    left_join_discriminator_filters_embedded_array,
+    expected = r#"[{"$collection": {"db": "web", "collection": "sessions"}}, {"$project": {"Session": "$$ROOT", "_id": false}}, {"$addFields": {"View": {"$filter": {"input": {"$ifNull": ["$Session.events", []]}, "as": "record", "cond": {"$eq": ["$$record.type", "view"]}}}}}, {"$unwind": {"path": "$View", "preserveNullAndEmptyArrays": true}}]"#,
+    erd = DISCRIMINATOR_ERD,
+    format = crate::erd::Erd,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input =
+        r#"[{"$join": {"$inner": {"root": "Session", "args": [{"$left": {"args": ["View"]}}]}}}]"#
+);
+
+test_join_rewrite_error!(
+    left_join_discriminator_in_bucket,
+    expected = "Left join of entity Reading from a bucket cannot filter on its discriminator",
+    erd = DISCRIMINATOR_ERD,
+    format = crate::erd::Erd,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Session", "args": [{"$left": {"args": ["Reading"]}}]}}}]"#
+);
+
+// Employees reference their manager and their mentor, who are Employees as well.
+const SELF_JOIN_ERD: &str = r#"{
+    "Employee": {
+        "Employee": [
+            {
+                "name": "manager",
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "hr",
+                    "collection": "employees",
+                    "localKey": "managerId",
+                    "foreignKey": "_id",
+                    "projection": []
+                }
+            },
+            {
+                "name": "mentor",
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "hr",
+                    "collection": "employees",
+                    "localKey": "mentorId",
+                    "foreignKey": "_id",
+                    "projection": []
+                }
+            }
+        ]
+    }
+}"#;
+
+test_join_rewrite!(
+    aliased_self_joins_have_distinct_outputs,
+    expected = r#"[{"$project": {"Employee": "$$ROOT", "_id": false}}, {"$lookup": {"from": "employees", "localField": "Employee.managerId", "foreignField": "_id", "as": "Manager"}}, {"$unwind": {"path": "$Manager", "preserveNullAndEmptyArrays": false}}, {"$lookup": {"from": "employees", "localField": "Employee.mentorId", "foreignField": "_id", "as": "Mentor"}}, {"$unwind": {"path": "$Mentor", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = SELF_JOIN_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Employee", "args": [
+        {"entity": "Employee", "as": "Manager", "via": "manager"},
+        {"entity": "Employee", "as": "Mentor", "via": "mentor"}
+    ]}}}]"#
+);
+
+test_join_rewrite_error!(
+    alias_in_scope_needs_via,
+    expected = "Alias Manager of entity Employee needs via, since Employee is already in scope",
+    erd = SELF_JOIN_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Employee", "args": [
+        {"entity": "Employee", "as": "Manager"}
+    ]}}}]"#
+);
+
+// the shipping address is embedded and cheaper than the billing address, listed first
+const NAMED_RELATIONSHIPS_ERD: &str = r#"{
+    "Order": {
+        "Address": [
+            {
+                "name": "billingAddress",
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "addresses",
+                    "localKey": "billingAddressId",
+                    "foreignKey": "_id",
+                    "projection": []
+                }
+            },
+            {
+                "name": "shippingAddress",
+                "relationshipType": "one-to-one",
+                "constraint": {
+                    "constraintType": "embedded",
+                    "targetPath": "shippingAddress",
+                    "projection": []
+                }
+            }
+        ]
+    },
+    "Address": {}
+}"#;
+
+test_join_rewrite!(
+    non_default_relationship_by_name,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$lookup": {"from": "addresses", "localField": "Order.billingAddressId", "foreignField": "_id", "as": "BillingAddress"}}, {"$unwind": {"path": "$BillingAddress", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = NAMED_RELATIONSHIPS_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": [
+        {"entity": "Address", "as": "BillingAddress", "via": "billingAddress"}
+    ]}}}]"#
+);
+
+test_join_rewrite!(
+    default_relationship_is_the_cheapest,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$unwind": {"path": "$Order.shippingAddress", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Address": "$Order.shippingAddress"}}]"#,
+    erd = NAMED_RELATIONSHIPS_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Address"]}}}]"#
+);
+
+// a shipment belongs to the order with the same tenant and order number
+const COMPOSITE_KEYS_ERD: &str = r#"{
+    "Order": {
+        "Shipment": {
+            "relationshipType": "one-to-one",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "shop",
+                "collection": "shipments",
+                "keys": [
+                    {"localKey": "tenantId", "foreignKey": "tenant"},
+                    {"localKey": "orderNo", "foreignKey": "order.number"}
+                ],
+                "projection": []
+            }
+        }
+    },
+    "Shipment": {}
+}"#;
+
+test_join_rewrite!(
+    composite_keys_inner_join,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$lookup": {"from": "shipments", "let": {"babelfish_Order_tenantId": "$Order.tenantId", "babelfish_Order_orderNo": "$Order.orderNo"}, "pipeline": [{"$match": {"$expr": {"$and": [{"$eq": ["$tenant", "$$babelfish_Order_tenantId"]}, {"$eq": ["$order.number", "$$babelfish_Order_orderNo"]}]}}}], "as": "Shipment"}}, {"$unwind": {"path": "$Shipment", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = COMPOSITE_KEYS_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Shipment"]}}}]"#
+);
+
+test_join_rewrite!(
+    composite_keys_left_join,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$lookup": {"from": "shipments", "let": {"babelfish_Order_tenantId": "$Order.tenantId", "babelfish_Order_orderNo": "$Order.orderNo"}, "pipeline": [{"$match": {"$expr": {"$and": [{"$eq": ["$tenant", "$$babelfish_Order_tenantId"]}, {"$eq": ["$order.number", "$$babelfish_Order_orderNo"]}]}}}], "as": "Shipment"}}, {"$unwind": {"path": "$Shipment", "preserveNullAndEmptyArrays": true}}]"#,
+    erd = COMPOSITE_KEYS_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": [{"$left": {"args": ["Shipment"]}}]}}}]"#
+);
+
+// students and courses are related through one enrollment document per pair
+const JUNCTION_ERD: &str = r#"{
+    "Student": {
+        "Course": {
+            "relationshipType": "many-to-many",
+            "constraint": {
+                "constraintType": "foreign",
+                "db": "school",
+                "collection": "courses",
+                "localKey": "_id",
+                "foreignKey": "_id",
+                "junction": {
+                    "db": "school",
+                    "collection": "enrollments",
+                    "localKey": "studentId",
+                    "foreignKey": "courseId"
+                },
+                "projection": []
+            }
+        }
+    },
+    "Course": {}
+}"#;
+
+test_join_rewrite!(
+    junction_inner_join,
+    expected = r#"[{"$project": {"Student": "$$ROOT", "_id": false}}, {"$lookup": {"from": "enrollments", "localField": "Student._id", "foreignField": "studentId", "as": "Course"}}, {"$unwind": {"path": "$Course", "preserveNullAndEmptyArrays": false}}, {"$lookup": {"from": "courses", "localField": "Course.courseId", "foreignField": "_id", "as": "Course"}}, {"$unwind": {"path": "$Course", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = JUNCTION_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Student", "args": ["Course"]}}}]"#
+);
+
+test_join_rewrite!(
+    left_join_junction_guards_missing_key,
+    expected = r#"[{"$project": {"Student": "$$ROOT", "_id": false}}, {"$lookup": {"from": "enrollments", "localField": "Student._id", "foreignField": "studentId", "as": "Course"}}, {"$unwind": {"path": "$Course", "preserveNullAndEmptyArrays": true}}, {"$lookup": {"from": "courses", "localField": "Course.courseId", "foreignField": "_id", "let": {"babelfish_Course_courseId": "$Course.courseId"}, "pipeline": [{"$match": {"$expr": {"$not": [{"$in": [{"$type": ["$$babelfish_Course_courseId"]}, ["missing", "null"]]}]}}}], "as": "Course"}}, {"$unwind": {"path": "$Course", "preserveNullAndEmptyArrays": true}}]"#,
+    erd = JUNCTION_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Student", "args": [{"$left": {"args": ["Course"]}}]}}}]"#
+);
+
+// the bucket relationship of the README, which leaves out the projection
+const BUCKET_ERD: &str = r#"{
+    "Sensor": {
+        "Reading": {
+            "relationshipType": "many-to-one",
+            "constraint": {
+                "constraintType": "bucket",
+                "db": "iot",
+                "collection": "reading_buckets",
+                "localKey": "_id",
+                "foreignKey": "sensorId",
+                "targetPath": "readings",
+                "bucketBounds": {"min": "start", "max": "end"}
+            }
+        }
+    },
+    "Reading": {}
+}"#;
+
+test_join_rewrite!(
+    bucket_inner_join,
+    expected = r#"[{"$project": {"Sensor": "$$ROOT", "_id": false}}, {"$lookup": {"from": "reading_buckets", "localField": "Sensor._id", "foreignField": "sensorId", "as": "Reading"}}, {"$unwind": {"path": "$Reading", "preserveNullAndEmptyArrays": false}}, {"$unwind": {"path": "$Reading.readings", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Reading": "$Reading.readings"}}]"#,
+    erd = BUCKET_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Sensor", "args": ["Reading"]}}}]"#
+);
+
+// orders embed an eventually consistent copy of their customer, which is cheaper than the
+// customer itself, and of their product, which has no other relationship
+const CONSISTENCY_ERD: &str = r#"{
+    "Order": {
+        "Customer": [
+            {
+                "name": "customerCopy",
+                "relationshipType": "many-to-one",
+                "consistency": "eventual",
+                "constraint": {"constraintType": "embedded", "targetPath": "customer"}
+            },
+            {
+                "name": "customer",
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "customers",
+                    "localKey": "customerId",
+                    "foreignKey": "_id"
+                }
+            }
+        ],
+        "Product": {
+            "relationshipType": "many-to-one",
+            "consistency": "eventual",
+            "constraint": {"constraintType": "embedded", "targetPath": "product"}
+        }
+    },
+    "Customer": {},
+    "Product": {}
+}"#;
+
+test_join_rewrite!(
+    no_read_concern_takes_cheapest_relationship,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$unwind": {"path": "$Order.customer", "preserveNullAndEmptyArrays": false}}, {"$addFields": {"Customer": "$Order.customer"}}]"#,
+    erd = CONSISTENCY_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Customer"]}}}]"#
+);
+
+test_join_rewrite!(
+    read_concern_passes_over_weaker_relationship,
+    expected = r#"[{"$project": {"Order": "$$ROOT", "_id": false}}, {"$lookup": {"from": "customers", "localField": "Order.customerId", "foreignField": "_id", "as": "Customer"}}, {"$unwind": {"path": "$Customer", "preserveNullAndEmptyArrays": false}}]"#,
+    erd = CONSISTENCY_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Customer"], "readConcern": "strong"}}}]"#
+);
+
+test_join_rewrite_error!(
+    read_concern_without_consistent_path,
+    expected = "No path to entity Product with strong consistency",
+    erd = CONSISTENCY_ERD,
+    options = crate::join_rewrite::JoinOptions::default(),
+    input = r#"[{"$join": {"$inner": {"root": "Order", "args": ["Product"], "readConcern": "strong"}}}]"#
+);
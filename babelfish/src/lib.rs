@@ -1,5 +1,35 @@
 pub mod conjure_rewrite;
+pub mod desugar;
+#[cfg(test)]
+mod desugar_tests;
 pub mod erd;
 pub mod erd_graph;
+#[cfg(test)]
+mod erd_graph_tests;
+pub mod erd_validation;
+#[cfg(test)]
+mod erd_validation_tests;
+pub mod explain;
+#[cfg(test)]
+mod explain_tests;
+pub mod graph_export;
+#[cfg(test)]
+mod graph_export_tests;
 pub mod join_rewrite;
+#[cfg(test)]
+mod join_rewrite_tests;
+pub mod lookup_normalize;
+#[cfg(test)]
+mod lookup_normalize_tests;
 pub mod match_movement_rewrite;
+#[cfg(test)]
+mod match_movement_tests;
+pub mod materialize;
+#[cfg(test)]
+mod materialize_tests;
+pub mod migration;
+#[cfg(test)]
+mod migration_tests;
+pub mod passes;
+#[cfg(test)]
+mod passes_tests;
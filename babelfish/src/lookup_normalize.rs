@@ -0,0 +1,178 @@
+use ast::{
+    definitions::{
+        ConciseSubqueryLookup, EqualityLookup, Expression, Lookup, MatchExpr, MatchExpression,
+        MatchStage, Pipeline, Ref, Stage, SubqueryLookup, UntaggedOperator, UntaggedOperatorName,
+        visitor::Visitor,
+    },
+    map, set,
+};
+
+/// LOCAL_FIELD_VAR is the `let` variable that holds the local field of an equality lookup once
+/// it is converted to a subquery lookup. Only subquery lookups binding it are converted back, so
+/// subquery lookups written by users keep their `$expr` equality semantics.
+pub const LOCAL_FIELD_VAR: &str = "babelfish_local_field";
+
+/// equality_to_subquery converts every equality lookup into the equivalent subquery lookup, so
+/// that predicates can be moved into and out of its pipeline:
+///
+/// `{$lookup: {from, localField: "a", foreignField: "b", as}}` becomes
+/// `{$lookup: {from, let: {babelfish_local_field: "$a"}, pipeline: [{$match: {$expr: {$eq:
+/// ["$b", "$$babelfish_local_field"]}}}], as}}`.
+pub fn equality_to_subquery(pipeline: Pipeline) -> Pipeline {
+    EqualityToSubquery.visit_pipeline(pipeline)
+}
+
+/// subquery_to_equality converts the subquery lookups created by equality_to_subquery back to
+/// equality lookups if nothing was moved into their pipeline, and to concise subquery lookups
+/// otherwise, so that the server can still use the equality fast path.
+pub fn subquery_to_equality(pipeline: Pipeline) -> Pipeline {
+    SubqueryToEquality.visit_pipeline(pipeline)
+}
+
+struct EqualityToSubquery;
+
+impl Visitor for EqualityToSubquery {
+    fn visit_stage(&mut self, stage: Stage) -> Stage {
+        match stage.walk(self) {
+            Stage::Lookup(Lookup::Equality(EqualityLookup {
+                from,
+                local_field,
+                foreign_field,
+                as_var,
+            })) => Stage::Lookup(Lookup::Subquery(SubqueryLookup {
+                from: Some(from),
+                let_body: Some(map! {
+                    LOCAL_FIELD_VAR.to_string() => Expression::Ref(Ref::FieldRef(local_field)),
+                }),
+                pipeline: Pipeline {
+                    pipeline: vec![Stage::Match(MatchStage {
+                        expr: vec![MatchExpression::Expr(MatchExpr {
+                            expr: Box::new(Expression::UntaggedOperator(UntaggedOperator {
+                                op: UntaggedOperatorName::Eq,
+                                args: vec![
+                                    Expression::Ref(Ref::FieldRef(foreign_field)),
+                                    Expression::Ref(Ref::VariableRef(LOCAL_FIELD_VAR.to_string())),
+                                ],
+                            })),
+                        })],
+                        numbering: None,
+                    })],
+                },
+                as_var,
+                is_left_join: None,
+            })),
+            stage => stage,
+        }
+    }
+}
+
+struct SubqueryToEquality;
+
+impl Visitor for SubqueryToEquality {
+    fn visit_stage(&mut self, stage: Stage) -> Stage {
+        match stage.walk(self) {
+            Stage::Lookup(Lookup::Subquery(lookup)) => Stage::Lookup(to_equality(lookup)),
+            stage => stage,
+        }
+    }
+}
+
+// key_foreign_field returns the foreign field if the expression is the key equality created by
+// equality_to_subquery.
+fn key_foreign_field(expr: &Expression) -> Option<String> {
+    let Expression::UntaggedOperator(UntaggedOperator {
+        op: UntaggedOperatorName::Eq,
+        args,
+    }) = expr
+    else {
+        return None;
+    };
+    match args.as_slice() {
+        [
+            Expression::Ref(Ref::FieldRef(field)),
+            Expression::Ref(Ref::VariableRef(var)),
+        ]
+        | [
+            Expression::Ref(Ref::VariableRef(var)),
+            Expression::Ref(Ref::FieldRef(field)),
+        ] if var == LOCAL_FIELD_VAR => Some(field.clone()),
+        _ => None,
+    }
+}
+
+// take_key_equality removes the key equality from the leading $match of the pipeline, which may
+// have been coalesced with moved predicates into an $and, and returns its foreign field.
+fn take_key_equality(pipeline: &mut Pipeline) -> Option<String> {
+    let Some(Stage::Match(MatchStage { expr, .. })) = pipeline.pipeline.first_mut() else {
+        return None;
+    };
+    let [MatchExpression::Expr(MatchExpr { expr })] = expr.as_mut_slice() else {
+        return None;
+    };
+    if let Some(foreign_field) = key_foreign_field(expr) {
+        pipeline.pipeline.remove(0);
+        return Some(foreign_field);
+    }
+    let Expression::UntaggedOperator(UntaggedOperator {
+        op: UntaggedOperatorName::And,
+        args,
+    }) = expr.as_mut()
+    else {
+        return None;
+    };
+    let position = args
+        .iter()
+        .position(|arg| key_foreign_field(arg).is_some())?;
+    let foreign_field = key_foreign_field(&args.remove(position));
+    match args.len() {
+        0 => {
+            pipeline.pipeline.remove(0);
+        }
+        1 => **expr = args.remove(0),
+        _ => {}
+    }
+    foreign_field
+}
+
+fn to_equality(mut lookup: SubqueryLookup) -> Lookup {
+    let local_field = match (&lookup.from, &lookup.let_body) {
+        (Some(_), Some(let_body)) => match let_body.get(LOCAL_FIELD_VAR) {
+            Some(Expression::Ref(Ref::FieldRef(local_field))) => local_field.clone(),
+            _ => return Lookup::Subquery(lookup),
+        },
+        _ => return Lookup::Subquery(lookup),
+    };
+    let mut pipeline = lookup.pipeline.clone();
+    let Some(foreign_field) = take_key_equality(&mut pipeline) else {
+        return Lookup::Subquery(lookup);
+    };
+    let mut let_body = lookup.let_body.take().unwrap_or_default();
+    // moved predicates may still refer to the local field through the variable
+    if !pipeline
+        .variable_uses()
+        .prefix_overlap(&set! {LOCAL_FIELD_VAR.to_string()})
+    {
+        let_body.remove(LOCAL_FIELD_VAR);
+    }
+    let from = lookup.from.unwrap();
+    if pipeline.is_empty() && let_body.is_empty() {
+        return Lookup::Equality(EqualityLookup {
+            from,
+            local_field,
+            foreign_field,
+            as_var: lookup.as_var,
+        });
+    }
+    Lookup::ConciseSubquery(ConciseSubqueryLookup {
+        from: Some(from),
+        local_field,
+        foreign_field,
+        let_body: if let_body.is_empty() {
+            None
+        } else {
+            Some(let_body)
+        },
+        pipeline,
+        as_var: lookup.as_var,
+    })
+}
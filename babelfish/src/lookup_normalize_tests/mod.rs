@@ -0,0 +1,130 @@
+macro_rules! test_lookup_normalize {
+    ($func_name:ident, $normalize:path, expected = $expected:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use ast::definitions::Pipeline;
+
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let result = $normalize(input);
+            assert_eq!(expected, serde_json::to_value(result).unwrap());
+        }
+    };
+}
+
+macro_rules! test_lookup_round_trip {
+    ($func_name:ident, $first:path, $second:path, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use ast::definitions::Pipeline;
+
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($input).unwrap();
+            let result = $second($first(input));
+            assert_eq!(expected, serde_json::to_value(result).unwrap());
+        }
+    };
+}
+
+use crate::lookup_normalize::{equality_to_subquery, subquery_to_equality};
+
+test_lookup_normalize!(
+    equality_to_subquery_binds_local_field,
+    equality_to_subquery,
+    expected = r#"[{"$lookup": {"from": "orders", "let": {"babelfish_local_field": "$_id"}, "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$babelfish_local_field"]}}}], "as": "orders"}}]"#,
+    input = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "orders"}}]"#
+);
+
+test_lookup_normalize!(
+    equality_to_subquery_in_sub_pipeline,
+    equality_to_subquery,
+    expected = r#"[{"$lookup": {"from": "orders", "pipeline": [{"$lookup": {"from": "items", "let": {"babelfish_local_field": "$itemId"}, "pipeline": [{"$match": {"$expr": {"$eq": ["$_id", "$$babelfish_local_field"]}}}], "as": "item"}}], "as": "orders"}}]"#,
+    input = r#"[{"$lookup": {"from": "orders", "pipeline": [
+        {"$lookup": {"from": "items", "localField": "itemId", "foreignField": "_id", "as": "item"}}
+    ], "as": "orders"}}]"#
+);
+
+test_lookup_normalize!(
+    subquery_to_equality_key_only,
+    subquery_to_equality,
+    expected = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "orders"}}]"#,
+    input = r#"[{"$lookup": {
+        "from": "orders",
+        "let": {"babelfish_local_field": "$_id"},
+        "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$babelfish_local_field"]}}}],
+        "as": "orders"
+    }}]"#
+);
+
+test_lookup_normalize!(
+    subquery_to_equality_keeps_moved_predicates,
+    subquery_to_equality,
+    expected = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "pipeline": [{"$match": {"$expr": {"$gt": ["$total", 100]}}}], "as": "orders"}}]"#,
+    input = r#"[{"$lookup": {
+        "from": "orders",
+        "let": {"babelfish_local_field": "$_id"},
+        "pipeline": [{"$match": {"$expr": {"$and": [
+            {"$eq": ["$customerId", "$$babelfish_local_field"]},
+            {"$gt": ["$total", 100]}
+        ]}}}],
+        "as": "orders"
+    }}]"#
+);
+
+test_lookup_normalize!(
+    subquery_to_equality_keeps_used_local_field_var,
+    subquery_to_equality,
+    expected = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "let": {"babelfish_local_field": "$_id"}, "pipeline": [{"$match": {"$expr": {"$ne": ["$referrerId", "$$babelfish_local_field"]}}}], "as": "orders"}}]"#,
+    input = r#"[{"$lookup": {
+        "from": "orders",
+        "let": {"babelfish_local_field": "$_id"},
+        "pipeline": [
+            {"$match": {"$expr": {"$eq": ["$customerId", "$$babelfish_local_field"]}}},
+            {"$match": {"$expr": {"$ne": ["$referrerId", "$$babelfish_local_field"]}}}
+        ],
+        "as": "orders"
+    }}]"#
+);
+
+test_lookup_normalize!(
+    subquery_to_equality_leaves_user_subquery,
+    subquery_to_equality,
+    expected = r#"[{"$lookup": {"from": "orders", "let": {"id": "$_id"}, "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}], "as": "orders"}}]"#,
+    input = r#"[{"$lookup": {
+        "from": "orders",
+        "let": {"id": "$_id"},
+        "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}],
+        "as": "orders"
+    }}]"#
+);
+
+test_lookup_round_trip!(
+    round_trip_equality,
+    equality_to_subquery,
+    subquery_to_equality,
+    input = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "orders"}}]"#
+);
+
+test_lookup_round_trip!(
+    round_trip_user_subquery,
+    equality_to_subquery,
+    subquery_to_equality,
+    input = r#"[{"$lookup": {
+        "from": "orders",
+        "let": {"id": "$_id"},
+        "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$id"]}}}],
+        "as": "orders"
+    }}]"#
+);
+
+test_lookup_round_trip!(
+    round_trip_local_field_subquery,
+    subquery_to_equality,
+    equality_to_subquery,
+    input = r#"[{"$lookup": {
+        "from": "orders",
+        "let": {"babelfish_local_field": "$_id"},
+        "pipeline": [{"$match": {"$expr": {"$eq": ["$customerId", "$$babelfish_local_field"]}}}],
+        "as": "orders"
+    }}]"#
+);
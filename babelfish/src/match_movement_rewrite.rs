@@ -1,11 +1,17 @@
+use crate::{
+    explain::{MatchMovement, MovementKind, describe_stage},
+    lookup_normalize,
+};
 use ast::{
     definitions::{
-        Expression, LiteralValue, Lookup, MatchExpr, MatchExpression, MatchStage, Pipeline, Stage,
-        UntaggedOperator, UntaggedOperatorName, visitor::Visitor,
+        Expression, LiteralValue, Lookup, MatchExpr, MatchExpression, MatchStage, Pipeline, Ref,
+        Stage, SubqueryLookup, UntaggedOperator, UntaggedOperatorName, Unwind, UnwindExpr,
+        visitor::Visitor,
     },
-    set,
+    map, set,
 };
-use std::collections::HashSet;
+use linked_hash_map::LinkedHashMap;
+use std::collections::{HashMap, HashSet};
 
 pub struct SubpipelineFlatten;
 
@@ -75,7 +81,9 @@ impl Visitor for MatchSplitter {
     }
 }
 
-struct MatchMover;
+struct MatchMover {
+    movements: Vec<MatchMovement>,
+}
 
 // TODO: Support moving matches out of subpipelines, probably easiest to do as a separate pass with
 // a changed output that we can then iterator to fix point with MatchMover
@@ -88,8 +96,10 @@ impl Visitor for MatchMover {
                 *numbering = Some(i);
             }
         }
-        let len = pipeline.pipeline.len();
-        let mut i = len - 1;
+        // hoisting may leave a $lookup subpipeline empty
+        let Some(mut i) = pipeline.pipeline.len().checked_sub(1) else {
+            return pipeline;
+        };
         let mut visited = HashSet::new();
         // we never move the first stage
         while i > 0 {
@@ -101,7 +111,7 @@ impl Visitor for MatchMover {
                     continue;
                 }
                 visited.insert(numbering.unwrap());
-                if !move_match(expr, &mut pipeline, i, numbering) {
+                if !move_match(expr, &mut pipeline, i, numbering, &mut self.movements) {
                     i -= 1;
                 }
             } else {
@@ -119,13 +129,26 @@ fn move_match(
     pipeline: &mut Pipeline,
     i: usize,
     numbering: Option<usize>,
+    movements: &mut Vec<MatchMovement>,
 ) -> bool {
+    // past describes the stages the match has moved past so far
+    let mut past = Vec::new();
     macro_rules! terminal_case {
         ($expr:expr, $idx:expr, $moved:expr) => {{
-            pipeline.pipeline[$idx] = Stage::Match(MatchStage {
-                expr: $expr,
-                numbering,
-            });
+            let expr = $expr;
+            if $moved {
+                if let Some(MatchExpression::Expr(MatchExpr { expr })) = expr.first() {
+                    movements.push(MatchMovement {
+                        kind: MovementKind::Moved,
+                        predicate: *expr.clone(),
+                        from: i,
+                        to: $idx,
+                        past,
+                        lookup: None,
+                    });
+                }
+            }
+            pipeline.pipeline[$idx] = Stage::Match(MatchStage { expr, numbering });
             return $moved;
         }};
     }
@@ -152,9 +175,19 @@ fn move_match(
                 moved
             );
         }
-        let defines = swap_stage.defines();
+        // $$ROOT and $$CURRENT are the whole document, which only stages that filter or order
+        // documents leave as it is
+        let uses_document = expr
+            .variable_uses()
+            .prefix_overlap(&set! {"ROOT".to_string(), "CURRENT".to_string()});
+        let defines = if uses_document && !matches!(swap_stage, Stage::Match(_) | Stage::Sort(_)) {
+            None
+        } else {
+            swap_stage.defines_for_uses(&uses)
+        };
         if let Some(defines) = defines {
             expr = expr.substitute(defines);
+            past.push(describe_stage(swap_stage));
             let swap_stage = std::mem::take(pipeline.pipeline.get_mut(j - 1).unwrap());
             pipeline.pipeline[j] = swap_stage;
             moved = true;
@@ -179,6 +212,7 @@ fn move_match(
 
 struct SubpipelineMatchMover {
     changed: bool,
+    movements: Vec<MatchMovement>,
 }
 
 impl Visitor for SubpipelineMatchMover {
@@ -214,7 +248,7 @@ impl Visitor for SubpipelineMatchMover {
                         match sub_stage {
                             Stage::Match(MatchStage { expr, numbering: _ }) => {
                                 let MatchExpression::Expr(MatchExpr { expr }) =
-                                    expr.into_iter().next().unwrap()
+                                    expr.iter_mut().next().unwrap()
                                 else {
                                     // TODO: perhaps handle other types of match stages, it
                                     // basically won't work, however.
@@ -249,6 +283,14 @@ impl Visitor for SubpipelineMatchMover {
                                     );
                                 }
                                 subquery.pipeline.pipeline.remove(j);
+                                self.movements.push(MatchMovement {
+                                    kind: MovementKind::Hoisted,
+                                    predicate: expr.clone(),
+                                    from: j,
+                                    to: i,
+                                    past: Vec::new(),
+                                    lookup: Some(format!("$lookup {}", subquery.as_var)),
+                                });
                                 // we do not increment j because we removed the element
                                 pipeline.pipeline.insert(
                                     i,
@@ -279,6 +321,135 @@ impl Visitor for SubpipelineMatchMover {
     }
 }
 
+/// LET_VARIABLE_PREFIX prefixes the `let` variables that LookupMatchPusher binds to the fields of
+/// the parent pipeline.
+pub const LET_VARIABLE_PREFIX: &str = "babelfish_";
+
+// LookupMatchPusher is the reverse of SubpipelineMatchMover: it pushes a $match that directly
+// follows a $lookup and the $unwind of its output into the $lookup subpipeline, if the $match
+// references the looked up entity. Fields of the looked up entity become fields of the
+// subpipeline, and every other field is passed in through the let body. Predicates are only
+// pushed past non-preserving $unwinds, since filtering in the subpipeline of a left join would
+// keep the rows the $match removes.
+struct LookupMatchPusher {
+    changed: bool,
+    movements: Vec<MatchMovement>,
+}
+
+impl Visitor for LookupMatchPusher {
+    fn visit_pipeline(&mut self, pipeline: Pipeline) -> Pipeline {
+        let mut out: Vec<Stage> = Vec::new();
+        for stage in pipeline.pipeline.into_iter() {
+            let stage = stage.walk(self);
+            let position = out.len();
+            if let (Stage::Match(MatchStage { expr, .. }), [.., lookup, unwind]) =
+                (&stage, out.as_mut_slice())
+                && let (Stage::Lookup(Lookup::Subquery(lookup)), Stage::Unwind(unwind)) =
+                    (lookup, &*unwind)
+                && let [MatchExpression::Expr(MatchExpr { expr })] = expr.as_slice()
+                && unwinds_output(unwind, &lookup.as_var)
+                && let Some(pushed) = push_into_lookup(lookup, expr)
+            {
+                self.changed = true;
+                self.movements.push(MatchMovement {
+                    kind: MovementKind::Pushed,
+                    predicate: pushed.clone(),
+                    from: position,
+                    to: lookup.pipeline.pipeline.len(),
+                    past: vec![describe_stage(&Stage::Unwind(unwind.clone()))],
+                    lookup: Some(format!("$lookup {}", lookup.as_var)),
+                });
+                // the pushed $match is appended, MatchMover moves it earlier in the subpipeline
+                lookup.pipeline.pipeline.push(Stage::Match(MatchStage {
+                    expr: vec![MatchExpression::Expr(MatchExpr {
+                        expr: Box::new(pushed),
+                    })],
+                    numbering: None,
+                }));
+                continue;
+            }
+            out.push(stage);
+        }
+        Pipeline { pipeline: out }
+    }
+}
+
+// unwinds_output returns true if the $unwind unwinds the whole output of a $lookup into as_var
+// without preserving empty arrays or adding an index, so that it acts as an inner join.
+fn unwinds_output(unwind: &Unwind, as_var: &str) -> bool {
+    let path = match unwind {
+        Unwind::FieldPath(path) => path,
+        Unwind::Document(UnwindExpr {
+            path,
+            include_array_index: None,
+            preserve_null_and_empty_arrays: None | Some(false),
+        }) => path.as_ref(),
+        Unwind::Document(_) => return false,
+    };
+    matches!(path, Expression::Ref(Ref::FieldRef(field)) if field == as_var)
+}
+
+// push_into_lookup returns the predicate rewritten for the subpipeline of the lookup, binding the
+// let variables it needs, or None if the predicate cannot be pushed into the lookup.
+fn push_into_lookup(lookup: &mut SubqueryLookup, expr: &Expression) -> Option<Expression> {
+    if lookup.is_left_join == Some(true) {
+        return None;
+    }
+    let as_var = lookup.as_var.clone();
+    let uses = expr.uses();
+    if !uses.prefix_overlap(&set! {as_var.clone()}) {
+        return None;
+    }
+    // $$ROOT and $$CURRENT refer to the looked up document in the subpipeline, and the let
+    // variables of the lookup shadow variables of the parent pipeline.
+    let mut shadowed: HashSet<String> = set! {"ROOT".to_string(), "CURRENT".to_string()};
+    if let Some(let_body) = &lookup.let_body {
+        shadowed.extend(let_body.keys().cloned());
+    }
+    if expr.variable_uses().prefix_overlap(&shadowed) {
+        return None;
+    }
+    let let_body = lookup.let_body.get_or_insert_with(LinkedHashMap::new);
+    let mut theta: HashMap<String, Expression> =
+        map! {as_var.clone() => Expression::Ref(Ref::VariableRef("ROOT".to_string()))};
+    let prefix = format!("{}.", as_var);
+    for field in uses {
+        if field == as_var || field.starts_with(prefix.as_str()) {
+            continue;
+        }
+        let var = let_variable(let_body, &field);
+        theta.insert(field, Expression::Ref(Ref::VariableRef(var)));
+    }
+    Some(expr.clone().substitute(theta))
+}
+
+/// let_variable returns the let variable bound to field, binding a new one if there is none.
+pub fn let_variable(let_body: &mut LinkedHashMap<String, Expression>, field: &str) -> String {
+    if let Some((var, _)) = let_body
+        .iter()
+        .find(|(_, value)| matches!(value, Expression::Ref(Ref::FieldRef(f)) if f == field))
+    {
+        return var.clone();
+    }
+    // variable names may only contain letters, digits and underscores
+    let name = field
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let base = format!("{}{}", LET_VARIABLE_PREFIX, name);
+    let mut var = base.clone();
+    let mut i = 1;
+    while let_body.contains_key(&var) {
+        var = format!("{}_{}", base, i);
+        i += 1;
+    }
+    let_body.insert(
+        var.clone(),
+        Expression::Ref(Ref::FieldRef(field.to_string())),
+    );
+    var
+}
+
 struct MatchCoalescer;
 
 impl Visitor for MatchCoalescer {
@@ -294,7 +465,10 @@ impl Visitor for MatchCoalescer {
                     else {
                         todo!("handle other types of match stages");
                     };
-                    current_match.push(*expr);
+                    // simplification leaves predicates that always hold as true
+                    if *expr != Expression::Literal(LiteralValue::Boolean(true)) {
+                        current_match.push(*expr);
+                    }
                 }
                 stage => {
                     if !current_match.is_empty() {
@@ -334,18 +508,67 @@ impl Visitor for MatchCoalescer {
 }
 
 pub fn rewrite_match_move(pipeline: Pipeline) -> Pipeline {
+    rewrite_match_move_with_explain(pipeline).0
+}
+
+/// rewrite_match_move_with_explain performs match movement and also returns every movement
+/// that was made, in the order they were made.
+///
+/// Equality lookups are converted to subquery lookups for the duration of the movement, so that
+/// they are treated like any other subquery lookup, and converted back afterwards. Once moved,
+/// predicates are simplified, since substituting through definitions often leaves constant
+/// parts, and predicates that always hold are dropped. Only movements visible in the output are
+/// returned, see visible_movement.
+pub fn rewrite_match_move_with_explain(pipeline: Pipeline) -> (Pipeline, Vec<MatchMovement>) {
+    let mut movements = Vec::new();
+    let pipeline = lookup_normalize::equality_to_subquery(pipeline);
     let mut visitor = MatchSplitter;
     let mut pipeline = visitor.visit_pipeline(pipeline);
     let mut visitor = SubpipelineFlatten;
     pipeline = visitor.visit_pipeline(pipeline);
     let mut changed = true;
     while changed {
-        let mut visitor = MatchMover;
+        let mut visitor = MatchMover {
+            movements: Vec::new(),
+        };
         pipeline = visitor.visit_pipeline(pipeline);
-        let mut visitor = SubpipelineMatchMover { changed: false };
+        movements.extend(visitor.movements);
+        let mut visitor = SubpipelineMatchMover {
+            changed: false,
+            movements: Vec::new(),
+        };
         pipeline = visitor.visit_pipeline(pipeline);
+        movements.extend(visitor.movements);
         changed = visitor.changed;
+        let mut visitor = LookupMatchPusher {
+            changed: false,
+            movements: Vec::new(),
+        };
+        pipeline = visitor.visit_pipeline(pipeline);
+        movements.extend(visitor.movements);
+        changed |= visitor.changed;
     }
+    pipeline = pipeline.simplify_matches();
     let mut visitor = MatchCoalescer;
-    visitor.visit_pipeline(pipeline)
+    pipeline = visitor.visit_pipeline(pipeline);
+    let movements = movements.into_iter().filter_map(visible_movement).collect();
+    (lookup_normalize::subquery_to_equality(pipeline), movements)
+}
+
+// visible_movement returns the movement as it shows in the output pipeline, or None if it does
+// not show. $match stages are merged by MatchCoalescer, so moving past one is not reported, and
+// the $match lookup normalization adds to equality lookups is gone once they are converted back.
+fn visible_movement(mut movement: MatchMovement) -> Option<MatchMovement> {
+    if movement
+        .predicate
+        .variable_uses()
+        .prefix_overlap(&set! {lookup_normalize::LOCAL_FIELD_VAR.to_string()})
+    {
+        return None;
+    }
+    movement.past.retain(|stage| stage != "$match");
+    if movement.kind == MovementKind::Moved && movement.past.is_empty() {
+        return None;
+    }
+    Some(movement)
 }
@@ -0,0 +1,104 @@
+macro_rules! test_match_movement {
+    ($func_name:ident, expected = $expected:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::match_movement_rewrite::rewrite_match_move;
+            use ast::definitions::Pipeline;
+
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let result = rewrite_match_move(input);
+            assert_eq!(expected, serde_json::to_value(result).unwrap());
+        }
+    };
+}
+
+test_match_movement!(
+    push_foreign_only_predicate,
+    expected = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "pipeline": [{"$match": {"$expr": {"$eq": ["$status", "shipped"]}}}], "as": "order"}}, {"$unwind": "$order"}]"#,
+    input = r#"[
+        {"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "order"}},
+        {"$unwind": "$order"},
+        {"$match": {"$expr": {"$eq": ["$order.status", "shipped"]}}}
+    ]"#
+);
+
+test_match_movement!(
+    push_predicate_on_both_sides_binds_let_variables,
+    expected = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "let": {"babelfish_creditLimit": "$creditLimit"}, "pipeline": [{"$match": {"$expr": {"$gt": ["$total", "$$babelfish_creditLimit"]}}}], "as": "order"}}, {"$unwind": "$order"}]"#,
+    input = r#"[
+        {"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "order"}},
+        {"$unwind": "$order"},
+        {"$match": {"$expr": {"$gt": ["$order.total", "$creditLimit"]}}}
+    ]"#
+);
+
+test_match_movement!(
+    split_conjunction_moves_each_conjunct,
+    expected = r#"[{"$match": {"$expr": {"$and": [{"$eq": ["$country", "NZ"]}]}}}, {"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "let": {"babelfish_vip": "$vip"}, "pipeline": [{"$match": {"$expr": {"$and": [{"$eq": ["$status", "shipped"]}, {"$or": [{"$eq": ["$total", 0]}, {"$eq": ["$$babelfish_vip", true]}]}]}}}], "as": "order"}}, {"$unwind": "$order"}]"#,
+    input = r#"[
+        {"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "order"}},
+        {"$unwind": "$order"},
+        {"$match": {"$expr": {"$and": [
+            {"$eq": ["$order.status", "shipped"]},
+            {"$eq": ["$country", "NZ"]},
+            {"$or": [{"$eq": ["$order.total", 0]}, {"$eq": ["$vip", true]}]}
+        ]}}}
+    ]"#
+);
+
+test_match_movement!(
+    left_join_predicate_is_not_pushed,
+    expected = r#"[{"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "order"}}, {"$unwind": {"path": "$order", "preserveNullAndEmptyArrays": true}}, {"$match": {"$expr": {"$and": [{"$eq": ["$order.status", "shipped"]}]}}}]"#,
+    input = r#"[
+        {"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "order"}},
+        {"$unwind": {"path": "$order", "preserveNullAndEmptyArrays": true}},
+        {"$match": {"$expr": {"$eq": ["$order.status", "shipped"]}}}
+    ]"#
+);
+
+test_match_movement!(
+    root_use_stays_after_group,
+    expected = r#"[{"$group": {"_id": "$a", "n": {"$sum": 1}}}, {"$match": {"$expr": {"$and": [{"$eq": ["$$ROOT.n", 1]}]}}}]"#,
+    input = r#"[
+        {"$group": {"_id": "$a", "n": {"$sum": 1}}},
+        {"$match": {"$expr": {"$eq": ["$$ROOT.n", 1]}}}
+    ]"#
+);
+
+test_match_movement!(
+    current_use_stays_after_replace_with,
+    expected = r#"[{"$replaceWith": {"b": "$a"}}, {"$match": {"$expr": {"$and": [{"$eq": ["$$CURRENT.b", 1]}]}}}]"#,
+    input = r#"[
+        {"$replaceWith": {"b": "$a"}},
+        {"$match": {"$expr": {"$eq": ["$$CURRENT.b", 1]}}}
+    ]"#
+);
+
+test_match_movement!(
+    root_use_stays_after_inclusion_project,
+    expected = r#"[{"$project": {"a": true}}, {"$match": {"$expr": {"$and": [{"$eq": ["$$ROOT.b", null]}]}}}]"#,
+    input = r#"[
+        {"$project": {"a": 1}},
+        {"$match": {"$expr": {"$eq": ["$$ROOT.b", null]}}}
+    ]"#
+);
+
+test_match_movement!(
+    root_use_moves_past_sort,
+    expected =
+        r#"[{"$match": {"$expr": {"$and": [{"$eq": ["$$ROOT.a", 1]}]}}}, {"$sort": {"a": 1}}]"#,
+    input = r#"[
+        {"$sort": {"a": 1}},
+        {"$match": {"$expr": {"$eq": ["$$ROOT.a", 1]}}}
+    ]"#
+);
+
+test_match_movement!(
+    missing_group_key_is_null,
+    expected = r#"[{"$match": {"$expr": {"$and": [{"$eq": [{"$ifNull": ["$a", null]}, null]}]}}}, {"$group": {"_id": "$a", "n": {"$sum": 1}}}]"#,
+    input = r#"[
+        {"$group": {"_id": "$a", "n": {"$sum": 1}}},
+        {"$match": {"$expr": {"$eq": ["$_id", null]}}}
+    ]"#
+);
@@ -0,0 +1,271 @@
+use crate::{
+    erd::{ConstraintType, ErdRelationship, RelationshipType},
+    erd_graph::GetErdData,
+};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Entity {0} has records but is not in the ERD")]
+    UnknownEntity(String),
+    #[error("Records of entity {0} must be documents, found: {1}")]
+    NotADocument(String, String),
+    #[error(
+        "Entity {0} has no collection: it has no source, is not the target of a foreign relationship, and is not embedded"
+    )]
+    NoCollectionForEntity(String),
+    #[error("Entity {0} is only stored in buckets, which materialize does not build")]
+    OnlyInBuckets(String),
+    #[error("Embedded relationship {0} -> {1} is missing targetPath")]
+    MissingTargetPath(String, String),
+    #[error(
+        "Embedded relationship {0} -> {1} needs localKey and foreignKey or keys to match records"
+    )]
+    MissingEmbeddedKeys(String, String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// EntityData is the logical, normalized input: the records of every entity, keyed by entity
+/// name.
+pub type EntityData = HashMap<String, Vec<Value>>;
+
+/// Collections is the physical output: the documents of every collection, keyed by database and
+/// then collection name.
+pub type Collections = BTreeMap<String, BTreeMap<String, Vec<Value>>>;
+
+/// materialize builds the physical collections described by the ERD from normalized entity
+/// records.
+///
+/// An entity gets its own collection if it has a source, or if it is the target of a foreign
+/// relationship, in which case it is stored in that relationship's collection. Entities that are
+/// the target of an embedded relationship are also copied into every parent record whose
+/// `localKey` matches their `foreignKey`, or whose keys all match for composite `keys`, at the
+/// relationship's `targetPath`, keeping only the projected fields if the relationship has a
+/// projection. One-to-one relationships embed a single document, everything else embeds an
+/// array.
+///
+/// Projections always keep the local keys of the entity's foreign relationships, so that
+/// references out of an embedded copy can still be followed, and every document of an entity
+/// with a discriminator gets its discriminator value.
+pub fn materialize<T: GetErdData>(erd: &T, data: &EntityData) -> Result<Collections> {
+    let mut records = HashMap::new();
+    for (entity, values) in data.iter() {
+        if !erd.iter().any(|e| e == entity) {
+            return Err(Error::UnknownEntity(entity.clone()));
+        }
+        let documents = values
+            .iter()
+            .map(|value| match value {
+                Value::Object(document) => Ok(document),
+                value => Err(Error::NotADocument(entity.clone(), value.to_string())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        records.insert(entity.as_str(), documents);
+    }
+    let materializer = Materializer { erd, records };
+
+    let mut entities: Vec<&str> = materializer.records.keys().copied().collect();
+    entities.sort();
+    let mut collections = Collections::new();
+    for entity in entities {
+        for (db, collection) in materializer.namespaces(entity)? {
+            let projection = erd
+                .get_source(entity)
+                .and_then(|source| source.projection.clone())
+                .unwrap_or_default();
+            let documents = collections
+                .entry(db)
+                .or_default()
+                .entry(collection)
+                .or_default();
+            for record in materializer.records[entity].iter() {
+                documents.push(Value::Object(materializer.build_document(
+                    entity,
+                    record,
+                    &projection,
+                    &mut vec![],
+                )?));
+            }
+        }
+    }
+    Ok(collections)
+}
+
+struct Materializer<'a, T: GetErdData> {
+    erd: &'a T,
+    records: HashMap<&'a str, Vec<&'a Map<String, Value>>>,
+}
+
+impl<T: GetErdData> Materializer<'_, T> {
+    // incoming returns every relationship in the ERD that targets the entity.
+    fn incoming<'b>(&'b self, entity: &'b str) -> impl Iterator<Item = &'b ErdRelationship> + 'b {
+        self.erd.iter().flat_map(move |source| {
+            self.erd
+                .get_relationships(source)
+                .filter(move |(target, _)| *target == entity)
+                .map(|(_, relationship)| relationship)
+        })
+    }
+
+    // namespaces returns the (db, collection) pairs the entity is stored in as top level
+    // documents. This is empty for entities that are only ever embedded.
+    fn namespaces(&self, entity: &str) -> Result<BTreeSet<(String, String)>> {
+        let namespaces = self.erd.get_collections(entity);
+        let is_embedded = self
+            .erd
+            .get_source(entity)
+            .is_some_and(|source| source.target_path.is_some())
+            || self.incoming(entity).any(|relationship| {
+                relationship.constraint.constraint_type == ConstraintType::Embedded
+            });
+        if namespaces.is_empty() && !is_embedded {
+            if self.incoming(entity).any(|relationship| {
+                relationship.constraint.constraint_type == ConstraintType::Bucket
+            }) {
+                return Err(Error::OnlyInBuckets(entity.to_string()));
+            }
+            return Err(Error::NoCollectionForEntity(entity.to_string()));
+        }
+        Ok(namespaces)
+    }
+
+    // build_document projects the record and embeds the matching records of every embedded
+    // relationship of the entity. stack holds the entities being embedded into, so that cyclic
+    // embedded relationships terminate.
+    fn build_document<'b>(
+        &'b self,
+        entity: &'b str,
+        record: &Map<String, Value>,
+        projection: &[String],
+        stack: &mut Vec<&'b str>,
+    ) -> Result<Map<String, Value>> {
+        let mut document = self.project(entity, record, projection);
+        if let Some(discriminator) = self.erd.get_discriminator(entity) {
+            set_path(
+                &mut document,
+                &discriminator.field,
+                Value::String(discriminator.value.clone()),
+            );
+        }
+        stack.push(entity);
+        let mut relationships: Vec<_> = self
+            .erd
+            .get_relationships(entity)
+            .filter(|(_, relationship)| {
+                relationship.constraint.constraint_type == ConstraintType::Embedded
+            })
+            .collect();
+        relationships.sort_by_key(|(target, _)| *target);
+        for (target, relationship) in relationships {
+            if stack.contains(&target.as_str()) {
+                continue;
+            }
+            let Some(children) = self.records.get(target.as_str()) else {
+                continue;
+            };
+            let constraint = &relationship.constraint;
+            let target_path = constraint
+                .target_path
+                .as_ref()
+                .ok_or_else(|| Error::MissingTargetPath(entity.to_string(), target.clone()))?;
+            let keys = constraint.key_pairs();
+            if keys.is_empty() {
+                return Err(Error::MissingEmbeddedKeys(
+                    entity.to_string(),
+                    target.clone(),
+                ));
+            }
+            let Some(key) = keys
+                .iter()
+                .map(|pair| get_path(record, &pair.local_key).filter(|key| !key.is_null()))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            let mut embedded = Vec::new();
+            for child in children.iter().filter(|child| {
+                keys.iter()
+                    .zip(key.iter())
+                    .all(|(pair, key)| get_path(child, &pair.foreign_key) == Some(key))
+            }) {
+                embedded.push(Value::Object(self.build_document(
+                    target,
+                    child,
+                    &constraint.projection,
+                    stack,
+                )?));
+            }
+            let value = match relationship.relationship_type {
+                RelationshipType::OneToOne => match embedded.into_iter().next() {
+                    Some(value) => value,
+                    None => continue,
+                },
+                RelationshipType::ManyToOne | RelationshipType::ManyToMany => {
+                    Value::Array(embedded)
+                }
+            };
+            set_path(&mut document, target_path, value);
+        }
+        stack.pop();
+        Ok(document)
+    }
+
+    // project keeps only the projected fields of the record, plus the local keys of the
+    // entity's foreign relationships. An empty projection keeps the whole record.
+    fn project(
+        &self,
+        entity: &str,
+        record: &Map<String, Value>,
+        projection: &[String],
+    ) -> Map<String, Value> {
+        if projection.is_empty() {
+            return record.clone();
+        }
+        let local_keys = self
+            .erd
+            .get_relationships(entity)
+            .filter(|(_, relationship)| {
+                relationship.constraint.constraint_type == ConstraintType::Foreign
+            })
+            .flat_map(|(_, relationship)| relationship.constraint.key_pairs())
+            .map(|pair| pair.local_key);
+        let mut document = Map::new();
+        for field in projection.iter().cloned().chain(local_keys) {
+            if let Some(value) = get_path(record, &field) {
+                set_path(&mut document, &field, value.clone());
+            }
+        }
+        document
+    }
+}
+
+fn get_path<'a>(document: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let mut value = document.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_object()?.get(part)?;
+    }
+    Some(value)
+}
+
+fn set_path(document: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        None => {
+            document.insert(path.to_string(), value);
+        }
+        Some((head, rest)) => {
+            let child = document
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                set_path(child, rest, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+macro_rules! test_materialize {
+    ($func_name:ident, expected = $expected:expr, erd = $erd:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::{
+                erd::Erd,
+                materialize::{EntityData, materialize},
+            };
+
+            let erd: Erd = serde_json::from_str($erd).unwrap();
+            let input: EntityData = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let result = materialize(&erd, &input).unwrap();
+            assert_eq!(expected, serde_json::to_value(result).unwrap());
+        }
+    };
+}
+
+test_materialize!(
+    embedded_at_target_path,
+    expected = r#"{"shop": {"customers": [
+        {"_id": 1, "name": "Ada", "contact": {"address": {"customerId": 1, "city": "London"}}},
+        {"_id": 2, "name": "Grace"}
+    ]}}"#,
+    erd = r#"{
+        "Customer": {
+            "source": {"db": "shop", "collection": "customers"},
+            "primaryKey": "_id",
+            "relationships": {
+                "Address": {
+                    "relationshipType": "one-to-one",
+                    "constraint": {
+                        "constraintType": "embedded",
+                        "targetPath": "contact.address",
+                        "localKey": "_id",
+                        "foreignKey": "customerId",
+                        "projection": []
+                    }
+                }
+            },
+            "jsonSchema": {}
+        },
+        "Address": {
+            "source": {"db": "shop", "collection": "customers", "targetPath": "contact.address"},
+            "primaryKey": "customerId",
+            "relationships": {},
+            "jsonSchema": {}
+        }
+    }"#,
+    input = r#"{
+        "Customer": [{"_id": 1, "name": "Ada"}, {"_id": 2, "name": "Grace"}],
+        "Address": [{"customerId": 1, "city": "London"}]
+    }"#
+);
+
+test_materialize!(
+    partial_embed_keeps_projection,
+    expected = r#"{"shop": {
+        "customers": [
+            {"_id": 1, "name": "Ada", "orders": [
+                {"_id": 10, "total": 30},
+                {"_id": 11, "total": 12}
+            ]}
+        ],
+        "orders": [
+            {"_id": 10, "customerId": 1, "total": 30, "notes": "leave at door"},
+            {"_id": 11, "customerId": 1, "total": 12}
+        ]
+    }}"#,
+    erd = r#"{
+        "Customer": {
+            "source": {"db": "shop", "collection": "customers"},
+            "primaryKey": "_id",
+            "relationships": {
+                "Order": {
+                    "relationshipType": "many-to-one",
+                    "constraint": {
+                        "constraintType": "embedded",
+                        "targetPath": "orders",
+                        "localKey": "_id",
+                        "foreignKey": "customerId",
+                        "projection": ["_id", "total"]
+                    }
+                }
+            },
+            "jsonSchema": {}
+        },
+        "Order": {
+            "source": {"db": "shop", "collection": "orders"},
+            "primaryKey": "_id",
+            "relationships": {},
+            "jsonSchema": {}
+        }
+    }"#,
+    input = r#"{
+        "Customer": [{"_id": 1, "name": "Ada"}],
+        "Order": [
+            {"_id": 10, "customerId": 1, "total": 30, "notes": "leave at door"},
+            {"_id": 11, "customerId": 1, "total": 12}
+        ]
+    }"#
+);
+
+// the projection of the source keeps the localKey, so that the reference can be followed
+test_materialize!(
+    foreign_reference_by_keys,
+    expected = r#"{
+        "shop": {"orders": [
+            {"_id": 10, "productId": "p1", "quantity": 3},
+            {"_id": 11, "productId": "p2", "quantity": 1}
+        ]},
+        "catalog": {"products": [
+            {"sku": "p1", "name": "pen"},
+            {"sku": "p2", "name": "ink"}
+        ]}
+    }"#,
+    erd = r#"{
+        "Order": {
+            "source": {"db": "shop", "collection": "orders", "projection": ["_id", "quantity"]},
+            "primaryKey": "_id",
+            "relationships": {
+                "Product": {
+                    "relationshipType": "many-to-one",
+                    "constraint": {
+                        "constraintType": "foreign",
+                        "db": "catalog",
+                        "collection": "products",
+                        "localKey": "productId",
+                        "foreignKey": "sku",
+                        "projection": []
+                    }
+                }
+            },
+            "jsonSchema": {}
+        },
+        "Product": {
+            "source": {"db": "catalog", "collection": "products"},
+            "primaryKey": "sku",
+            "relationships": {},
+            "jsonSchema": {}
+        }
+    }"#,
+    input = r#"{
+        "Order": [
+            {"_id": 10, "productId": "p1", "quantity": 3, "gift": true},
+            {"_id": 11, "productId": "p2", "quantity": 1}
+        ],
+        "Product": [{"sku": "p1", "name": "pen"}, {"sku": "p2", "name": "ink"}]
+    }"#
+);
@@ -0,0 +1,708 @@
+use crate::{
+    erd::{Constraint, ConstraintType, ErdRelationship, KeyPair, RelationshipType},
+    erd_graph::GetErdData,
+    join_rewrite::JoinOptions,
+    match_movement_rewrite::let_variable,
+    passes::{self, DEFAULT_PASSES, Plan},
+};
+use ast::{
+    ROOT_NAME,
+    definitions::{
+        ConciseSubqueryLookup, EqualityLookup, Expression, Group, Lookup, LookupFrom, MatchExpr,
+        MatchExpression, MatchStage, Merge, MergeInto, MergeOn, MergeWhenMatched,
+        MergeWhenNotMatched, Namespace, Pipeline, ProjectItem, ProjectStage, Ref, ReplaceStage,
+        Stage, SubqueryLookup, Unset, UntaggedOperator, UntaggedOperatorName, Unwind, UnwindExpr,
+    },
+    map,
+};
+use linked_hash_map::LinkedHashMap;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// MigrationPlan is the set of aggregation pipelines that transform collections stored according
+/// to one ERD into the layout of another.
+///
+/// Steps are ordered so that every step that copies data runs before any step that removes the
+/// data it was copied from. Changes that cannot be migrated mechanically, such as a relationship
+/// whose keys are unknown, are reported as notes instead.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationPlan {
+    pub steps: Vec<MigrationStep>,
+    pub notes: Vec<String>,
+}
+
+/// MigrationStep is a single aggregation to run against `db.collection`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationStep {
+    pub source: String,
+    pub target: String,
+    pub description: String,
+    pub db: String,
+    pub collection: String,
+    pub pipeline: Pipeline,
+}
+
+/// PlanChange is a pipeline whose rewritten plan differs between the old and the new ERD. A plan
+/// is None if rewriting the pipeline failed with that ERD, and error holds the failure.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanChange {
+    pub name: String,
+    pub old_plan: Option<Plan>,
+    pub new_plan: Option<Plan>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// plan_migration diffs the constraint of every relationship in the two ERDs and generates the
+/// pipelines moving the data of every changed relationship:
+///
+/// - a relationship that becomes embedded is `$lookup`ed into its source and `$merge`d back,
+/// - a relationship that stops being embedded is `$unwind`ed, deduplicated with `$group` and
+///   `$merge`d into its new collection, and the embedded copies are `$unset` afterwards,
+/// - a moved `targetPath` or renamed key is copied to its new name and the old one is `$unset`,
+/// - a foreign relationship whose collection changes is `$merge`d into the new collection.
+///
+/// Steps never use `$out`, which would replace collections that already hold data: they
+/// `$merge` into their target, and steps that rewrite a collection in place `$merge` every
+/// document back over itself.
+pub fn plan_migration<A: GetErdData, B: GetErdData>(old: &A, new: &B) -> MigrationPlan {
+    let mut planner = Planner {
+        copies: Vec::new(),
+        cleanups: Vec::new(),
+        notes: Vec::new(),
+    };
+    // relationships are matched by their source, target and name, since there may be several
+    // named relationships between two entities
+    let keys: BTreeSet<(String, String, Option<String>)> = old
+        .iter()
+        .flat_map(|source| {
+            old.get_relationships(source)
+                .map(move |(target, relationship)| {
+                    (source.clone(), target.clone(), relationship.name.clone())
+                })
+        })
+        .chain(new.iter().flat_map(|source| {
+            new.get_relationships(source)
+                .map(move |(target, relationship)| {
+                    (source.clone(), target.clone(), relationship.name.clone())
+                })
+        }))
+        .collect();
+    for (source, target, name) in keys.iter() {
+        let old_relationship = find_relationship(old, source, target, name.as_deref());
+        let new_relationship = find_relationship(new, source, target, name.as_deref());
+        if old_relationship.map(|r| &r.constraint) == new_relationship.map(|r| &r.constraint) {
+            continue;
+        }
+        planner.plan_relationship(
+            source,
+            target,
+            old_relationship,
+            new_relationship,
+            &old.get_collections(source),
+            &old.get_collections(target),
+        );
+    }
+    MigrationPlan {
+        steps: planner.copies.into_iter().chain(planner.cleanups).collect(),
+        notes: planner.notes,
+    }
+}
+
+fn find_relationship<'a, T: GetErdData>(
+    erd: &'a T,
+    source: &str,
+    target: &str,
+    name: Option<&str>,
+) -> Option<&'a ErdRelationship> {
+    erd.get_relationships(source)
+        .find(|(t, relationship)| *t == target && relationship.name.as_deref() == name)
+        .map(|(_, relationship)| relationship)
+}
+
+/// changed_plans rewrites every named pipeline with the default passes against both ERDs, and
+/// returns the pipelines whose rewritten plan differs.
+pub fn changed_plans<A: GetErdData, B: GetErdData>(
+    old: &A,
+    new: &B,
+    pipelines: &[(String, Pipeline)],
+    options: JoinOptions,
+) -> Vec<PlanChange> {
+    let split = |result: passes::Result<Plan>| match result {
+        Ok(plan) => (Some(plan), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    pipelines
+        .iter()
+        .filter_map(|(name, pipeline)| {
+            let (old_plan, old_error) =
+                split(passes::plan(pipeline.clone(), DEFAULT_PASSES, old, options));
+            let (new_plan, new_error) =
+                split(passes::plan(pipeline.clone(), DEFAULT_PASSES, new, options));
+            if old_plan == new_plan && old_error == new_error {
+                return None;
+            }
+            Some(PlanChange {
+                name: name.clone(),
+                old_plan,
+                new_plan,
+                error: new_error.or(old_error),
+            })
+        })
+        .collect()
+}
+
+struct Planner {
+    copies: Vec<MigrationStep>,
+    cleanups: Vec<MigrationStep>,
+    notes: Vec<String>,
+}
+
+impl Planner {
+    fn plan_relationship(
+        &mut self,
+        source: &str,
+        target: &str,
+        old: Option<&ErdRelationship>,
+        new: Option<&ErdRelationship>,
+        source_collections: &BTreeSet<(String, String)>,
+        target_collections: &BTreeSet<(String, String)>,
+    ) {
+        let old_constraint = old.map(|r| &r.constraint);
+        let new_constraint = new.map(|r| &r.constraint);
+        match (
+            old_constraint.map(|c| c.constraint_type),
+            new_constraint.map(|c| c.constraint_type),
+        ) {
+            (Some(ConstraintType::Bucket), _) | (_, Some(ConstraintType::Bucket)) => {
+                self.notes.push(format!(
+                    "{} -> {}: bucket relationships are not migrated automatically",
+                    source, target
+                ))
+            }
+            (Some(ConstraintType::Embedded), Some(ConstraintType::Embedded)) => {
+                let (old_path, new_path) = (
+                    old_constraint.and_then(|c| c.target_path.as_ref()),
+                    new_constraint.and_then(|c| c.target_path.as_ref()),
+                );
+                match (old_path, new_path) {
+                    (Some(old_path), Some(new_path)) if old_path != new_path => self.rename(
+                        source,
+                        target,
+                        source_collections,
+                        old_path,
+                        new_path,
+                        format!("move embedded {} from {} to {}", target, old_path, new_path),
+                    ),
+                    (Some(_), Some(_)) => self.notes.push(format!(
+                        "{} -> {}: embedded constraint changed without moving targetPath, no data to migrate",
+                        source, target
+                    )),
+                    _ => self.notes.push(format!(
+                        "{} -> {}: embedded relationship is missing targetPath",
+                        source, target
+                    )),
+                }
+            }
+            (_, Some(ConstraintType::Embedded)) => {
+                let new = new.unwrap();
+                // prefer the collection the old relationship pointed at, since that is where
+                // the data lives today
+                let from = old_constraint
+                    .and_then(|c| Some((c.db.clone()?, c.collection.clone()?)))
+                    .or_else(|| target_collections.iter().next().cloned());
+                self.embed(source, target, new, source_collections, from)
+            }
+            (Some(ConstraintType::Embedded), Some(ConstraintType::Foreign)) => self.unembed(
+                source,
+                target,
+                old.unwrap(),
+                new_constraint.unwrap(),
+                source_collections,
+            ),
+            (Some(ConstraintType::Foreign), Some(ConstraintType::Foreign)) => self.move_foreign(
+                source,
+                target,
+                old_constraint.unwrap(),
+                new_constraint.unwrap(),
+                source_collections,
+            ),
+            (None, Some(ConstraintType::Foreign)) => self.notes.push(format!(
+                "{} -> {}: new foreign relationship, no data to migrate",
+                source, target
+            )),
+            (Some(ConstraintType::Embedded), None) => {
+                match old_constraint.and_then(|c| c.target_path.as_ref()) {
+                    Some(path) => self.remove(
+                        source,
+                        target,
+                        source_collections,
+                        path,
+                        format!("remove embedded {} at {}", target, path),
+                    ),
+                    None => self.notes.push(format!(
+                        "{} -> {}: removed embedded relationship is missing targetPath",
+                        source, target
+                    )),
+                }
+            }
+            (Some(ConstraintType::Foreign), None) => self.notes.push(format!(
+                "{} -> {}: removed foreign relationship, {} documents are left in place",
+                source, target, target
+            )),
+            (None, None) => {}
+        }
+        if source_collections.is_empty()
+            && matches!(
+                (old_constraint, new_constraint),
+                (
+                    Some(Constraint {
+                        constraint_type: ConstraintType::Embedded,
+                        ..
+                    }),
+                    _
+                ) | (
+                    _,
+                    Some(Constraint {
+                        constraint_type: ConstraintType::Embedded,
+                        ..
+                    })
+                )
+            )
+        {
+            self.notes.push(format!(
+                "{} -> {}: {} is not stored in its own collection, migrate it through its parent",
+                source, target, source
+            ));
+        }
+    }
+
+    // embed generates the $lookup + $merge pipeline copying target documents from the from
+    // collection into every source document.
+    fn embed(
+        &mut self,
+        source: &str,
+        target: &str,
+        relationship: &ErdRelationship,
+        source_collections: &BTreeSet<(String, String)>,
+        from: Option<(String, String)>,
+    ) {
+        let constraint = &relationship.constraint;
+        let keys = constraint.key_pairs();
+        let (Some(target_path), false, Some((from_db, from_coll))) =
+            (constraint.target_path.as_ref(), keys.is_empty(), from)
+        else {
+            self.notes.push(format!(
+                "{} -> {}: embedding needs targetPath, localKey and foreignKey or keys, and an existing {} collection",
+                source, target, target
+            ));
+            return;
+        };
+        for (db, collection) in source_collections.iter() {
+            let from = if &from_db == db {
+                LookupFrom::Collection(from_coll.clone())
+            } else {
+                LookupFrom::Namespace(Namespace {
+                    db: from_db.clone(),
+                    coll: from_coll.clone(),
+                })
+            };
+            let project = (!constraint.projection.is_empty()).then(|| {
+                Stage::Project(ProjectStage {
+                    items: constraint
+                        .projection
+                        .iter()
+                        .map(|field| (field.clone(), ProjectItem::Inclusion))
+                        .collect(),
+                })
+            });
+            let lookup = match (keys.as_slice(), project) {
+                ([pair], None) => Lookup::Equality(EqualityLookup {
+                    from,
+                    local_field: pair.local_key.clone(),
+                    foreign_field: pair.foreign_key.clone(),
+                    as_var: target_path.clone(),
+                }),
+                ([pair], Some(project)) => Lookup::ConciseSubquery(ConciseSubqueryLookup {
+                    from: Some(from),
+                    local_field: pair.local_key.clone(),
+                    foreign_field: pair.foreign_key.clone(),
+                    let_body: None,
+                    pipeline: Pipeline {
+                        pipeline: vec![project],
+                    },
+                    as_var: target_path.clone(),
+                }),
+                // composite keys need a pipeline lookup matching every key pair
+                (keys, project) => {
+                    let mut let_body = LinkedHashMap::new();
+                    let equalities = keys
+                        .iter()
+                        .map(|pair| {
+                            let var = let_variable(&mut let_body, &pair.local_key);
+                            Expression::UntaggedOperator(UntaggedOperator {
+                                op: UntaggedOperatorName::Eq,
+                                args: vec![
+                                    Expression::Ref(Ref::FieldRef(pair.foreign_key.clone())),
+                                    Expression::Ref(Ref::VariableRef(var)),
+                                ],
+                            })
+                        })
+                        .collect();
+                    let mut pipeline = vec![Stage::Match(MatchStage {
+                        expr: vec![MatchExpression::Expr(MatchExpr {
+                            expr: Box::new(Expression::UntaggedOperator(UntaggedOperator {
+                                op: UntaggedOperatorName::And,
+                                args: equalities,
+                            })),
+                        })],
+                        numbering: None,
+                    })];
+                    pipeline.extend(project);
+                    Lookup::Subquery(SubqueryLookup {
+                        from: Some(from),
+                        let_body: Some(let_body),
+                        pipeline: Pipeline { pipeline },
+                        as_var: target_path.clone(),
+                        is_left_join: None,
+                    })
+                }
+            };
+            let mut pipeline = vec![Stage::Lookup(lookup)];
+            if relationship.relationship_type == RelationshipType::OneToOne {
+                pipeline.push(Stage::Unwind(Unwind::Document(UnwindExpr {
+                    path: Box::new(Expression::Ref(Ref::FieldRef(target_path.clone()))),
+                    include_array_index: None,
+                    preserve_null_and_empty_arrays: Some(true),
+                })));
+            }
+            pipeline.push(merge(
+                db,
+                collection,
+                vec!["_id".to_string()],
+                MergeWhenMatched::Merge,
+                MergeWhenNotMatched::Discard,
+            ));
+            self.copies.push(MigrationStep {
+                source: source.to_string(),
+                target: target.to_string(),
+                description: format!(
+                    "embed {} from {}.{} at {}",
+                    target, from_db, from_coll, target_path
+                ),
+                db: db.clone(),
+                collection: collection.clone(),
+                pipeline: Pipeline { pipeline },
+            });
+        }
+    }
+
+    // unembed generates the pipeline moving embedded target documents into the new foreign
+    // collection, setting the foreign keys from the source's local keys, and the $unset removing
+    // the embedded copies.
+    //
+    // Copies are $merged into the collection, which may already hold the target documents, so
+    // that matching documents gain the foreign keys and the others are inserted. A target
+    // embedded as a single document is matched by its foreign keys, which $merge needs a unique
+    // index on, and a target embedded in an array by its _id. Since several sources can embed
+    // the same target, the copies are first grouped by the keys they are matched by.
+    fn unembed(
+        &mut self,
+        source: &str,
+        target: &str,
+        old: &ErdRelationship,
+        new: &Constraint,
+        source_collections: &BTreeSet<(String, String)>,
+    ) {
+        let keys = new.key_pairs();
+        let (Some(target_path), Some(db), Some(coll), false) = (
+            old.constraint.target_path.as_ref(),
+            new.db.as_ref(),
+            new.collection.as_ref(),
+            keys.is_empty(),
+        ) else {
+            self.notes.push(format!(
+                "{} -> {}: moving out of the embedding needs the old targetPath and the new db, collection, localKey and foreignKey or keys",
+                source, target
+            ));
+            return;
+        };
+        let on: Vec<String> = if old.relationship_type == RelationshipType::OneToOne {
+            keys.iter().map(|pair| pair.foreign_key.clone()).collect()
+        } else {
+            let projection = &old.constraint.projection;
+            if !projection.is_empty() && !projection.iter().any(|field| field == "_id") {
+                self.notes.push(format!(
+                    "{} -> {}: the embedded copies of {} at {} have no _id to merge them into {}.{} by, add _id to the projection or migrate them by hand",
+                    source, target, target, target_path, db, coll
+                ));
+                return;
+            }
+            vec!["_id".to_string()]
+        };
+        for (source_db, source_coll) in source_collections.iter() {
+            let mut pipeline = vec![
+                Stage::Unwind(Unwind::Document(UnwindExpr {
+                    path: Box::new(Expression::Ref(Ref::FieldRef(target_path.clone()))),
+                    include_array_index: None,
+                    preserve_null_and_empty_arrays: Some(false),
+                })),
+                Stage::AddFields(
+                    keys.iter()
+                        .map(|pair| {
+                            (
+                                format!("{}.{}", target_path, pair.foreign_key),
+                                Expression::Ref(Ref::FieldRef(pair.local_key.clone())),
+                            )
+                        })
+                        .collect(),
+                ),
+                Stage::ReplaceWith(ReplaceStage::Expression(Expression::Ref(Ref::FieldRef(
+                    target_path.clone(),
+                )))),
+            ];
+            pipeline.extend(deduplicate(&on));
+            // the _id of a copy matched by other fields would overwrite the _id of the
+            // document it is merged into
+            if on != ["_id"] {
+                pipeline.push(Stage::Unset(Unset::Single("_id".to_string())));
+            }
+            pipeline.push(merge(
+                db,
+                coll,
+                on.clone(),
+                MergeWhenMatched::Merge,
+                MergeWhenNotMatched::Insert,
+            ));
+            self.copies.push(MigrationStep {
+                source: source.to_string(),
+                target: target.to_string(),
+                description: format!(
+                    "move embedded {} at {} to {}.{}",
+                    target, target_path, db, coll
+                ),
+                db: source_db.clone(),
+                collection: source_coll.clone(),
+                pipeline: Pipeline { pipeline },
+            });
+        }
+        self.remove(
+            source,
+            target,
+            source_collections,
+            target_path,
+            format!("remove embedded {} at {}", target, target_path),
+        );
+    }
+
+    // move_foreign handles foreign relationships whose namespace or keys change: target
+    // documents are merged into the new collection, renaming the foreign keys, and renamed
+    // local keys are renamed in the source collections. Key pairs are matched by position.
+    fn move_foreign(
+        &mut self,
+        source: &str,
+        target: &str,
+        old: &Constraint,
+        new: &Constraint,
+        source_collections: &BTreeSet<(String, String)>,
+    ) {
+        let (old_keys, new_keys) = (old.key_pairs(), new.key_pairs());
+        let (Some(old_db), Some(old_coll), Some(new_db), Some(new_coll), false) = (
+            old.db.as_ref(),
+            old.collection.as_ref(),
+            new.db.as_ref(),
+            new.collection.as_ref(),
+            old_keys.is_empty() || old_keys.len() != new_keys.len(),
+        ) else {
+            self.notes.push(format!(
+                "{} -> {}: foreign relationships need db, collection, and localKey and foreignKey or the same number of keys",
+                source, target
+            ));
+            return;
+        };
+        let renamed_foreign_keys: Vec<(&KeyPair, &KeyPair)> = old_keys
+            .iter()
+            .zip(new_keys.iter())
+            .filter(|(old, new)| old.foreign_key != new.foreign_key)
+            .collect();
+        if (old_db, old_coll) != (new_db, new_coll) || !renamed_foreign_keys.is_empty() {
+            let mut pipeline = Vec::new();
+            if !renamed_foreign_keys.is_empty() {
+                pipeline.push(Stage::AddFields(
+                    renamed_foreign_keys
+                        .iter()
+                        .map(|(old, new)| {
+                            (
+                                new.foreign_key.clone(),
+                                Expression::Ref(Ref::FieldRef(old.foreign_key.clone())),
+                            )
+                        })
+                        .collect(),
+                ));
+            }
+            pipeline.push(merge(
+                new_db,
+                new_coll,
+                vec!["_id".to_string()],
+                MergeWhenMatched::Merge,
+                MergeWhenNotMatched::Insert,
+            ));
+            self.copies.push(MigrationStep {
+                source: source.to_string(),
+                target: target.to_string(),
+                description: format!(
+                    "copy {} from {}.{} to {}.{}",
+                    target, old_db, old_coll, new_db, new_coll
+                ),
+                db: old_db.clone(),
+                collection: old_coll.clone(),
+                pipeline: Pipeline { pipeline },
+            });
+            for (old, new) in renamed_foreign_keys {
+                self.remove(
+                    source,
+                    target,
+                    &BTreeSet::from([(new_db.clone(), new_coll.clone())]),
+                    &old.foreign_key,
+                    format!(
+                        "remove foreign key {} renamed to {}",
+                        old.foreign_key, new.foreign_key
+                    ),
+                );
+            }
+        }
+        for (old, new) in old_keys.iter().zip(new_keys.iter()) {
+            if old.local_key != new.local_key {
+                self.rename(
+                    source,
+                    target,
+                    source_collections,
+                    &old.local_key,
+                    &new.local_key,
+                    format!("rename local key {} to {}", old.local_key, new.local_key),
+                );
+            }
+        }
+    }
+
+    // rename copies the from field to the to field in every source collection, and removes the
+    // from field once every copy has run.
+    fn rename(
+        &mut self,
+        source: &str,
+        target: &str,
+        source_collections: &BTreeSet<(String, String)>,
+        from: &str,
+        to: &str,
+        description: String,
+    ) {
+        for (db, collection) in source_collections.iter() {
+            self.copies.push(MigrationStep {
+                source: source.to_string(),
+                target: target.to_string(),
+                description: description.clone(),
+                db: db.clone(),
+                collection: collection.clone(),
+                pipeline: Pipeline {
+                    pipeline: vec![
+                        Stage::AddFields(map! {
+                            to.to_string() => Expression::Ref(Ref::FieldRef(from.to_string())),
+                        }),
+                        replace_in_place(db, collection),
+                    ],
+                },
+            });
+        }
+        self.remove(source, target, source_collections, from, description);
+    }
+
+    // remove $unsets the field in every collection, once every copy has run.
+    fn remove(
+        &mut self,
+        source: &str,
+        target: &str,
+        collections: &BTreeSet<(String, String)>,
+        field: &str,
+        description: String,
+    ) {
+        for (db, collection) in collections.iter() {
+            self.cleanups.push(MigrationStep {
+                source: source.to_string(),
+                target: target.to_string(),
+                description: description.clone(),
+                db: db.clone(),
+                collection: collection.clone(),
+                pipeline: Pipeline {
+                    pipeline: vec![
+                        Stage::Unset(Unset::Single(field.to_string())),
+                        replace_in_place(db, collection),
+                    ],
+                },
+            });
+        }
+    }
+}
+
+// deduplicate keeps one document per value of the fields.
+fn deduplicate(fields: &[String]) -> Vec<Stage> {
+    let keys = match fields {
+        [field] => Expression::Ref(Ref::FieldRef(field.clone())),
+        fields => Expression::Document(
+            fields
+                .iter()
+                .map(|field| (field.clone(), Expression::Ref(Ref::FieldRef(field.clone()))))
+                .collect(),
+        ),
+    };
+    vec![
+        Stage::Group(Group {
+            keys,
+            aggregations: map! {
+                "document".to_string() => Expression::UntaggedOperator(UntaggedOperator {
+                    op: UntaggedOperatorName::First,
+                    args: vec![Expression::Ref(Ref::VariableRef(ROOT_NAME.to_string()))],
+                }),
+            },
+        }),
+        Stage::ReplaceWith(ReplaceStage::Expression(Expression::Ref(Ref::FieldRef(
+            "document".to_string(),
+        )))),
+    ]
+}
+
+fn merge(
+    db: &str,
+    collection: &str,
+    on: Vec<String>,
+    when_matched: MergeWhenMatched,
+    when_not_matched: MergeWhenNotMatched,
+) -> Stage {
+    Stage::Merge(Merge {
+        into: MergeInto::Namespace(Namespace {
+            db: db.to_string(),
+            coll: collection.to_string(),
+        }),
+        on: Some(match <[String; 1]>::try_from(on) {
+            Ok([field]) => MergeOn::Single(field),
+            Err(on) => MergeOn::Multiple(on),
+        }),
+        let_body: None,
+        when_matched: Some(when_matched),
+        when_not_matched: Some(when_not_matched),
+    })
+}
+
+// replace_in_place writes every document back over itself. Unlike $out, this keeps the
+// documents of the collection that the pipeline does not output.
+fn replace_in_place(db: &str, collection: &str) -> Stage {
+    merge(
+        db,
+        collection,
+        vec!["_id".to_string()],
+        MergeWhenMatched::Replace,
+        MergeWhenNotMatched::Discard,
+    )
+}
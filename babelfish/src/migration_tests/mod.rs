@@ -0,0 +1,206 @@
+macro_rules! test_migration {
+    ($func_name:ident, expected = $expected:expr, old = $old:expr, new = $new:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::{erd::Erd, migration::plan_migration};
+
+            let old: Erd = serde_json::from_str(&crate::migration_tests::erd($old)).unwrap();
+            let new: Erd = serde_json::from_str(&crate::migration_tests::erd($new)).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let plan = serde_json::to_value(plan_migration(&old, &new)).unwrap();
+            assert_eq!(expected, plan);
+            // $out would replace the collections the steps write to
+            assert!(!plan.to_string().contains("\"$out\""));
+        }
+    };
+}
+
+// erd is an ERD of customers and their orders, related by the given relationship from Customer
+// to Order.
+fn erd(relationship: &str) -> String {
+    format!(
+        r#"{{
+            "Customer": {{
+                "source": {{"db": "shop", "collection": "customers"}},
+                "primaryKey": "_id",
+                "relationships": {{"Order": {}}},
+                "jsonSchema": {{}}
+            }},
+            "Order": {{
+                "source": {{"db": "shop", "collection": "orders"}},
+                "primaryKey": "_id",
+                "relationships": {{}},
+                "jsonSchema": {{}}
+            }}
+        }}"#,
+        relationship
+    )
+}
+
+const FOREIGN: &str = r#"{
+    "relationshipType": "many-to-one",
+    "constraint": {
+        "constraintType": "foreign",
+        "db": "shop",
+        "collection": "orders",
+        "localKey": "_id",
+        "foreignKey": "customerId",
+        "projection": []
+    }
+}"#;
+
+const EMBEDDED: &str = r#"{
+    "relationshipType": "many-to-one",
+    "constraint": {
+        "constraintType": "embedded",
+        "targetPath": "orders",
+        "localKey": "_id",
+        "foreignKey": "customerId",
+        "projection": []
+    }
+}"#;
+
+test_migration!(
+    foreign_to_embedded,
+    expected = r#"{"steps": [
+        {
+            "source": "Customer",
+            "target": "Order",
+            "description": "embed Order from shop.orders at orders",
+            "db": "shop",
+            "collection": "customers",
+            "pipeline": [
+                {"$lookup": {"from": "orders", "localField": "_id", "foreignField": "customerId", "as": "orders"}},
+                {"$merge": {"into": {"db": "shop", "coll": "customers"}, "on": "_id", "whenMatched": "merge", "whenNotMatched": "discard"}}
+            ]
+        }
+    ], "notes": []}"#,
+    old = FOREIGN,
+    new = EMBEDDED
+);
+
+test_migration!(
+    embedded_to_foreign,
+    expected = r#"{"steps": [
+        {
+            "source": "Customer",
+            "target": "Order",
+            "description": "move embedded Order at orders to shop.orders",
+            "db": "shop",
+            "collection": "customers",
+            "pipeline": [
+                {"$unwind": {"path": "$orders", "preserveNullAndEmptyArrays": false}},
+                {"$addFields": {"orders.customerId": "$_id"}},
+                {"$replaceWith": "$orders"},
+                {"$group": {"_id": "$_id", "document": {"$first": "$$ROOT"}}},
+                {"$replaceWith": "$document"},
+                {"$merge": {"into": {"db": "shop", "coll": "orders"}, "on": "_id", "whenMatched": "merge", "whenNotMatched": "insert"}}
+            ]
+        },
+        {
+            "source": "Customer",
+            "target": "Order",
+            "description": "remove embedded Order at orders",
+            "db": "shop",
+            "collection": "customers",
+            "pipeline": [
+                {"$unset": "orders"},
+                {"$merge": {"into": {"db": "shop", "coll": "customers"}, "on": "_id", "whenMatched": "replace", "whenNotMatched": "discard"}}
+            ]
+        }
+    ], "notes": []}"#,
+    old = EMBEDDED,
+    new = FOREIGN
+);
+
+test_migration!(
+    move_target_path,
+    expected = r#"{"steps": [
+        {
+            "source": "Customer",
+            "target": "Order",
+            "description": "move embedded Order from orders to history.orders",
+            "db": "shop",
+            "collection": "customers",
+            "pipeline": [
+                {"$addFields": {"history.orders": "$orders"}},
+                {"$merge": {"into": {"db": "shop", "coll": "customers"}, "on": "_id", "whenMatched": "replace", "whenNotMatched": "discard"}}
+            ]
+        },
+        {
+            "source": "Customer",
+            "target": "Order",
+            "description": "move embedded Order from orders to history.orders",
+            "db": "shop",
+            "collection": "customers",
+            "pipeline": [
+                {"$unset": "orders"},
+                {"$merge": {"into": {"db": "shop", "coll": "customers"}, "on": "_id", "whenMatched": "replace", "whenNotMatched": "discard"}}
+            ]
+        }
+    ], "notes": []}"#,
+    old = EMBEDDED,
+    new = &EMBEDDED.replace(
+        r#""targetPath": "orders""#,
+        r#""targetPath": "history.orders""#
+    )
+);
+
+test_migration!(
+    move_foreign_collection,
+    expected = r#"{"steps": [
+        {
+            "source": "Customer",
+            "target": "Order",
+            "description": "copy Order from shop.orders to shop.purchases",
+            "db": "shop",
+            "collection": "orders",
+            "pipeline": [
+                {"$merge": {"into": {"db": "shop", "coll": "purchases"}, "on": "_id", "whenMatched": "merge", "whenNotMatched": "insert"}}
+            ]
+        }
+    ], "notes": []}"#,
+    old = FOREIGN,
+    new = &FOREIGN.replace(r#""collection": "orders""#, r#""collection": "purchases""#)
+);
+
+// only the pipelines whose plan changes are reported
+#[test]
+fn changed_plans() {
+    use crate::{erd::Erd, join_rewrite::JoinOptions, migration::changed_plans};
+
+    let old: Erd = serde_json::from_str(&erd(FOREIGN)).unwrap();
+    let new: Erd = serde_json::from_str(&erd(EMBEDDED)).unwrap();
+    let pipelines = vec![
+        (
+            "orders".to_string(),
+            serde_json::from_str(
+                r#"[{"$join": {"$inner": {"root": "Customer", "args": ["Order"]}}}]"#,
+            )
+            .unwrap(),
+        ),
+        (
+            "customers".to_string(),
+            serde_json::from_str(r#"[{"$join": {"$inner": {"root": "Customer", "args": []}}}]"#)
+                .unwrap(),
+        ),
+    ];
+    let expected: serde_json::Value = serde_json::from_str(
+        r#"[{
+            "name": "orders",
+            "oldPlan": {"db": "shop", "collection": "customers", "pipeline": [
+                {"$project": {"Customer": "$$ROOT", "_id": false}},
+                {"$lookup": {"from": "orders", "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}},
+                {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}
+            ]},
+            "newPlan": {"db": "shop", "collection": "customers", "pipeline": [
+                {"$project": {"Customer": "$$ROOT", "_id": false}},
+                {"$unwind": {"path": "$Customer.orders", "preserveNullAndEmptyArrays": false}},
+                {"$addFields": {"Order": "$Customer.orders"}}
+            ]}
+        }]"#,
+    )
+    .unwrap();
+    let result = changed_plans(&old, &new, &pipelines, JoinOptions::default());
+    assert_eq!(expected, serde_json::to_value(result).unwrap());
+}
@@ -0,0 +1,164 @@
+use crate::{
+    conjure_rewrite, desugar,
+    erd_graph::GetErdData,
+    join_rewrite::{self, JoinOptions},
+    match_movement_rewrite,
+};
+use ast::definitions::{Collection, Pipeline, Stage};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Conjure error: {0}")]
+    Conjure(#[from] conjure_rewrite::Error),
+    #[error("Join error: {0}")]
+    Join(#[from] join_rewrite::Error),
+    #[error("Desugar error: {0}")]
+    Desugar(#[from] desugar::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Pass is a single rewrite that can be applied to a pipeline. Passes are run in the order
+/// they are given, so callers can pick both which rewrites run and in what order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    Conjure,
+    Join,
+    MatchMove,
+    Desugar,
+}
+
+pub const DEFAULT_PASSES: &[Pass] = &[Pass::Conjure, Pass::Join, Pass::MatchMove, Pass::Desugar];
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Conjure => "conjure",
+            Pass::Join => "join",
+            Pass::MatchMove => "match-move",
+            Pass::Desugar => "desugar",
+        }
+    }
+
+    pub fn run<T: GetErdData>(
+        &self,
+        pipeline: Pipeline,
+        erd: &T,
+        options: JoinOptions,
+    ) -> Result<Pipeline> {
+        Ok(match self {
+            Pass::Conjure => conjure_rewrite::rewrite_pipeline(pipeline)?,
+            Pass::Join => join_rewrite::rewrite_pipeline_with_options(pipeline, erd, options)?,
+            Pass::MatchMove => match_movement_rewrite::rewrite_match_move(pipeline),
+            Pass::Desugar => desugar::desugar_pipeline(pipeline)?,
+        })
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "conjure" => Ok(Pass::Conjure),
+            "join" => Ok(Pass::Join),
+            "match-move" => Ok(Pass::MatchMove),
+            "desugar" => Ok(Pass::Desugar),
+            _ => Err(format!(
+                "unknown pass: {}, expected one of: conjure, join, match-move, desugar",
+                s
+            )),
+        }
+    }
+}
+
+/// run_passes runs the passes in order and returns the rewritten pipeline without the
+/// `$collection` stages the join pass starts from, since the server does not accept them. Use
+/// plan to also get the namespace they name.
+pub fn run_passes<T: GetErdData>(
+    pipeline: Pipeline,
+    passes: &[Pass],
+    erd: &T,
+    options: JoinOptions,
+) -> Result<Pipeline> {
+    Ok(plan(pipeline, passes, erd, options)?.pipeline)
+}
+
+/// Plan is a rewritten pipeline together with the namespace to run it on, which is the source of
+/// the root entity of its first `$join`. The namespace is unknown if the ERD has no sources or
+/// the pipeline has no `$join`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Plan {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    pub pipeline: Pipeline,
+}
+
+impl Plan {
+    /// from_pipeline takes the namespace from the first `$collection` stage of the pipeline, and
+    /// removes every `$collection` stage, since the server does not accept them.
+    pub fn from_pipeline(pipeline: Pipeline) -> Self {
+        let mut namespace = None;
+        let pipeline = pipeline
+            .pipeline
+            .into_iter()
+            .filter_map(|stage| match stage {
+                Stage::Collection(Collection { db, collection }) => {
+                    namespace.get_or_insert((db, collection));
+                    None
+                }
+                stage => Some(stage),
+            })
+            .collect();
+        let (db, collection) = namespace.unzip();
+        Plan {
+            db,
+            collection,
+            pipeline: Pipeline { pipeline },
+        }
+    }
+
+    /// aggregate_command is the command document that runs the plan:
+    /// `{aggregate: <collection>, pipeline: [...], cursor: {}, $db: <db>}`. Without a collection
+    /// the pipeline runs as a database aggregate, `aggregate: 1`, and `$db` is left out without a
+    /// db.
+    pub fn aggregate_command(&self) -> serde_json::Result<Value> {
+        let mut command = json!({
+            "aggregate": match &self.collection {
+                Some(collection) => json!(collection),
+                None => json!(1),
+            },
+            "pipeline": serde_json::to_value(&self.pipeline)?,
+            "cursor": {},
+        });
+        if let Some(db) = &self.db {
+            command["$db"] = json!(db);
+        }
+        Ok(command)
+    }
+}
+
+/// plan runs the passes and returns the rewritten pipeline with the namespace to run it on.
+pub fn plan<T: GetErdData>(
+    mut pipeline: Pipeline,
+    passes: &[Pass],
+    erd: &T,
+    options: JoinOptions,
+) -> Result<Plan> {
+    for pass in passes {
+        pipeline = pass.run(pipeline, erd, options)?;
+    }
+    Ok(Plan::from_pipeline(pipeline))
+}
@@ -0,0 +1,114 @@
+macro_rules! test_plan {
+    ($func_name:ident, expected = $expected:expr, namespace = $namespace:expr, input = $input:expr) => {
+        #[test]
+        fn $func_name() {
+            use crate::{
+                erd::Erd,
+                join_rewrite::JoinOptions,
+                passes::{DEFAULT_PASSES, plan, run_passes},
+            };
+            use ast::definitions::Pipeline;
+
+            let erd: Erd = serde_json::from_str(crate::passes_tests::ERD).unwrap();
+            let input: Pipeline = serde_json::from_str($input).unwrap();
+            let expected: serde_json::Value = serde_json::from_str($expected).unwrap();
+            let plan = plan(input.clone(), DEFAULT_PASSES, &erd, JoinOptions::default()).unwrap();
+            assert_eq!(
+                $namespace,
+                plan.db.as_deref().zip(plan.collection.as_deref())
+            );
+            assert_eq!(expected, serde_json::to_value(&plan.pipeline).unwrap());
+            // run_passes gives the same pipeline, without the namespace
+            let pipeline = run_passes(input, DEFAULT_PASSES, &erd, JoinOptions::default()).unwrap();
+            assert_eq!(expected, serde_json::to_value(pipeline).unwrap());
+        }
+    };
+}
+
+// Customers are stored with only their name and email, and embed their Address, which can also
+// be a join root of its own.
+const ERD: &str = r#"{
+    "Customer": {
+        "source": {"db": "shop", "collection": "customers", "projection": ["name", "contact.email"]},
+        "primaryKey": "_id",
+        "relationships": {
+            "Address": {
+                "relationshipType": "one-to-one",
+                "constraint": {
+                    "constraintType": "embedded",
+                    "targetPath": "address",
+                    "projection": []
+                }
+            },
+            "Order": {
+                "relationshipType": "many-to-one",
+                "constraint": {
+                    "constraintType": "foreign",
+                    "db": "shop",
+                    "collection": "orders",
+                    "localKey": "_id",
+                    "foreignKey": "customerId",
+                    "projection": []
+                }
+            }
+        },
+        "jsonSchema": {}
+    },
+    "Address": {
+        "source": {"db": "shop", "collection": "customers", "targetPath": "address", "projection": ["city"]},
+        "primaryKey": "_id",
+        "relationships": {},
+        "jsonSchema": {}
+    },
+    "Order": {
+        "source": {"db": "shop", "collection": "orders"},
+        "primaryKey": "_id",
+        "relationships": {},
+        "jsonSchema": {}
+    }
+}"#;
+
+test_plan!(
+    collection_becomes_namespace,
+    expected = r#"[{"$match": {"$expr": {"$and": [{"$eq": ["$name", "Ada"]}]}}}, {"$project": {"Customer": {"_id": "$_id", "address": "$address", "contact": {"email": "$contact.email"}, "name": "$name"}, "_id": false}}, {"$lookup": {"from": "orders", "localField": "Customer._id", "foreignField": "customerId", "as": "Order"}}, {"$unwind": {"path": "$Order", "preserveNullAndEmptyArrays": false}}]"#,
+    namespace = Some(("shop", "customers")),
+    input = r#"[{"$join": {"$inner": {
+        "root": "Customer",
+        "args": ["Order"],
+        "condition": {"$eq": ["$Customer.name", "Ada"]}
+    }}}]"#
+);
+
+test_plan!(
+    embedded_root_projection,
+    expected = r#"[{"$unwind": {"path": "$address", "preserveNullAndEmptyArrays": false}}, {"$project": {"Address": {"city": "$address.city"}, "_id": false}}]"#,
+    namespace = Some(("shop", "customers")),
+    input = r#"[{"$join": {"$inner": {"root": "Address", "args": []}}}]"#
+);
+
+test_plan!(
+    no_join_has_no_namespace,
+    expected = r#"[{"$match": {"$expr": {"$and": [{"$eq": ["$name", "Ada"]}]}}}]"#,
+    namespace = None::<(&str, &str)>,
+    input = r#"[{"$match": {"$expr": {"$eq": ["$name", "Ada"]}}}]"#
+);
+
+#[test]
+fn explain_names_namespace() {
+    use crate::{
+        erd::Erd, explain::explain_pipeline, join_rewrite::JoinOptions, passes::DEFAULT_PASSES,
+    };
+    use ast::definitions::Pipeline;
+
+    let erd: Erd = serde_json::from_str(ERD).unwrap();
+    let input: Pipeline =
+        serde_json::from_str(r#"[{"$join": {"$inner": {"root": "Order", "args": []}}}]"#).unwrap();
+    let explain = explain_pipeline(input, DEFAULT_PASSES, &erd, JoinOptions::default()).unwrap();
+    assert_eq!(
+        r#"$join rooted at Order
+pipeline on shop.orders
+  0: {"$project":{"Order":"$$ROOT","_id":false}}
+"#,
+        explain.to_string()
+    );
+}